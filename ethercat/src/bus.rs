@@ -2,95 +2,446 @@
 use crate::opcua::OpcuaServerHandle;
 use ethercrab::{
     std::{ethercat_now, tx_rx_task},
-    MainDevice, MainDeviceConfig, PduStorage, SubDeviceGroup, Timeouts,
+    Command, DcSync, MainDevice, MainDeviceConfig, PduStorage, RegisterAddress, SubDeviceGroup,
+    SubDevicePdi, SubDeviceRef, Timeouts,
 };
+use futures::stream::{self, StreamExt};
 use log::{debug, error, info, trace, warn};
-use schemars::JsonSchema;
+use schemars::{schema::RootSchema, schema_for, JsonSchema};
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 use std::error::Error;
-use std::time::Instant;
+use std::path::Path;
+use std::sync::atomic::AtomicU64;
 use std::{sync::Arc, time::Duration};
 use tfc::confman::ConfMan;
-use tfc::time::MicroDuration;
+use tfc::time::{MicroDuration, MilliDuration};
+use tokio::sync::{watch, Mutex};
+use tokio::task::JoinSet;
 use zbus;
 use zbus::Connection;
 
-use crate::devices::device::make_device;
-use crate::devices::device_trait::{Device, UnimplementedDevice};
+use crate::dc::{DcConfig, DcSignals};
+use crate::devices::device::DeviceRegistry;
+use crate::devices::device_trait::{Device, SetupRef, UnimplementedDevice};
+use crate::devices::esi::EsiLibrary;
+use crate::group::{
+    group_index, validate as validate_groups, GroupConfig, GroupRunner, OpGroup, RunSettings,
+    MAX_GROUPS,
+};
+use crate::recovery::RecoveryConfig;
+use crate::sdo::SdoAccess;
+#[cfg(feature = "simulation")]
+use crate::sim::{self, Segment, SimulatedSubDevice};
+use crate::stats::CycleStats;
+use crate::status::DeviceStatus;
+use crate::topology::{
+    check_expected, mismatch_report, ExpectedSubDevice, FoundSubDevice, Identity, MismatchPolicy,
+    TopologyChange, TopologyConfig, TopologyMonitor, TopologySignals,
+};
 
-/// Maximum number of SubDevices that can be stored. This must be a power of 2 greater than 1.
-const MAX_SUBDEVICES: usize = 16;
-/// Maximum PDU data payload size - set this to the max PDI size or higher.
+/// Maximum PDU data payload size, a PDI larger than this is split over multiple frames.
 const MAX_PDU_DATA: usize = 1100;
 /// Maximum number of EtherCAT frames that can be in flight at any one time.
 const MAX_FRAMES: usize = 16;
-/// Maximum total PDI length. // LENZE i550 requires 66 bytes
-const PDI_LEN: usize = 76;
 
 static PDU_STORAGE: PduStorage<MAX_FRAMES, MAX_PDU_DATA> = PduStorage::new();
 
+/// Capacity of the bus, the maximum number of subdevices and total PDI length are compile time
+/// parameters of the group so one bus is instantiated per profile and selected at startup.
+/// Maximum number of subdevices must be a power of 2 greater than 1. LENZE i550 requires 66 bytes of PDI.
+#[derive(Deserialize, Serialize, JsonSchema, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BusProfile {
+    #[schemars(description = "Up to 16 subdevices and 1024 bytes of process data")]
+    Small,
+    #[schemars(description = "Up to 64 subdevices and 4096 bytes of process data")]
+    Medium,
+    #[schemars(description = "Up to 128 subdevices and 8192 bytes of process data")]
+    Large,
+}
+impl Default for BusProfile {
+    fn default() -> Self {
+        Self::Small
+    }
+}
+
 #[derive(Deserialize, Serialize, JsonSchema)]
 struct BusConfig {
     pub interface: String,
+    #[cfg(feature = "simulation")]
+    #[serde(default)]
+    #[schemars(
+        description = "Simulated subdevices in segment order, when not empty the bus runs against an in-process segment instead of the interface"
+    )]
+    pub simulation: Vec<SimulatedSubDevice>,
+    #[schemars(description = "Cycle time of the default group. Microseconds")]
     pub cycle_time: MicroDuration,
+    #[serde(default)]
+    #[schemars(
+        description = "Groups with their own cycle time, subdevices not assigned to a group run in the default group"
+    )]
+    pub groups: Vec<GroupConfig>,
     #[schemars(
         description = "Minimum number of subdevices that must be in the init state before the bus is transitioned into operational"
     )]
     pub subdevice_min_count: u8,
+    #[serde(default = "default_setup_concurrency")]
+    #[schemars(
+        description = "Number of subdevices set up at the same time, any failure still stops the bus from going to operational"
+    )]
+    pub setup_concurrency: usize,
+    #[serde(default)]
+    #[schemars(
+        description = "Segment positions of optional subdevices, when their setup fails they are marked faulted and hold the safe outputs of their driver while the rest of the line runs. The subdevice must still reach operational with its default configuration"
+    )]
+    pub optional_positions: Vec<usize>,
+    #[serde(default)]
+    #[schemars(
+        description = "Capacity of the bus in subdevices and process data, takes effect on restart"
+    )]
+    pub profile: BusProfile,
+    #[serde(default)]
+    #[schemars(description = "Recovery of subdevices dropping out of operational")]
+    pub recovery: RecoveryConfig,
+    #[serde(default)]
+    #[schemars(description = "Detection of added, removed or re-powered subdevices")]
+    pub topology: TopologyConfig,
+    #[serde(default)]
+    #[schemars(
+        description = "Subdevices expected in the segment in order, empty list accepts any topology"
+    )]
+    pub expected_topology: Vec<ExpectedSubDevice>,
+    #[serde(default)]
+    #[schemars(description = "What to do when the segment does not match the expected topology")]
+    pub topology_mismatch: MismatchPolicy,
+    #[serde(default)]
+    #[schemars(description = "Distributed clocks synchronisation")]
+    pub dc: DcConfig,
+    #[serde(default = "default_stats_publish_interval")]
+    #[schemars(
+        description = "Interval of publishing cycle timing statistics and subdevice status. Milliseconds"
+    )]
+    pub stats_publish_interval: MilliDuration,
+    #[serde(default = "default_shutdown_timeout")]
+    #[schemars(
+        description = "Time given to stop the groups, send safe outputs and bring the subdevices to init on shutdown. Milliseconds"
+    )]
+    pub shutdown_timeout: MilliDuration,
+    #[serde(default = "default_esi_directory")]
+    #[schemars(
+        description = "Directory of ESI XML files, subdevices without a driver get a generic one publishing their default PDO entries when described there. Takes effect on restart"
+    )]
+    pub esi_directory: String,
+    #[serde(default)]
+    #[schemars(
+        description = "Drivers bound to positions by name instead of by the identity of the subdevice"
+    )]
+    pub drivers: Vec<DriverBinding>,
+}
+
+#[derive(Deserialize, Serialize, JsonSchema, Clone)]
+struct DriverBinding {
+    #[schemars(description = "Position of the subdevice in the segment, starting from 0")]
+    pub position: usize,
+    #[schemars(description = "Name of a registered driver, e.g. el1008")]
+    pub driver: String,
+}
+fn default_setup_concurrency() -> usize {
+    8
+}
+fn default_stats_publish_interval() -> MilliDuration {
+    Duration::from_millis(1000).into()
+}
+fn default_shutdown_timeout() -> MilliDuration {
+    Duration::from_millis(5000).into()
+}
+fn default_esi_directory() -> String {
+    "/etc/tfc/ethercat/esi".to_string()
 }
 impl Default for BusConfig {
     fn default() -> Self {
         Self {
             interface: "eth0".to_string(),
+            #[cfg(feature = "simulation")]
+            simulation: Vec::new(),
             cycle_time: Duration::from_millis(1).into(),
+            groups: Vec::new(),
             subdevice_min_count: 1,
+            setup_concurrency: default_setup_concurrency(),
+            optional_positions: Vec::new(),
+            profile: BusProfile::default(),
+            recovery: RecoveryConfig::default(),
+            topology: TopologyConfig::default(),
+            expected_topology: Vec::new(),
+            topology_mismatch: MismatchPolicy::default(),
+            dc: DcConfig::default(),
+            stats_publish_interval: default_stats_publish_interval(),
+            shutdown_timeout: default_shutdown_timeout(),
+            esi_directory: default_esi_directory(),
+            drivers: Vec::new(),
+        }
+    }
+}
+
+/// Command line settings taking precedence over the configuration
+#[derive(Default, Clone)]
+pub struct Overrides {
+    pub interface: Option<String>,
+    /// Cycle time of the default group
+    pub cycle_time: Option<Duration>,
+    /// Set up the subdevices but leave them in pre-op
+    pub dry_run: bool,
+}
+
+/// Driver bound to a position in the segment
+pub(crate) struct DeviceSlot {
+    pub device: Box<dyn Device + Send + Sync>,
+    /// Identity of the subdevice the driver was bound to, None for an empty position
+    pub identity: Option<Identity>,
+    /// Setup is skipped on re-init for subdevices that were reachable the whole time
+    pub needs_setup: bool,
+    /// Setup of an optional device failed, the driver only writes its safe outputs
+    pub faulted: bool,
+    pub status: Option<DeviceStatus>,
+    /// SDO requests for the position, kept when the driver changes
+    pub sdo: Option<SdoAccess>,
+}
+impl Default for DeviceSlot {
+    fn default() -> Self {
+        Self {
+            device: Box::new(UnimplementedDevice),
+            identity: None,
+            needs_setup: true,
+            faulted: false,
+            status: None,
+            sdo: None,
+        }
+    }
+}
+impl DeviceSlot {
+    /// Cyclic exchange of the driver, a faulted one holds its safe outputs
+    pub async fn process_data<'maindevice, 'group>(
+        &mut self,
+        subdevice: &mut SubDeviceRef<'maindevice, SubDevicePdi<'group>>,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        if self.faulted {
+            self.device.safe_outputs(subdevice);
+            self.device.publish_safe_outputs().await;
+            return Ok(());
+        }
+        self.device.process_data(subdevice).await
+    }
+    /// SYNC0 is left off for a faulted device, its setup may not have completed
+    pub fn dc_sync(&self) -> DcSync {
+        if self.faulted {
+            DcSync::Disabled
+        } else {
+            self.device.dc_sync()
         }
     }
 }
 
-pub struct Bus {
+pub struct Bus<const MAX_SUBDEVICES: usize, const PDI_LEN: usize> {
     main_device: Arc<MainDevice<'static>>,
     config: ConfMan<BusConfig>,
-    devices: [Box<dyn Device + Send + Sync>; MAX_SUBDEVICES],
-    group: Option<SubDeviceGroup<MAX_SUBDEVICES, PDI_LEN, ethercrab::subdevice_group::Op>>,
+    /// Drivers by segment position, the ones in a running group are lent to its runner
+    devices: [DeviceSlot; MAX_SUBDEVICES],
+    runners: Vec<GroupRunner<MAX_SUBDEVICES, PDI_LEN>>,
     log_key: String,
-    expected_working_counter: u16,
+    topology: Option<TopologyMonitor>,
+    topology_signals: TopologySignals,
+    dc_signals: Arc<Mutex<DcSignals>>,
+    /// Statistics by group name, kept across re-init so the signals are only created once
+    stats: HashMap<String, CycleStats>,
+    stats_reset: Arc<AtomicU64>,
+    /// Set when the process is asked to stop, the groups then send their safe outputs and the bus is not re-initialized
+    shutdown: watch::Receiver<bool>,
+    overrides: Overrides,
+    /// Drivers by identity, with the ESI descriptions for subdevices without one
+    registry: DeviceRegistry,
     #[cfg(feature = "opcua-expose")]
     opcua_handle: OpcuaServerHandle,
 }
 
-impl Bus {
-    pub fn new(
+/// Create the bus with the capacity of the configured profile and keep it running until shutdown is set
+pub async fn init_and_run(
+    dbus: zbus::Connection,
+    shutdown: watch::Receiver<bool>,
+    overrides: Overrides,
+    registry: DeviceRegistry,
+    #[cfg(feature = "opcua-expose")] opcua_handle: OpcuaServerHandle,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let config = ConfMan::<BusConfig>::new(dbus.clone(), "bus");
+    let profile = config.read().profile;
+    info!(target: "ethercat", "Bus profile: {:?}", profile);
+    let source = SegmentSource {
+        interface: overrides
+            .interface
+            .clone()
+            .unwrap_or_else(|| config.read().interface.clone()),
+        #[cfg(feature = "simulation")]
+        simulation: config.read().simulation.clone(),
+    };
+    let main_device = main_device(
+        &source,
+        MainDeviceConfig {
+            dc_static_sync_iterations: config.read().dc.static_sync_iterations,
+            ..MainDeviceConfig::default()
+        },
+    );
+    match profile {
+        BusProfile::Small => {
+            Bus::<16, 1024>::new(
+                dbus.clone(),
+                config,
+                main_device,
+                shutdown,
+                overrides,
+                registry,
+                #[cfg(feature = "opcua-expose")]
+                opcua_handle,
+            )
+            .init_and_run(dbus)
+            .await
+        }
+        BusProfile::Medium => {
+            Bus::<64, 4096>::new(
+                dbus.clone(),
+                config,
+                main_device,
+                shutdown,
+                overrides,
+                registry,
+                #[cfg(feature = "opcua-expose")]
+                opcua_handle,
+            )
+            .init_and_run(dbus)
+            .await
+        }
+        BusProfile::Large => {
+            Bus::<128, 8192>::new(
+                dbus.clone(),
+                config,
+                main_device,
+                shutdown,
+                overrides,
+                registry,
+                #[cfg(feature = "opcua-expose")]
+                opcua_handle,
+            )
+            .init_and_run(dbus)
+            .await
+        }
+    }
+}
+
+/// Where the main device exchanges its frames
+pub struct SegmentSource {
+    pub interface: String,
+    /// Runs against an in-process segment instead of the interface when not empty
+    #[cfg(feature = "simulation")]
+    pub simulation: Vec<SimulatedSubDevice>,
+}
+
+impl SegmentSource {
+    pub fn new(interface: String) -> Self {
+        Self {
+            interface,
+            #[cfg(feature = "simulation")]
+            simulation: Vec::new(),
+        }
+    }
+}
+
+/// Main device on the interface, or on an in-process segment when subdevices are simulated.
+/// The PDU storage can only be split once, so there is one main device per process
+pub fn main_device(source: &SegmentSource, config: MainDeviceConfig) -> MainDevice<'static> {
+    let (tx, rx, pdu_loop) = PDU_STORAGE.try_split().expect("can only split once");
+    #[cfg(feature = "simulation")]
+    if !source.simulation.is_empty() {
+        info!(target: "ethercat", "Running against simulated segment: {:?}", source.simulation);
+        let segment = Arc::new(std::sync::Mutex::new(Segment::new(&source.simulation)));
+        tokio::spawn(sim::tx_rx_task(segment, tx, rx));
+        return MainDevice::new(pdu_loop, Timeouts::default(), config);
+    }
+    tokio::spawn(
+        tx_rx_task(&source.interface, tx, rx)
+            .expect(format!("spawn TX/RX task failed on interface: {}", source.interface).as_str()),
+    );
+    MainDevice::new(pdu_loop, Timeouts::default(), config)
+}
+
+/// Interface and simulated subdevices from the bus configuration
+pub fn configured_segment(dbus: zbus::Connection) -> SegmentSource {
+    let config = ConfMan::<BusConfig>::new(dbus, "bus");
+    let config = config.read();
+    SegmentSource {
+        interface: config.interface.clone(),
+        #[cfg(feature = "simulation")]
+        simulation: config.simulation.clone(),
+    }
+}
+
+/// Schema of the bus configuration and of every driver configuration, by configuration name
+pub fn config_schemas() -> Vec<(&'static str, RootSchema)> {
+    let mut schemas = vec![("bus", schema_for!(BusConfig))];
+    schemas.extend(crate::devices::device::config_schemas());
+    schemas
+}
+
+impl<const MAX_SUBDEVICES: usize, const PDI_LEN: usize> Bus<MAX_SUBDEVICES, PDI_LEN> {
+    fn new(
         conn: Connection,
+        config: ConfMan<BusConfig>,
+        main_device: MainDevice<'static>,
+        shutdown: watch::Receiver<bool>,
+        overrides: Overrides,
+        mut registry: DeviceRegistry,
         #[cfg(feature = "opcua-expose")] opcua_handle: OpcuaServerHandle,
     ) -> Self {
-        let (tx, rx, pdu_loop) = PDU_STORAGE.try_split().expect("can only split once");
-
-        let main_device = Arc::new(MainDevice::new(
-            pdu_loop,
-            Timeouts::default(),
-            MainDeviceConfig::default(),
-        ));
-
-        let config = ConfMan::<BusConfig>::new(conn.clone(), "bus");
-        tokio::spawn(
-            tx_rx_task(&config.read().interface, tx, rx).expect(
-                format!(
-                    "spawn TX/RX task failed on interface: {}",
-                    config.read().interface
-                )
-                .as_str(),
-            ),
-        );
+        let main_device = Arc::new(main_device);
+        let topology_signals = TopologySignals::new(conn.clone());
+        #[cfg(feature = "opcua-expose")]
+        topology_signals.opcua_register(&opcua_handle);
+        let dc_signals = DcSignals::new(conn.clone());
+        #[cfg(feature = "opcua-expose")]
+        dc_signals.opcua_register(&opcua_handle);
+        registry.set_esi(EsiLibrary::load(Path::new(&config.read().esi_directory)));
+        let stats_reset = Arc::new(AtomicU64::new(0));
+        #[cfg(feature = "dbus-expose")]
+        {
+            let stats_interface = crate::stats::StatsDbusInterface::new(stats_reset.clone());
+            let conn = conn.clone();
+            tokio::spawn(async move {
+                match conn
+                    .object_server()
+                    .at(crate::stats::DBUS_PATH, stats_interface)
+                    .await
+                {
+                    Ok(true) => {}
+                    Ok(false) => {
+                        warn!(target: "ethercat", "Stats interface already registered at {}", crate::stats::DBUS_PATH)
+                    }
+                    Err(e) => {
+                        error!(target: "ethercat", "Error registering object {}: {}", crate::stats::DBUS_PATH, e)
+                    }
+                }
+            });
+        }
         Self {
             main_device,
             config,
-            devices: std::array::from_fn(|_| {
-                Box::new(UnimplementedDevice) as Box<dyn Device + Send + Sync>
-            }),
-            group: None,
+            devices: std::array::from_fn(|_| DeviceSlot::default()),
+            runners: Vec::new(),
             log_key: "ethercat".to_string(),
-            expected_working_counter: 0,
+            topology: None,
+            topology_signals,
+            dc_signals: Arc::new(Mutex::new(dc_signals)),
+            stats: HashMap::new(),
+            stats_reset,
+            shutdown,
+            overrides,
+            registry,
             #[cfg(feature = "opcua-expose")]
             opcua_handle,
         }
@@ -100,136 +451,517 @@ impl Bus {
         dbus: zbus::Connection,
     ) -> Result<(), Box<dyn Error + Send + Sync>> {
         debug!(target: &self.log_key, "Initializing main device");
-        let mut group = self
+        self.topology = None;
+        let count = Command::brd(RegisterAddress::Type.into())
+            .receive_wkc::<u8>(&self.main_device)
+            .await? as usize;
+        if count > MAX_SUBDEVICES {
+            return Err(format!(
+                "Found {} subdevices, the {:?} bus profile supports at most {}, select a larger profile",
+                count,
+                self.config.read().profile,
+                MAX_SUBDEVICES
+            )
+            .into());
+        }
+        let groups_config = self.config.read().groups.clone();
+        validate_groups(&groups_config)?;
+        let mut position = 0;
+        let groups = self
             .main_device
-            .init_single_group::<MAX_SUBDEVICES, PDI_LEN>(ethercat_now)
-            .await?; // BIG TODO HOW CAN I CONFIGURE THIS TIMEOUT, that is putting group into init state? state_transition does not work here
+            .init::<MAX_SUBDEVICES, [SubDeviceGroup<MAX_SUBDEVICES, PDI_LEN>; MAX_GROUPS]>(
+                ethercat_now,
+                |groups, _subdevice| {
+                    let index = group_index(&groups_config, position);
+                    position += 1;
+                    Ok(&groups[index])
+                },
+            )
+            .await
+            .map_err(|e| self.capacity_error(e))?; // BIG TODO HOW CAN I CONFIGURE THIS TIMEOUT, that is putting group into init state? state_transition does not work here
         debug!(target: &self.log_key, "Initialized main device");
 
-        if group.len() < self.config.read().subdevice_min_count as usize {
+        let total: usize = groups.iter().map(|group| group.len()).sum();
+        if total < self.config.read().subdevice_min_count as usize {
             return Err(format!(
                 "Not enough subdevices in init state, expected: {}, got: {}",
                 self.config.read().subdevice_min_count,
-                group.len()
+                total
             )
             .into());
         }
+        // segment position of each subdevice, per group in group order
+        let mut positions: [Vec<usize>; MAX_GROUPS] = Default::default();
+        for position in 0..total {
+            positions[group_index(&groups_config, position)].push(position);
+        }
 
-        let mut index: u16 = 0;
-        for (idx, mut subdevice) in group.iter(&self.main_device).enumerate() {
-            let identity = subdevice.identity();
-            if self.devices[idx].vendor_id() != identity.vendor_id
-                || self.devices[idx].product_id() != identity.product_id
-            {
-                self.devices[idx] = make_device(
-                    dbus.clone(),
-                    identity.vendor_id,
-                    identity.product_id,
-                    index,
-                    subdevice.alias_address(),
-                    subdevice.name(),
+        // every subdevice by segment position, they are independent so all can be borrowed at once
+        let mut subdevices = Vec::with_capacity(total);
+        for (group, positions) in groups.iter().zip(positions.iter()) {
+            subdevices.extend(positions.iter().copied().zip(group.iter(&self.main_device)));
+        }
+        subdevices.sort_by_key(|(position, _)| *position);
+
+        let found: Vec<FoundSubDevice> = subdevices
+            .iter()
+            .map(|(_, subdevice)| FoundSubDevice {
+                identity: Identity::from(subdevice.identity()),
+                alias: subdevice.alias_address(),
+                name: subdevice.name().to_string(),
+            })
+            .collect();
+        let monitored: Vec<(u16, Identity)> = subdevices
+            .iter()
+            .map(|(_, subdevice)| {
+                (
+                    subdevice.configured_address(),
+                    Identity::from(subdevice.identity()),
+                )
+            })
+            .collect();
+        let mismatches = check_expected(&self.config.read().expected_topology, &found);
+        self.topology_signals.publish_mismatches(&mismatches).await;
+        if !mismatches.is_empty() {
+            let report = mismatch_report(&mismatches);
+            if self.config.read().topology_mismatch == MismatchPolicy::Refuse {
+                return Err(
+                    format!("Subdevices do not match expected topology:\n{}", report).into(),
                 );
+            }
+            warn!(target: &self.log_key, "Subdevices do not match expected topology, running degraded:\n{}", report);
+        }
+
+        for (position, subdevice) in subdevices.iter() {
+            let position = *position;
+            let identity = Identity::from(subdevice.identity());
+            let slot = &mut self.devices[position];
+            if mismatches.iter().any(|m| m.position == position) {
+                // degraded, leave the unexpected subdevice without a driver
+                *slot = DeviceSlot {
+                    sdo: slot.sdo.take(),
+                    ..DeviceSlot::default()
+                };
+                slot.identity = None;
+                slot.needs_setup = false;
+                continue;
+            }
+            let driver = self
+                .config
+                .read()
+                .drivers
+                .iter()
+                .find(|binding| binding.position == position)
+                .map(|binding| binding.driver.clone());
+            let rebound = driver.as_deref().is_some_and(|driver| {
+                driver != slot.device.name() && self.registry.by_name(driver).is_some()
+            });
+            if slot.identity != Some(identity) || rebound {
+                // a replacement of the same revision keeps its driver and signals
+                if rebound
+                    || slot.device.vendor_id() != identity.vendor_id
+                    || slot.device.product_id() != identity.product_id
+                    || slot.identity.map(|i| i.revision) != Some(identity.revision)
+                {
+                    // the old driver releases its signal names first
+                    slot.device = Box::new(UnimplementedDevice);
+                    slot.device = self.registry.make_device(
+                        dbus.clone(),
+                        (identity.vendor_id, identity.product_id, identity.revision),
+                        position as u16,
+                        subdevice.alias_address(),
+                        subdevice.name(),
+                        driver.as_deref(),
+                    );
+                    #[cfg(feature = "opcua-expose")]
+                    slot.device.opcua_register(
+                        self.opcua_handle.manager.clone(),
+                        self.opcua_handle.subscriptions.clone(),
+                        self.opcua_handle.namespace,
+                    )?;
+                }
+                slot.identity = Some(identity);
+                slot.needs_setup = true;
+            }
+            if !slot.needs_setup {
+                trace!(target: &self.log_key, "Subdevice {} unchanged, skipping setup", position);
+            }
+        }
+
+        // every position gets a status, it is replaced when a different driver takes the position
+        for (position, slot) in self.devices.iter_mut().enumerate().take(total) {
+            let name = format!("{}/{}", slot.device.name(), position);
+            if slot
+                .status
+                .as_ref()
+                .map_or(true, |status| status.name() != name)
+            {
+                let status = DeviceStatus::new(dbus.clone(), slot.device.name(), position);
                 #[cfg(feature = "opcua-expose")]
-                self.devices[idx].opcua_register(
-                    self.opcua_handle.manager.clone(),
-                    self.opcua_handle.subscriptions.clone(),
-                    self.opcua_handle.namespace,
-                )?;
-            }
-            // TODO: Make futures that can be awaited in parallel
-            self.devices[idx].setup(&mut subdevice).await.map_err(|e| {
-                warn!(target: &self.log_key, "Failed to setup device {}: {}", index, e);
-                e
-            })?;
-            index += 1;
+                status.opcua_register(&self.opcua_handle);
+                slot.status = Some(status);
+            }
+            if slot.sdo.is_none() {
+                let sdo = SdoAccess::new(dbus.clone(), position);
+                #[cfg(feature = "opcua-expose")]
+                sdo.opcua_register(&self.opcua_handle).await;
+                slot.sdo = Some(sdo);
+            }
+        }
+
+        // setup is mostly SDO round trips, run it for several subdevices at a time.
+        // positions are 0..total, so the drivers line up with the sorted subdevices
+        let setup_concurrency = self.config.read().setup_concurrency.max(1);
+        let results: Vec<(usize, Result<(), Box<dyn Error + Send + Sync>>)> = stream::iter(
+            self.devices
+                .iter_mut()
+                .zip(subdevices.iter_mut())
+                .filter(|(slot, _)| slot.needs_setup),
+        )
+        .map(|(slot, (position, subdevice))| async move {
+            (
+                *position,
+                slot.device.setup(&mut SetupRef::PreOp(subdevice)).await,
+            )
+        })
+        .buffer_unordered(setup_concurrency)
+        .collect()
+        .await;
+        let optional_positions = self.config.read().optional_positions.clone();
+        let mut failures = Vec::new();
+        for (position, result) in results {
+            let slot = &mut self.devices[position];
+            match result {
+                Ok(()) => {
+                    slot.needs_setup = false;
+                    slot.faulted = false;
+                    if let Some(status) = slot.status.as_mut() {
+                        status.set_ok().await;
+                    }
+                }
+                Err(e) => {
+                    if let Some(status) = slot.status.as_mut() {
+                        status.set_faulted(&e.to_string()).await;
+                    }
+                    if optional_positions.contains(&position) {
+                        // setup is tried again on the next init
+                        warn!(target: &self.log_key, "Failed to setup optional device {}, holding its safe outputs: {}", position, e);
+                        slot.faulted = true;
+                    } else {
+                        warn!(target: &self.log_key, "Failed to setup device {}: {}", position, e);
+                        failures.push((position, e));
+                    }
+                }
+            }
+        }
+        if !failures.is_empty() {
+            failures.sort_by_key(|(position, _)| *position);
+            return Err(format!(
+                "Setup failed for {} subdevices:\n{}",
+                failures.len(),
+                failures
+                    .iter()
+                    .map(|(position, e)| format!("position {}: {}", position, e))
+                    .collect::<Vec<_>>()
+                    .join("\n")
+            )
+            .into());
+        }
+
+        let dc_enabled = self.config.read().dc.enabled;
+        if dc_enabled {
+            for (slot, (_, subdevice)) in self.devices.iter_mut().zip(subdevices.iter_mut()) {
+                subdevice.set_dc_sync(slot.dc_sync());
+            }
+        }
+        drop(subdevices);
+        trace!(target: &self.log_key, "Setup complete for devices: {}", total);
+        // positions which are no longer present
+        for slot in self.devices.iter_mut().skip(total) {
+            if slot.identity.is_some() {
+                *slot = DeviceSlot {
+                    sdo: slot.sdo.take(),
+                    ..DeviceSlot::default()
+                };
+            }
+        }
+        self.topology_signals.publish_count(total).await;
+        if self.overrides.dry_run {
+            info!(target: &self.log_key, "Dry run, leaving {} subdevices in pre-op", total);
+            return Ok(());
         }
-        trace!(target: &self.log_key, "Setup complete for devices: {}", index);
 
-        // let group = group.into_op(&self.main_device).await?;
+        // every group is brought to operational before any driver is handed to a runner,
+        // so a failing transition leaves all drivers with the bus
+        let mut op_groups = Vec::with_capacity(MAX_GROUPS);
+        for (group_id, (group, positions)) in groups.into_iter().zip(positions).enumerate() {
+            if positions.is_empty() {
+                continue;
+            }
+            let (name, cycle_time): (String, Duration) = match group_id {
+                0 => (
+                    "default".to_string(),
+                    self.overrides
+                        .cycle_time
+                        .unwrap_or_else(|| self.config.read().cycle_time.into()),
+                ),
+                _ => {
+                    let group_config = &groups_config[group_id - 1];
+                    (group_config.name.clone(), group_config.cycle_time.into())
+                }
+            };
+
+            // let group = group.into_op(&self.main_device).await?;
+
+            let (group, expected_working_counter) = if dc_enabled {
+                let dc_configuration = self.config.read().dc.configuration(cycle_time);
+                let group = group
+                    .into_pre_op_pdi(&self.main_device)
+                    .await
+                    .map_err(|e| self.capacity_error(e))?
+                    .configure_dc_sync(&self.main_device, dc_configuration)
+                    .await?
+                    .into_safe_op(&self.main_device)
+                    .await?;
 
-        let group = group.into_safe_op(&self.main_device).await?;
+                debug!(target: &self.log_key, "Group {} in safe op with distributed clocks", name);
 
-        debug!(target: &self.log_key, "Group in safe op");
+                let expected_working_counter = group.tx_rx(&self.main_device).await?;
+                info!(target: &self.log_key, "Group {} in safe op Tx/Rx complete, now will expect working counter to be: {}", name, expected_working_counter);
 
-        self.expected_working_counter = group.tx_rx(&self.main_device).await?;
-        info!(target: &self.log_key, "Group in safe op Tx/Rx complete, now will expect working counter to be: {}", self.expected_working_counter);
+                (
+                    OpGroup::Dc(group.into_op(&self.main_device).await?),
+                    expected_working_counter,
+                )
+            } else {
+                let group = group
+                    .into_safe_op(&self.main_device)
+                    .await
+                    .map_err(|e| self.capacity_error(e))?;
+
+                debug!(target: &self.log_key, "Group {} in safe op", name);
+
+                let expected_working_counter = group.tx_rx(&self.main_device).await?;
+                info!(target: &self.log_key, "Group {} in safe op Tx/Rx complete, now will expect working counter to be: {}", name, expected_working_counter);
 
-        let group = group.into_op(&self.main_device).await?;
+                (
+                    OpGroup::NoDc(group.into_op(&self.main_device).await?),
+                    expected_working_counter,
+                )
+            };
 
-        debug!(target: &self.log_key, "Group in operational");
+            debug!(target: &self.log_key, "Group {} in operational", name);
+            op_groups.push((name, group, positions, expected_working_counter, cycle_time));
+        }
 
-        self.group = Some(group);
+        for (name, group, positions, expected_working_counter, cycle_time) in op_groups {
+            let slots = positions
+                .iter()
+                .map(|position| std::mem::take(&mut self.devices[*position]))
+                .collect();
+            let stats = match self.stats.remove(&name) {
+                Some(stats) => stats,
+                None => {
+                    let stats = CycleStats::new(dbus.clone(), &name, self.stats_reset.clone());
+                    #[cfg(feature = "opcua-expose")]
+                    stats.opcua_register(&self.opcua_handle);
+                    stats
+                }
+            };
+            self.runners.push(GroupRunner::new(
+                name,
+                group,
+                positions,
+                slots,
+                expected_working_counter,
+                cycle_time,
+                stats,
+                &self.log_key,
+            ));
+        }
+        if self.config.read().topology.monitor {
+            self.topology = Some(TopologyMonitor::spawn(
+                self.main_device.clone(),
+                monitored,
+                self.config.read().topology.scan_interval.into(),
+                self.log_key.clone(),
+            ));
+        }
         Ok(())
     }
 
+    /// Process data is mapped when the group leaves pre-op, running out of room there means the profile is too small
+    fn capacity_error(&self, e: ethercrab::error::Error) -> Box<dyn Error + Send + Sync> {
+        match e {
+            ethercrab::error::Error::Capacity(item) => format!(
+                "{:?} capacity exceeded, the {:?} bus profile supports {} subdevices and {} bytes of process data per group, select a larger profile",
+                item,
+                self.config.read().profile,
+                MAX_SUBDEVICES,
+                PDI_LEN
+            )
+            .into(),
+            e => e.into(),
+        }
+    }
+
+    /// Runs every group in its own task until one of them fails or subdevices are added with
+    /// re-init on added configured, then stops the others, takes the drivers back and walks every
+    /// group to init
     pub async fn run(&mut self) -> Result<(), Box<dyn Error + Send + Sync>> {
-        let ref mut group = self.group.as_mut().expect("Group not initialized");
-
-        let mut tick_interval = tokio::time::interval(self.config.read().cycle_time.into());
-        info!(target: &self.log_key, "Ethercat tick interval: {:?}", self.config.read().cycle_time);
-        tick_interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Skip);
-        let mut cnt = 0;
-        let mut instant = Instant::now();
-        let mut tx_rx_duration = Duration::ZERO;
-        let mut process_data_duration = Duration::ZERO;
-        let mut device_errors: [Option<Box<dyn Error + Send + Sync>>; MAX_SUBDEVICES] =
-            std::array::from_fn(|_| None);
-        loop {
-            let tx_rx_instant = Instant::now();
-            let wc = group.tx_rx(&self.main_device).await?;
-            tx_rx_duration += tx_rx_instant.elapsed();
-
-            if wc != self.expected_working_counter {
-                // TODO we need to recover less tremeendously than this
-                // https://github.com/ethercrab-rs/ethercrab/discussions/253
-                return Err(format!(
-                    "Working counter mismatch, expected: {}, got: {}",
-                    self.expected_working_counter, wc
-                )
-                .into());
-            }
+        let settings = RunSettings {
+            wkc_error_tolerance: self.config.read().recovery.wkc_error_tolerance,
+            recovery_timeout: self.config.read().recovery.recovery_timeout.into(),
+            dc_publish_interval: self.config.read().dc.publish_interval.into(),
+            stats_publish_interval: self.config.read().stats_publish_interval.into(),
+        };
+        let (stop_tx, stop_rx) = watch::channel(false);
+        let mut tasks = JoinSet::new();
+        for runner in self.runners.drain(..) {
+            tasks.spawn(runner.run(
+                self.main_device.clone(),
+                settings,
+                self.dc_signals.clone(),
+                stop_rx.clone(),
+            ));
+        }
 
-            let process_data_instant = Instant::now();
-            for (device_index, mut subdevice) in group.iter(&self.main_device).enumerate() {
-                if let Some(device) = self.devices.get_mut(device_index) {
-                    match device.process_data(&mut subdevice).await {
-                        Ok(()) => {
-                            device_errors[device_index] = None;
+        let mut result: Result<(), Box<dyn Error + Send + Sync>> = Ok(());
+        let mut lost = Vec::new();
+        let mut groups = Vec::new();
+        let mut topology_check = tokio::time::interval(Duration::from_millis(100));
+        let mut shutdown = self.shutdown.clone();
+        loop {
+            tokio::select! {
+                joined = tasks.join_next() => {
+                    let Some(joined) = joined else {
+                        break;
+                    };
+                    match joined {
+                        Ok((runner, res)) => {
+                            let (name, stats, group, slots) = runner.into_parts();
+                            if let Err(e) = res {
+                                warn!(target: &self.log_key, "Group {} stopped: {}", name, e);
+                                if result.is_ok() {
+                                    result = Err(e);
+                                }
+                            }
+                            for (position, slot) in slots {
+                                self.devices[position] = slot;
+                            }
+                            groups.push((name.clone(), group));
+                            self.stats.insert(name, stats);
                         }
                         Err(e) => {
-                            if device_errors[device_index].is_none() {
-                                warn!(target: &self.log_key, "Failed to process data for subdevice {}: {}", device_index, e);
+                            if result.is_ok() {
+                                result = Err(format!("Group task failed: {}", e).into());
                             }
-                            device_errors[device_index] = Some(e);
                         }
                     }
+                    let _ = stop_tx.send(true);
+                }
+                _ = shutdown.wait_for(|shutdown| *shutdown), if !*stop_tx.borrow() => {
+                    info!(target: &self.log_key, "Shutting down, stopping all groups");
+                    let _ = stop_tx.send(true);
+                }
+                _ = topology_check.tick(), if result.is_ok() => {
+                    // re-powered subdevices are brought back by the recovery of their group,
+                    // added ones need the whole segment initialized again to be mapped and
+                    // replaced ones to get a driver for what is there now
+                    if let Some(change) = self.topology.as_mut().and_then(|t| t.changed()) {
+                        self.topology_signals.publish_change(&change).await;
+                        if !change.replaced.is_empty()
+                            || (change.added() && self.config.read().topology.reinit_on_added)
+                        {
+                            lost = change.lost.iter().chain(change.replaced.iter()).copied().collect();
+                            result = Err(change.into());
+                            let _ = stop_tx.send(true);
+                        }
+                    }
+                }
+            }
+        }
+        for (name, group) in groups {
+            match group.into_init(&self.main_device).await {
+                Ok(()) => info!(target: &self.log_key, "Group {} in init", name),
+                Err(e) => {
+                    warn!(target: &self.log_key, "Group {} failed to go to init: {}", name, e)
                 }
             }
-            process_data_duration += process_data_instant.elapsed();
+        }
+        // only the positions which went missing and new ones get their setup run again
+        for position in lost {
+            if let Some(slot) = self.devices.get_mut(position) {
+                slot.needs_setup = true;
+            }
+        }
+        result
+    }
 
-            tick_interval.tick().await;
-            cnt += 1;
-            if cnt % 1000 == 0 {
-                info!(target: &self.log_key, "Ethercat tick interval: {:?}", instant.elapsed()/1000);
-                info!(target: &self.log_key, "Tx/Rx duration: {:?}", tx_rx_duration/1000);
-                info!(target: &self.log_key, "Process data duration: {:?}", process_data_duration/1000);
-                instant = Instant::now();
-                tx_rx_duration = Duration::ZERO;
-                process_data_duration = Duration::ZERO;
+    /// Keeps the bus running until shutdown is set, then stops it within the shutdown timeout
+    pub async fn init_and_run(
+        &mut self,
+        dbus: zbus::Connection,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let mut shutdown = self.shutdown.clone();
+        let timeout: Duration = self.config.read().shutdown_timeout.into();
+        let log_key = self.log_key.clone();
+        let deadline = async {
+            let _ = shutdown.wait_for(|shutdown| *shutdown).await;
+            tokio::time::sleep(timeout).await;
+        };
+        tokio::select! {
+            result = self.run_until_shutdown(dbus.clone()) => result?,
+            _ = deadline => {
+                warn!(target: &log_key, "Bus did not stop within {:?}, subdevices are left to their watchdogs", timeout);
+            }
+        }
+        self.flush_config();
+        #[cfg(feature = "dbus-expose")]
+        if let Err(e) = dbus
+            .object_server()
+            .remove::<crate::stats::StatsDbusInterface, _>(crate::stats::DBUS_PATH)
+            .await
+        {
+            warn!(target: &self.log_key, "Error removing object {}: {}", crate::stats::DBUS_PATH, e);
+        }
+        #[cfg(feature = "dbus-expose")]
+        for (position, _) in self
+            .devices
+            .iter()
+            .enumerate()
+            .filter(|(_, slot)| slot.sdo.is_some())
+        {
+            let path = format!("{}/{}", crate::sdo::DBUS_PATH, position);
+            if let Err(e) = dbus
+                .object_server()
+                .remove::<crate::sdo::SdoDbusInterface, _>(path.as_str())
+                .await
+            {
+                warn!(target: &self.log_key, "Error removing object {}: {}", path, e);
             }
         }
-        // If I comment the loop out the load falls down to 0 ish %
-        // std::future::pending::<()>().await;
+        Ok(())
+    }
 
-        // Ok(())
+    /// Saves the bus configuration and the configuration of every driver
+    fn flush_config(&self) {
+        self.config.write().value_mut();
+        for slot in self.devices.iter() {
+            slot.device.flush_config();
+        }
+        info!(target: &self.log_key, "Configuration saved");
     }
 
-    pub async fn init_and_run(
+    async fn run_until_shutdown(
         &mut self,
         dbus: zbus::Connection,
     ) -> Result<(), Box<dyn Error + Send + Sync>> {
         loop {
             loop {
+                if *self.shutdown.borrow() {
+                    return Ok(());
+                }
                 let res = self.init(dbus.clone()).await;
                 if let Err(e) = res {
                     warn!(target: &self.log_key, "Failed to init: {}", e);
@@ -238,11 +970,97 @@ impl Bus {
                 }
                 tokio::time::sleep(Duration::from_millis(1000)).await;
             }
+            if self.overrides.dry_run {
+                let mut shutdown = self.shutdown.clone();
+                let _ = shutdown.wait_for(|shutdown| *shutdown).await;
+                return Ok(());
+            }
 
             let _ = self.run().await.map_err(|e| {
                 error!(target: &self.log_key, "Failed to run will retry: {}", e);
+                if e.downcast_ref::<TopologyChange>().is_none() {
+                    // we don't know what happened to the subdevices, set them all up again
+                    for slot in self.devices.iter_mut() {
+                        slot.needs_setup = true;
+                    }
+                }
                 e
             });
+            if *self.shutdown.borrow() {
+                info!(target: &self.log_key, "Bus stopped");
+                return Ok(());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sim::{self, Segment, SimulatedSubDevice};
+
+    const AL_STATE_INIT: u8 = 0x01;
+    const AL_STATE_OP: u8 = 0x08;
+
+    #[tokio::test]
+    async fn test_init_and_run() {
+        let kinds = [
+            SimulatedSubDevice::Ek1100,
+            SimulatedSubDevice::El1008,
+            SimulatedSubDevice::El2794,
+            SimulatedSubDevice::El3356,
+            SimulatedSubDevice::I550,
+        ];
+        let segment = Arc::new(std::sync::Mutex::new(Segment::new(&kinds)));
+        let dbus = sim::dbus().await;
+        let (shutdown_tx, shutdown_rx) = watch::channel(false);
+        let mut bus = Bus::<16, 1024>::new(
+            dbus.clone(),
+            ConfMan::new(dbus.clone(), "bus"),
+            sim::main_device(&segment),
+            shutdown_rx,
+            Overrides::default(),
+            DeviceRegistry::with_builtin(),
+            #[cfg(feature = "opcua-expose")]
+            crate::opcua::test_handle(),
+        );
+
+        bus.init(dbus.clone()).await.expect("init");
+        {
+            let segment = segment.lock().unwrap();
+            for position in 0..kinds.len() {
+                assert_eq!(segment.al_state(position), AL_STATE_OP);
+            }
+            // the PDOs assigned by the setup of the EL2794 and EL3356 drivers
+            assert_eq!(
+                segment.object(2, 0x1C13, 0x01),
+                Some(&0x1A00u16.to_le_bytes()[..])
+            );
+            assert_eq!(
+                segment.object(3, 0x1C13, 0x02),
+                Some(&0x1A02u16.to_le_bytes()[..])
+            );
         }
+
+        let exchanges = segment.lock().unwrap().exchanges();
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(200)).await;
+            let _ = shutdown_tx.send(true);
+        });
+        bus.run().await.expect("run");
+        let segment = segment.lock().unwrap();
+        assert!(segment.exchanges() > exchanges + 10);
+        for position in 0..kinds.len() {
+            assert_eq!(segment.al_state(position), AL_STATE_INIT);
+        }
+        // the drivers are handed back with the subdevices they were bound to
+        assert_eq!(
+            bus.devices
+                .iter()
+                .take(kinds.len())
+                .map(|slot| slot.device.name())
+                .collect::<Vec<_>>(),
+            vec!["Ek1100", "el1008", "el2794", "El3356", "i550"]
+        );
     }
 }
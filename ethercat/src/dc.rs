@@ -0,0 +1,99 @@
+#[cfg(feature = "opcua-expose")]
+use crate::opcua::OpcuaServerHandle;
+use ethercrab::{std::ethercat_now, subdevice_group::CycleInfo, DcConfiguration};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::time::{Duration, Instant};
+use tfc::ipc::{Base, Signal};
+use tfc::time::{MicroDuration, MilliDuration};
+
+/// Distributed clocks, the reference clock is the first subdevice in the segment supporting DC.
+/// Subdevices only run SYNC0 when their driver asks for it, see `Device::dc_sync`.
+#[derive(Deserialize, Serialize, JsonSchema)]
+pub struct DcConfig {
+    #[schemars(
+        description = "Configure distributed clocks and pace the cycle by the reference clock instead of the host timer"
+    )]
+    pub enabled: bool,
+    #[schemars(
+        description = "Number of frames sent during init to compensate the static drift between the subdevice clocks"
+    )]
+    pub static_sync_iterations: u32,
+    #[schemars(
+        description = "Delay from the first SYNC0 pulse relative to the current system time. Milliseconds"
+    )]
+    pub start_delay: MilliDuration,
+    #[schemars(
+        description = "Shift of the SYNC0 pulse relative to the start of the cycle, leaves room for the frame to reach the subdevices. Microseconds"
+    )]
+    pub sync0_shift: MicroDuration,
+    #[schemars(description = "Interval of publishing the system time offset. Milliseconds")]
+    pub publish_interval: MilliDuration,
+}
+impl Default for DcConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            static_sync_iterations: 10000,
+            start_delay: Duration::from_millis(100).into(),
+            sync0_shift: Duration::ZERO.into(),
+            publish_interval: Duration::from_millis(1000).into(),
+        }
+    }
+}
+impl DcConfig {
+    pub fn configuration(&self, cycle_time: Duration) -> DcConfiguration {
+        DcConfiguration {
+            start_delay: self.start_delay.into(),
+            sync0_period: cycle_time,
+            sync0_shift: self.sync0_shift.into(),
+        }
+    }
+}
+
+pub struct DcSignals {
+    system_time_offset: Signal<i64>,
+    last_publish: Instant,
+}
+
+impl DcSignals {
+    pub fn new(dbus: zbus::Connection) -> Self {
+        let system_time_offset = Signal::new(
+            dbus.clone(),
+            Base::new(
+                "dc/system_time_offset",
+                Some("Reference clock system time minus host time. Nanoseconds"),
+            ),
+        );
+        #[cfg(feature = "dbus-expose")]
+        tfc::ipc::dbus::SignalInterface::register(
+            system_time_offset.base(),
+            dbus.clone(),
+            system_time_offset.subscribe(),
+        );
+        Self {
+            system_time_offset,
+            last_publish: Instant::now(),
+        }
+    }
+    #[cfg(feature = "opcua-expose")]
+    pub fn opcua_register(&self, handle: &OpcuaServerHandle) {
+        tfc::ipc::opcua::SignalInterface::new(
+            self.system_time_offset.base(),
+            self.system_time_offset.subscribe(),
+            handle.manager.clone(),
+            handle.subscriptions.clone(),
+            handle.namespace,
+        )
+        .register();
+    }
+    /// Called every cycle, the offset is only published once per interval
+    pub async fn cycle(&mut self, cycle_info: &CycleInfo, interval: Duration) {
+        if self.last_publish.elapsed() < interval {
+            return;
+        }
+        self.last_publish = Instant::now();
+        let offset = cycle_info.dc_system_time as i64 - ethercat_now() as i64;
+        let _ = self.system_time_offset.async_send(offset).await;
+    }
+}
@@ -84,7 +84,7 @@ impl ControlWord {
             ..Default::default()
         }
     }
-    fn quick_stop() -> Self {
+    pub fn quick_stop() -> Self {
         Self {
             enable_voltage: true,
             ..Default::default()
@@ -1,7 +1,7 @@
-use crate::devices::device_trait::{Device, DeviceInfo};
+use crate::devices::device::{DeviceRegistry, Registration};
+use crate::devices::device_trait::{Device, DeviceInfo, SetupRef};
 use async_trait::async_trait;
-use atomic_refcell::AtomicRefMut;
-use ethercrab::{SubDevice, SubDevicePdi, SubDeviceRef};
+use ethercrab::{SubDevicePdi, SubDeviceRef};
 #[cfg(feature = "opcua-expose")]
 use opcua::server::{
     node_manager::memory::{InMemoryNodeManager, SimpleNodeManagerImpl},
@@ -12,11 +12,15 @@ use std::sync::Arc;
 
 pub struct Ek1100;
 
+pub fn register(registry: &mut DeviceRegistry) {
+    registry.register(Registration::new::<Ek1100>(|_, _, _| Box::new(Ek1100)));
+}
+
 #[async_trait]
 impl Device for Ek1100 {
     async fn setup<'maindevice, 'group>(
         &mut self,
-        device: &mut SubDeviceRef<'maindevice, AtomicRefMut<'group, SubDevice>>,
+        device: &mut SetupRef<'_, 'maindevice, 'group>,
     ) -> Result<(), Box<dyn Error + Send + Sync>> {
         Ok(())
     }
@@ -32,6 +36,9 @@ impl Device for Ek1100 {
     fn product_id(&self) -> u32 {
         Self::PRODUCT_ID
     }
+    fn name(&self) -> &'static str {
+        Self::NAME
+    }
     #[cfg(feature = "opcua-expose")]
     fn opcua_register(
         &mut self,
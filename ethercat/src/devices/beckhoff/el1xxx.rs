@@ -1,28 +1,151 @@
-use crate::devices::device_trait::{Device, DeviceInfo};
+use crate::devices::device::{DeviceRegistry, Registration};
+use crate::devices::device_trait::{Device, DeviceInfo, SetupRef};
+use crate::devices::naming::{Names, Naming};
 use async_trait::async_trait;
-use atomic_refcell::AtomicRefMut;
 use bitvec::view::BitView;
-use ethercrab::{SubDevice, SubDevicePdi, SubDeviceRef};
-use log::error;
+use ethercrab::{SubDevicePdi, SubDeviceRef};
+use log::{error, warn};
 #[cfg(feature = "opcua-expose")]
 use opcua::server::{
     node_manager::memory::{InMemoryNodeManager, SimpleNodeManagerImpl},
     SubscriptionCache,
 };
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
 use std::error::Error;
 use std::marker::PhantomData;
 use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tfc::confman::ConfMan;
 use tfc::ipc::{Base, Signal};
+use tfc::time::MilliDuration;
 
 pub type El1002 = El1xxx<El1002Info, 2, 1>;
 pub type El1008 = El1xxx<El1008Info, 8, 1>;
 pub type El1809 = El1xxx<El1809Info, 16, 2>;
 
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+enum Debounce {
+    #[schemars(
+        description = "Time the input must be stable before a change is published. Milliseconds"
+    )]
+    Time(MilliDuration),
+    #[schemars(description = "Number of consecutive bus cycles the input must be stable")]
+    Cycles(u32),
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Default)]
+struct InputConfig {
+    #[serde(default)]
+    #[schemars(description = "Publish the inverted input, e.g. for NPN sensors")]
+    invert: bool,
+    #[serde(default)]
+    #[schemars(description = "Software debounce, none publishes every change")]
+    debounce: Option<Debounce>,
+    #[serde(default)]
+    #[schemars(
+        description = "Publish the number of rising edges, after inversion and debounce, as <input>/rising_edges"
+    )]
+    count_rising_edges: bool,
+    #[serde(default)]
+    #[schemars(
+        description = "Publish the number of falling edges, after inversion and debounce, as <input>/falling_edges"
+    )]
+    count_falling_edges: bool,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Default)]
+struct Config {
+    #[serde(default)]
+    #[schemars(description = "Signal names by input, e.g. I3, takes effect on restart")]
+    names: Names,
+    #[serde(default)]
+    #[schemars(description = "Input filtering by input, e.g. I3, takes effect on restart")]
+    inputs: BTreeMap<String, InputConfig>,
+}
+
+/// Holds back a changed input until it has been stable for the configured time or cycles
+#[derive(Default)]
+struct Debouncer {
+    /// Changed value, when it was first seen and the number of cycles it has been seen
+    pending: Option<(bool, Instant, u32)>,
+}
+
+impl Debouncer {
+    fn reset(&mut self) {
+        self.pending = None;
+    }
+    /// Whether the changed value is to be published
+    fn stable(&mut self, value: bool, debounce: Option<Debounce>, now: Instant) -> bool {
+        let (since, cycles) = match self.pending {
+            Some((pending, since, cycles)) if pending == value => (since, cycles + 1),
+            _ => (now, 1),
+        };
+        let stable = match debounce {
+            None => true,
+            Some(Debounce::Time(time)) => now.duration_since(since) >= Duration::from(time),
+            Some(Debounce::Cycles(count)) => cycles >= count,
+        };
+        self.pending = if stable {
+            None
+        } else {
+            Some((value, since, cycles))
+        };
+        stable
+    }
+}
+
+/// Number of edges in one direction
+struct EdgeCounter {
+    signal: Signal<u64>,
+    count: u64,
+}
+
+impl EdgeCounter {
+    fn new(dbus: zbus::Connection, naming: &mut Naming, channel: &str, description: &str) -> Self {
+        let (name, description) = naming.channel(channel, Some(description));
+        let signal = Signal::new(dbus.clone(), Base::new(name.as_str(), description.as_deref()));
+        #[cfg(feature = "dbus-expose")]
+        tfc::ipc::dbus::SignalInterface::register(signal.base(), dbus, signal.subscribe());
+        Self { signal, count: 0 }
+    }
+}
+
+/// Filtering of one input
+struct Channel {
+    input: InputConfig,
+    debouncer: Debouncer,
+    rising_edges: Option<EdgeCounter>,
+    falling_edges: Option<EdgeCounter>,
+}
+
+pub fn config_schema() -> schemars::schema::RootSchema {
+    schemars::schema_for!(Config)
+}
+
+pub fn register(registry: &mut DeviceRegistry) {
+    registry.register(Registration::new::<El1002Info>(|dbus, number, alias| {
+        Box::new(El1002::new(dbus, number, alias))
+    }));
+    registry.register(Registration::new::<El1008Info>(|dbus, number, alias| {
+        Box::new(El1008::new(dbus, number, alias))
+    }));
+    registry.register(Registration::new::<El1809Info>(|dbus, number, alias| {
+        Box::new(El1809::new(dbus, number, alias))
+    }));
+}
+
 // todo use this: https://github.com/rust-lang/rust/issues/76560
 pub struct El1xxx<D: DeviceInfo + Entries<N>, const N: usize, const ARR_LEN: usize> {
     signals: [Signal<bool>; N],
     last_bits: [Option<bool>; N],
+    channels: [Channel; N],
     log_key: String,
+    // the configuration and names are held so they stay registered
+    config: ConfMan<Config>,
+    _naming: Naming,
     _marker: PhantomData<D>,
     error: bool,
 }
@@ -34,22 +157,60 @@ impl<D: DeviceInfo + Entries<N>, const N: usize, const ARR_LEN: usize> El1xxx<D,
         if _subdevice_alias != 0 {
             prefix = format!("{}/alias/{_subdevice_alias}", D::NAME);
         }
+        let config: ConfMan<Config> = ConfMan::new(dbus.clone(), &prefix);
+        let mut naming = Naming::new(&prefix, &config.read().names);
+        let signals = core::array::from_fn(|idx| {
+            let (name, description) = naming.channel(&format!("I{}", D::ENTRIES[idx]), None);
+            let signal = Signal::new(
+                dbus.clone(),
+                Base::new(name.as_str(), description.as_deref()),
+            );
+            #[cfg(feature = "dbus-expose")]
+            tfc::ipc::dbus::SignalInterface::register(
+                signal.base(),
+                dbus.clone(),
+                signal.subscribe(),
+            );
+            signal
+        });
+        let inputs = config.read().inputs.clone();
+        let channels = core::array::from_fn(|idx| {
+            let channel = format!("I{}", D::ENTRIES[idx]);
+            let input = inputs.get(&channel).cloned().unwrap_or_default();
+            Channel {
+                rising_edges: input.count_rising_edges.then(|| {
+                    EdgeCounter::new(
+                        dbus.clone(),
+                        &mut naming,
+                        &format!("{channel}/rising_edges"),
+                        "Number of rising edges since start",
+                    )
+                }),
+                falling_edges: input.count_falling_edges.then(|| {
+                    EdgeCounter::new(
+                        dbus.clone(),
+                        &mut naming,
+                        &format!("{channel}/falling_edges"),
+                        "Number of falling edges since start",
+                    )
+                }),
+                input,
+                debouncer: Debouncer::default(),
+            }
+        });
+        for channel in inputs.keys() {
+            if !D::ENTRIES.iter().any(|entry| *channel == format!("I{entry}")) {
+                warn!(target: &log_key, "Input filter configured for unknown input {}", channel);
+            }
+        }
+        naming.check_unused();
         Self {
-            signals: core::array::from_fn(|idx| {
-                let signal = Signal::new(
-                    dbus.clone(),
-                    Base::new(format!("{prefix}/I{}", D::ENTRIES[idx]).as_str(), None),
-                );
-                #[cfg(feature = "dbus-expose")]
-                tfc::ipc::dbus::SignalInterface::register(
-                    signal.base(),
-                    dbus.clone(),
-                    signal.subscribe(),
-                );
-                signal
-            }),
+            signals,
             last_bits: [None; N],
+            channels,
             log_key,
+            config,
+            _naming: naming,
             _marker: PhantomData,
             error: false,
         }
@@ -62,7 +223,7 @@ impl<D: DeviceInfo + Entries<N> + Send + Sync, const N: usize, const ARR_LEN: us
 {
     async fn setup<'maindevice, 'group>(
         &mut self,
-        _device: &mut SubDeviceRef<'maindevice, AtomicRefMut<'group, SubDevice>>,
+        _device: &mut SetupRef<'_, 'maindevice, 'group>,
     ) -> Result<(), Box<dyn Error + Send + Sync>> {
         Ok(())
     }
@@ -84,11 +245,38 @@ impl<D: DeviceInfo + Entries<N> + Send + Sync, const N: usize, const ARR_LEN: us
         self.error = false;
 
         let input_bits = input_data.view_bits::<bitvec::order::Lsb0>();
+        let now = Instant::now();
 
         for idx in 0..N {
-            let bit = input_bits[idx];
-            if self.last_bits[idx].is_none() || self.last_bits[idx].unwrap() != bit {
-                let _ = self.signals[idx].async_send(bit).await.map_err(|e| {
+            let channel = &mut self.channels[idx];
+            let bit = input_bits[idx] ^ channel.input.invert;
+            let changed = match self.last_bits[idx] {
+                None => true,
+                Some(last) if last == bit => {
+                    channel.debouncer.reset();
+                    false
+                }
+                Some(_) => channel.debouncer.stable(bit, channel.input.debounce, now),
+            };
+            if !changed {
+                continue;
+            }
+            let _ = self.signals[idx].async_send(bit).await.map_err(|e| {
+                error!("Error sending signal {}: {}", self.log_key, e);
+                e
+            });
+            let first = self.last_bits[idx].is_none();
+            // the counters are published at start and on each edge
+            let counters = match self.last_bits[idx] {
+                None => [channel.rising_edges.as_mut(), channel.falling_edges.as_mut()],
+                Some(_) if bit => [channel.rising_edges.as_mut(), None],
+                Some(_) => [None, channel.falling_edges.as_mut()],
+            };
+            for counter in counters.into_iter().flatten() {
+                if !first {
+                    counter.count += 1;
+                }
+                let _ = counter.signal.async_send(counter.count).await.map_err(|e| {
                     error!("Error sending signal {}: {}", self.log_key, e);
                     e
                 });
@@ -104,6 +292,12 @@ impl<D: DeviceInfo + Entries<N> + Send + Sync, const N: usize, const ARR_LEN: us
     fn product_id(&self) -> u32 {
         D::PRODUCT_ID
     }
+    fn name(&self) -> &'static str {
+        D::NAME
+    }
+    fn flush_config(&self) {
+        self.config.write().value_mut();
+    }
     #[cfg(feature = "opcua-expose")]
     fn opcua_register(
         &mut self,
@@ -121,6 +315,21 @@ impl<D: DeviceInfo + Entries<N> + Send + Sync, const N: usize, const ARR_LEN: us
             )
             .register();
         }
+        for channel in self.channels.iter() {
+            for counter in [&channel.rising_edges, &channel.falling_edges]
+                .into_iter()
+                .flatten()
+            {
+                tfc::ipc::opcua::SignalInterface::new(
+                    counter.signal.base(),
+                    counter.signal.subscribe(),
+                    manager.clone(),
+                    subscriptions.clone(),
+                    namespace,
+                )
+                .register();
+            }
+        }
         Ok(())
     }
 }
@@ -157,3 +366,30 @@ impl Entries<8> for El1008Info {
 impl Entries<16> for El1809Info {
     const ENTRIES: [u8; 16] = [1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16];
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_debounce() {
+        let start = Instant::now();
+        let mut debouncer = Debouncer::default();
+        let cycles = Some(Debounce::Cycles(3));
+        assert!(!debouncer.stable(true, cycles, start));
+        assert!(!debouncer.stable(true, cycles, start));
+        assert!(debouncer.stable(true, cycles, start));
+        // a bounce restarts the count
+        assert!(!debouncer.stable(false, cycles, start));
+        debouncer.reset();
+        assert!(!debouncer.stable(false, cycles, start));
+
+        let mut debouncer = Debouncer::default();
+        let time = Some(Debounce::Time(Duration::from_millis(20).into()));
+        assert!(!debouncer.stable(true, time, start));
+        assert!(!debouncer.stable(true, time, start + Duration::from_millis(19)));
+        assert!(debouncer.stable(true, time, start + Duration::from_millis(20)));
+
+        assert!(Debouncer::default().stable(true, None, start));
+    }
+}
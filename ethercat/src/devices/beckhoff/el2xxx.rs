@@ -1,28 +1,250 @@
-use crate::devices::device_trait::{Device, DeviceInfo};
+use crate::devices::device::{DeviceRegistry, Registration};
+use crate::devices::device_trait::{Device, DeviceInfo, SetupRef};
+use crate::devices::naming::{Names, Naming};
 use async_trait::async_trait;
-use atomic_refcell::AtomicRefMut;
 use bitvec::view::BitView;
-use ethercrab::{SubDevice, SubDevicePdi, SubDeviceRef};
-use log::error;
+use ethercrab::{SubDevicePdi, SubDeviceRef};
+use log::{error, info, warn};
 #[cfg(feature = "opcua-expose")]
 use opcua::server::{
     node_manager::memory::{InMemoryNodeManager, SimpleNodeManagerImpl},
     SubscriptionCache,
 };
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
 use std::marker::PhantomData;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 use std::{error::Error, sync::atomic::AtomicBool};
-use tfc::ipc::{Base, Slot};
+use tfc::confman::ConfMan;
+use tfc::ipc::{Base, Signal, Slot};
+
+static TX_PDO_ASSIGN: u16 = 0x1C13;
 
 pub type El2794 = El2xxx<El2794Info, 4, 1>;
 pub type El2004 = El2xxx<El2004Info, 4, 1>;
 pub type El2008 = El2xxx<El2008Info, 8, 1>;
 pub type El2809 = El2xxx<El2809Info, 16, 2>;
 
+pub fn register(registry: &mut DeviceRegistry) {
+    registry.register(Registration::new::<El2794Info>(|dbus, number, alias| {
+        Box::new(El2794::new(dbus, number, alias))
+    }));
+    registry.register(Registration::new::<El2004Info>(|dbus, number, alias| {
+        Box::new(El2004::new(dbus, number, alias))
+    }));
+    registry.register(Registration::new::<El2008Info>(|dbus, number, alias| {
+        Box::new(El2008::new(dbus, number, alias))
+    }));
+    registry.register(Registration::new::<El2809Info>(|dbus, number, alias| {
+        Box::new(El2809::new(dbus, number, alias))
+    }));
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Default)]
+struct Config {
+    #[serde(default)]
+    #[schemars(
+        description = "Output values applied when the bus stops or is lost, first entry is output 1, outputs not listed are off"
+    )]
+    safe_outputs: Vec<bool>,
+    #[serde(default)]
+    #[schemars(description = "Slot names by output, e.g. O3, takes effect on restart")]
+    names: Names,
+    #[serde(default)]
+    #[schemars(
+        description = "Pulse and blink commands by output, e.g. O3, takes effect on restart"
+    )]
+    outputs: BTreeMap<String, OutputConfig>,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Clone)]
+struct OutputConfig {
+    #[serde(default)]
+    #[schemars(
+        description = "Add the slot <output>/pulse, the output is on for the received number of milliseconds, a new pulse restarts the time"
+    )]
+    pulse: bool,
+    #[serde(default)]
+    #[schemars(
+        description = "Add the slot <output>/blink, the output blinks with the received period in milliseconds, 0 stops. A pulse takes precedence"
+    )]
+    blink: bool,
+    #[serde(default = "default_blink_duty")]
+    #[schemars(
+        description = "Part of the blink period the output is on. Percent",
+        range(min = 0, max = 100)
+    )]
+    blink_duty: f64,
+}
+fn default_blink_duty() -> f64 {
+    50.0
+}
+impl Default for OutputConfig {
+    fn default() -> Self {
+        Self {
+            pulse: false,
+            blink: false,
+            blink_duty: default_blink_duty(),
+        }
+    }
+}
+
+pub fn config_schema() -> schemars::schema::RootSchema {
+    schemars::schema_for!(Config)
+}
+
+/// Pulse and blink state of one output, advanced on bus cycles
+#[derive(Default)]
+struct Timing {
+    pulse_end: Option<Instant>,
+    /// Blink period and the start of the first period
+    blink: Option<(Duration, Instant)>,
+}
+
+impl Timing {
+    /// Applies the commands received since the last cycle
+    fn command(&mut self, pulse: Option<Duration>, blink_period: Duration, now: Instant) {
+        if let Some(pulse) = pulse {
+            self.pulse_end = Some(now + pulse);
+        }
+        match self.blink {
+            Some((period, _)) if period == blink_period => {}
+            _ if blink_period.is_zero() => self.blink = None,
+            _ => self.blink = Some((blink_period, now)),
+        }
+    }
+    /// Output value, a running pulse wins over blinking which wins over the latched value
+    fn output(&mut self, latched: bool, blink_duty: f64, now: Instant) -> bool {
+        if let Some(end) = self.pulse_end {
+            if now < end {
+                return true;
+            }
+            self.pulse_end = None;
+        }
+        if let Some((period, start)) = self.blink {
+            let phase = now.duration_since(start).as_nanos() % period.as_nanos();
+            return (phase as f64) < period.as_nanos() as f64 * blink_duty / 100.0;
+        }
+        latched
+    }
+}
+
+/// Commands of one output
+struct Channel {
+    output: OutputConfig,
+    /// Requested pulse in milliseconds, 0 when none is pending
+    pulse: Arc<AtomicU64>,
+    /// Blink period in milliseconds, 0 when not blinking
+    blink_period: Arc<AtomicU64>,
+    // the slots are held so they stay registered
+    pulse_slot: Option<Slot<u64>>,
+    blink_slot: Option<Slot<u64>>,
+    timing: Timing,
+}
+
+fn command_slot(
+    dbus: zbus::Connection,
+    naming: &mut Naming,
+    channel: &str,
+    description: &str,
+    value: Arc<AtomicU64>,
+) -> Slot<u64> {
+    let (name, description) = naming.channel(channel, Some(description));
+    let mut slot = Slot::new(
+        dbus.clone(),
+        Base::new(name.as_str(), description.as_deref()),
+    );
+    #[cfg(feature = "dbus-expose")]
+    tfc::ipc::dbus::SlotInterface::register(slot.base(), dbus, slot.channel("dbus"));
+    slot.recv(Box::new(move |new_value| {
+        value.store(*new_value, Ordering::Relaxed);
+    }));
+    slot
+}
+
+/// One signal per output, published on change
+struct OutputSignals<const N: usize> {
+    signals: [Signal<bool>; N],
+    last: [Option<bool>; N],
+}
+
+impl<const N: usize> OutputSignals<N> {
+    fn new(
+        dbus: zbus::Connection,
+        naming: &mut Naming,
+        entries: &[u8; N],
+        suffix: &str,
+        description: &str,
+    ) -> Self {
+        let signals = core::array::from_fn(|idx| {
+            let (name, description) =
+                naming.channel(&format!("O{}/{suffix}", entries[idx]), Some(description));
+            let signal = Signal::new(
+                dbus.clone(),
+                Base::new(name.as_str(), description.as_deref()),
+            );
+            #[cfg(feature = "dbus-expose")]
+            tfc::ipc::dbus::SignalInterface::register(
+                signal.base(),
+                dbus.clone(),
+                signal.subscribe(),
+            );
+            signal
+        });
+        Self {
+            signals,
+            last: [None; N],
+        }
+    }
+    async fn publish(&mut self, values: &[bool; N]) {
+        for (idx, value) in values.iter().enumerate() {
+            if self.last[idx] != Some(*value) {
+                let _ = self.signals[idx].async_send(*value).await.map_err(|e| {
+                    error!("Error sending output signal: {}", e);
+                    e
+                });
+                self.last[idx] = Some(*value);
+            }
+        }
+    }
+    #[cfg(feature = "opcua-expose")]
+    fn opcua_register(
+        &self,
+        manager: &Arc<InMemoryNodeManager<SimpleNodeManagerImpl>>,
+        subscriptions: &Arc<SubscriptionCache>,
+        namespace: u16,
+    ) {
+        for signal in self.signals.iter() {
+            tfc::ipc::opcua::SignalInterface::new(
+                signal.base(),
+                signal.subscribe(),
+                manager.clone(),
+                subscriptions.clone(),
+                namespace,
+            )
+            .register();
+        }
+    }
+}
+
 // todo use this: https://github.com/rust-lang/rust/issues/76560
 pub struct El2xxx<D: DeviceInfo + Entries<N>, const N: usize, const ARR_LEN: usize> {
     slots: [Slot<bool>; N],
+    config: ConfMan<Config>,
     last_bits: [Arc<AtomicBool>; N],
+    channels: [Channel; N],
+    /// Outputs as written to the terminal
+    written: OutputSignals<N>,
+    /// Outputs last written by `safe_outputs`
+    safe_written: [bool; N],
+    faults: Option<OutputSignals<N>>,
+    /// Whether the missing diagnostic inputs have been logged
+    faults_missing: bool,
+    log_key: String,
+    _naming: Naming,
     _marker: PhantomData<D>,
     error: bool,
 }
@@ -30,30 +252,98 @@ pub struct El2xxx<D: DeviceInfo + Entries<N>, const N: usize, const ARR_LEN: usi
 impl<D: DeviceInfo + Entries<N>, const N: usize, const ARR_LEN: usize> El2xxx<D, N, ARR_LEN> {
     pub fn new(dbus: zbus::Connection, subdevice_number: u16, _subdevice_alias: u16) -> Self {
         let last_bits = core::array::from_fn(|_| Arc::new(AtomicBool::new(false)));
+        let log_key = format!("{}:{}", D::NAME, subdevice_number);
         let mut prefix = format!("{}/{subdevice_number}", D::NAME);
         if _subdevice_alias != 0 {
             prefix = format!("{}/alias/{_subdevice_alias}", D::NAME);
         }
+        let config: ConfMan<Config> = ConfMan::new(dbus.clone(), &prefix);
+        let mut naming = Naming::new(&prefix, &config.read().names);
+        let slots = core::array::from_fn(|idx| {
+            let (name, description) = naming.channel(&format!("O{}", D::ENTRIES[idx]), None);
+            let mut slot = Slot::new(
+                dbus.clone(),
+                Base::new(name.as_str(), description.as_deref()),
+            );
+            #[cfg(feature = "dbus-expose")]
+            tfc::ipc::dbus::SlotInterface::register(
+                slot.base(),
+                dbus.clone(),
+                slot.channel("dbus"),
+            );
+            let last_bit = last_bits[idx].clone();
+            slot.recv(Box::new(move |bit| {
+                last_bit.store(*bit, std::sync::atomic::Ordering::Relaxed);
+            }));
+            slot
+        });
+        let outputs = config.read().outputs.clone();
+        let channels = core::array::from_fn(|idx| {
+            let channel = format!("O{}", D::ENTRIES[idx]);
+            let output = outputs.get(&channel).cloned().unwrap_or_default();
+            let pulse = Arc::new(AtomicU64::new(0));
+            let blink_period = Arc::new(AtomicU64::new(0));
+            Channel {
+                pulse_slot: output.pulse.then(|| {
+                    command_slot(
+                        dbus.clone(),
+                        &mut naming,
+                        &format!("{channel}/pulse"),
+                        "Turn the output on for this number of milliseconds",
+                        pulse.clone(),
+                    )
+                }),
+                blink_slot: output.blink.then(|| {
+                    command_slot(
+                        dbus.clone(),
+                        &mut naming,
+                        &format!("{channel}/blink"),
+                        "Blink the output with this period in milliseconds, 0 stops",
+                        blink_period.clone(),
+                    )
+                }),
+                output,
+                pulse,
+                blink_period,
+                timing: Timing::default(),
+            }
+        });
+        for channel in outputs.keys() {
+            if !D::ENTRIES
+                .iter()
+                .any(|entry| *channel == format!("O{entry}"))
+            {
+                warn!(target: &log_key, "Commands configured for unknown output {}", channel);
+            }
+        }
+        let written = OutputSignals::new(
+            dbus.clone(),
+            &mut naming,
+            &D::ENTRIES,
+            "state",
+            "Output state as written to the terminal",
+        );
+        let faults = D::DIAGNOSTICS.is_some().then(|| {
+            OutputSignals::new(
+                dbus.clone(),
+                &mut naming,
+                &D::ENTRIES,
+                "fault",
+                "Diagnostic input of the output, e.g. short circuit",
+            )
+        });
+        naming.check_unused();
         Self {
-            slots: core::array::from_fn(|idx| {
-                let mut slot = Slot::new(
-                    dbus.clone(),
-                    Base::new(format!("{prefix}/O{}", D::ENTRIES[idx]).as_str(), None),
-                );
-                #[cfg(feature = "dbus-expose")]
-                tfc::ipc::dbus::SlotInterface::register(
-                    slot.base(),
-                    dbus.clone(),
-                    slot.channel("dbus"),
-                );
-                let last_bit = last_bits[idx].clone();
-                slot.recv(Box::new(move |bit| {
-                    last_bit.store(*bit, std::sync::atomic::Ordering::Relaxed);
-                }));
-                slot
-            }),
+            slots,
+            config,
             last_bits,
-            // log_key,
+            channels,
+            written,
+            safe_written: [false; N],
+            faults,
+            faults_missing: false,
+            log_key,
+            _naming: naming,
             _marker: PhantomData,
             error: false,
         }
@@ -66,8 +356,13 @@ impl<D: DeviceInfo + Entries<N> + Send + Sync, const N: usize, const ARR_LEN: us
 {
     async fn setup<'maindevice, 'group>(
         &mut self,
-        _device: &mut SubDeviceRef<'maindevice, AtomicRefMut<'group, SubDevice>>,
+        device: &mut SetupRef<'_, 'maindevice, 'group>,
     ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        if let Some(diagnostics) = D::DIAGNOSTICS {
+            device
+                .assign_pdos(TX_PDO_ASSIGN, diagnostics.tx_pdos)
+                .await?;
+        }
         Ok(())
     }
     async fn process_data<'maindevice, 'group>(
@@ -88,20 +383,78 @@ impl<D: DeviceInfo + Entries<N> + Send + Sync, const N: usize, const ARR_LEN: us
         self.error = false;
 
         let output_bits = output_data.view_bits_mut::<bitvec::order::Lsb0>();
+        let now = Instant::now();
+        let mut written = [false; N];
 
         for idx in 0..N {
-            let bit = self.last_bits[idx].load(std::sync::atomic::Ordering::Relaxed);
+            let channel = &mut self.channels[idx];
+            let pulse = channel.pulse.swap(0, Ordering::Relaxed);
+            let blink_period = channel.blink_period.load(Ordering::Relaxed);
+            channel.timing.command(
+                (pulse != 0).then(|| Duration::from_millis(pulse)),
+                Duration::from_millis(blink_period),
+                now,
+            );
+            let latched = self.last_bits[idx].load(Ordering::Relaxed);
+            let bit = channel
+                .timing
+                .output(latched, channel.output.blink_duty, now);
             output_bits.set(idx, bit);
+            written[idx] = bit;
+        }
+        self.written.publish(&written).await;
+
+        if let (Some(faults), Some(diagnostics)) = (self.faults.as_mut(), D::DIAGNOSTICS) {
+            let input_bits = device.inputs_raw().view_bits::<bitvec::order::Lsb0>();
+            if diagnostics.bits.iter().any(|bit| *bit >= input_bits.len()) {
+                if !self.faults_missing {
+                    error!(target: &self.log_key, "Diagnostic inputs are not mapped, the input data has {} bits", input_bits.len());
+                    self.faults_missing = true;
+                }
+            } else {
+                if self.faults_missing {
+                    info!(target: &self.log_key, "Diagnostic inputs are mapped again");
+                    self.faults_missing = false;
+                }
+                let values = core::array::from_fn(|idx| input_bits[diagnostics.bits[idx]]);
+                faults.publish(&values).await;
+            }
         }
 
         Ok(())
     }
+    fn safe_outputs<'maindevice, 'group>(
+        &mut self,
+        device: &mut SubDeviceRef<'maindevice, SubDevicePdi<'group>>,
+    ) {
+        let output_data = device.outputs_raw_mut();
+        output_data.fill(0);
+        let output_bits = output_data.view_bits_mut::<bitvec::order::Lsb0>();
+        let config = self.config.read();
+        let mut written = [false; N];
+        for (idx, bit) in config.safe_outputs.iter().take(N).enumerate() {
+            if idx < output_bits.len() {
+                output_bits.set(idx, *bit);
+                written[idx] = *bit;
+            }
+        }
+        self.safe_written = written;
+    }
+    async fn publish_safe_outputs(&mut self) {
+        self.written.publish(&self.safe_written).await;
+    }
     fn vendor_id(&self) -> u32 {
         D::VENDOR_ID
     }
     fn product_id(&self) -> u32 {
         D::PRODUCT_ID
     }
+    fn name(&self) -> &'static str {
+        D::NAME
+    }
+    fn flush_config(&self) {
+        self.config.write().value_mut();
+    }
     #[cfg(feature = "opcua-expose")]
     fn opcua_register(
         &mut self,
@@ -119,6 +472,26 @@ impl<D: DeviceInfo + Entries<N> + Send + Sync, const N: usize, const ARR_LEN: us
             )
             .register();
         }
+        for channel in self.channels.iter() {
+            for slot in [&channel.pulse_slot, &channel.blink_slot]
+                .into_iter()
+                .flatten()
+            {
+                tfc::ipc::opcua::SlotInterface::new(
+                    slot.base(),
+                    slot.channel("opcua"),
+                    manager.clone(),
+                    subscriptions.clone(),
+                    namespace,
+                )
+                .register();
+            }
+        }
+        self.written
+            .opcua_register(&manager, &subscriptions, namespace);
+        if let Some(faults) = self.faults.as_ref() {
+            faults.opcua_register(&manager, &subscriptions, namespace);
+        }
         Ok(())
     }
 }
@@ -151,9 +524,25 @@ impl DeviceInfo for El2809Info {
 
 pub trait Entries<const N: usize> {
     const ENTRIES: [u8; N];
+    /// Diagnostic inputs, published as <output>/fault, None for terminals without
+    const DIAGNOSTICS: Option<Diagnostics<N>> = None;
 }
+
+/// Where a terminal reports the faults of its outputs
+pub struct Diagnostics<const N: usize> {
+    /// Input PDOs assigned in setup
+    pub tx_pdos: &'static [u16],
+    /// Bit of each output's fault in the input data
+    pub bits: [usize; N],
+}
+
 impl Entries<4> for El2794Info {
     const ENTRIES: [u8; 4] = [1, 2, 3, 4];
+    // short circuit bit per output
+    const DIAGNOSTICS: Option<Diagnostics<4>> = Some(Diagnostics {
+        tx_pdos: &[0x1A00],
+        bits: [0, 1, 2, 3],
+    });
 }
 impl Entries<4> for El2004Info {
     const ENTRIES: [u8; 4] = [1, 2, 3, 4];
@@ -161,6 +550,86 @@ impl Entries<4> for El2004Info {
 impl Entries<8> for El2008Info {
     const ENTRIES: [u8; 8] = [1, 2, 3, 4, 5, 6, 7, 8];
 }
+// the EL2809 reports no faults in its process data
 impl Entries<16> for El2809Info {
     const ENTRIES: [u8; 16] = [1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16];
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sim::{self, Segment, SimulatedSubDevice};
+    use ethercrab::std::ethercat_now;
+
+    #[test]
+    fn test_timing() {
+        let start = Instant::now();
+        let ms = Duration::from_millis;
+        let mut timing = Timing::default();
+        timing.command(None, Duration::ZERO, start);
+        assert!(!timing.output(false, 50.0, start));
+
+        timing.command(Some(ms(150)), Duration::ZERO, start);
+        assert!(timing.output(false, 50.0, start + ms(149)));
+        assert!(!timing.output(false, 50.0, start + ms(150)));
+
+        timing.command(None, ms(100), start);
+        assert!(timing.output(false, 50.0, start + ms(20)));
+        assert!(!timing.output(true, 50.0, start + ms(70)));
+        assert!(timing.output(false, 50.0, start + ms(120)));
+        // a pulse wins over blinking
+        timing.command(Some(ms(50)), ms(100), start + ms(150));
+        assert!(timing.output(false, 50.0, start + ms(160)));
+        // the same period keeps the phase
+        assert!(!timing.output(false, 50.0, start + ms(250)));
+
+        timing.command(None, Duration::ZERO, start + ms(300));
+        assert!(timing.output(true, 50.0, start + ms(360)));
+    }
+
+    #[tokio::test]
+    async fn test_process_data() {
+        let segment = Arc::new(std::sync::Mutex::new(Segment::new(&[
+            SimulatedSubDevice::El2794,
+        ])));
+        let main_device = sim::main_device(&segment);
+        let mut el2794 = El2794::new(sim::dbus().await, 0, 0);
+        let group = main_device
+            .init_single_group::<16, 64>(ethercat_now)
+            .await
+            .expect("init");
+        {
+            let mut subdevice = group.iter(&main_device).next().expect("el2794");
+            el2794
+                .setup(&mut SetupRef::PreOp(&mut subdevice))
+                .await
+                .expect("setup");
+        }
+        let group = group.into_op(&main_device).await.expect("op");
+
+        // output 2 switched on by its slot, output 3 short circuited
+        el2794.last_bits[1].store(true, Ordering::Relaxed);
+        segment.lock().unwrap().set_inputs(0, &[0b0100]);
+        group.tx_rx(&main_device).await.expect("tx/rx");
+        {
+            let mut subdevice = group.subdevice(&main_device, 0).expect("el2794");
+            el2794
+                .process_data(&mut subdevice)
+                .await
+                .expect("process data");
+        }
+        group.tx_rx(&main_device).await.expect("tx/rx");
+
+        assert_eq!(segment.lock().unwrap().outputs(0), &[0b0010]);
+        assert_eq!(
+            el2794.written.last,
+            [Some(false), Some(true), Some(false), Some(false)]
+        );
+        let faults = el2794.faults.as_ref().expect("faults");
+        assert_eq!(
+            faults.last,
+            [Some(false), Some(false), Some(true), Some(false)]
+        );
+        assert!(!el2794.faults_missing);
+    }
+}
@@ -1,6 +1,5 @@
 use async_trait::async_trait;
-use atomic_refcell::AtomicRefMut;
-use ethercrab::{EtherCrabWireReadWrite, SubDevice, SubDevicePdi, SubDeviceRef};
+use ethercrab::{DcSync, EtherCrabWireReadWrite, SubDevice, SubDevicePdi, SubDeviceRef};
 use ethercrab_wire::{EtherCrabWireRead, EtherCrabWireWrite};
 use log::{info, warn};
 #[cfg(feature = "opcua-expose")]
@@ -16,12 +15,17 @@ use tfc::confman::ConfMan;
 use zbus::interface;
 
 use crate::define_value_type;
-use crate::devices::device_trait::{Device, DeviceInfo, Index, WriteValueIndex};
+use crate::devices::device::{DeviceRegistry, Registration};
+use crate::devices::device_trait::{Device, DeviceInfo, Index, SetupRef, WriteValueIndex};
+use crate::devices::naming::{Names, Naming};
 
 static RX_PDO_ASSIGN: u16 = 0x1C12;
 static TX_PDO_ASSIGN: u16 = 0x1C13;
 static RX_PDO_MAPPING: u16 = 0x1600;
 static TX_PDO_MAPPING: u16 = 0x1A00;
+static SM_OUTPUT_PARAMETER: u16 = 0x1C32;
+static SM_INPUT_PARAMETER: u16 = 0x1C33;
+static SYNC_MODE_DC_SYNC0: u16 = 0x02;
 
 smlang::statemachine! {
     name: Calibrate,
@@ -220,6 +224,16 @@ struct Config {
         description = "Number of samples to use for average filter. Process data interval."
     )]
     filter_window: u16,
+    #[serde(default)]
+    #[schemars(
+        description = "Synchronize to the SYNC0 pulse, only has effect when distributed clocks are enabled on the bus"
+    )]
+    dc_sync: bool,
+    #[serde(default)]
+    #[schemars(
+        description = "Signal and slot names by channel, mass, tare, ratio, calibrate or zero_calibrate, takes effect on restart"
+    )]
+    names: Names,
 }
 
 impl Default for Config {
@@ -237,6 +251,8 @@ impl Default for Config {
             resolution: 0.001,
             mode: Mode::default(),
             filter_window: 100,
+            dc_sync: false,
+            names: Names::default(),
         }
     }
 }
@@ -280,22 +296,24 @@ pub struct Scale {
     last_mass: f64,
 }
 impl Scale {
-    pub fn new(dbus: zbus::Connection, prefix: String) -> Self {
+    pub fn new(dbus: zbus::Connection, naming: &mut Naming) -> Self {
+        let (name, description) = naming.channel(
+            "ratio",
+            Some("Scale raw input by this factor, useful for onboard vessel scale"),
+        );
         let mut ratio_slot = tfc::ipc::Slot::new(
             dbus.clone(),
-            tfc::ipc::Base::new(
-                format!("{prefix}/ratio").as_str(),
-                Some("Scale raw input by this factor, useful for onboard vessel scale"),
-            ),
+            tfc::ipc::Base::new(name.as_str(), description.as_deref()),
         );
         let ratio = Arc::new(AtomicF64::new(1.0));
         let ratio_cp = ratio.clone();
         ratio_slot.recv(Box::new(move |new_ratio| {
             ratio_cp.store(*new_ratio, std::sync::atomic::Ordering::Relaxed);
         }));
+        let (name, description) = naming.channel("mass", Some("Mass output in kg"));
         let mass_signal = tfc::ipc::Signal::new(
             dbus.clone(),
-            tfc::ipc::Base::new(format!("{prefix}/mass").as_str(), Some("Mass output in kg")),
+            tfc::ipc::Base::new(name.as_str(), description.as_deref()),
         );
         tfc::ipc::dbus::SignalInterface::register(
             mass_signal.base(),
@@ -303,12 +321,13 @@ impl Scale {
             mass_signal.subscribe(),
         );
         // tare slot
+        let (name, description) = naming.channel(
+            "tare",
+            Some("Offset current weight on cell as tare, meaning it will zero out the current weight"),
+        );
         let mut tare_slot = tfc::ipc::Slot::new(
             dbus.clone(),
-            tfc::ipc::Base::new(
-                format!("{prefix}/tare").as_str(),
-                Some("Offset current weight on cell as tare, meaning it will zero out the current weight"),
-            ),
+            tfc::ipc::Base::new(name.as_str(), description.as_deref()),
         );
         tfc::ipc::dbus::SlotInterface::register(
             tare_slot.base(),
@@ -340,13 +359,14 @@ pub struct ReferenceScale {
 }
 
 impl ReferenceScale {
-    pub fn new(dbus: zbus::Connection, prefix: String) -> Self {
+    pub fn new(dbus: zbus::Connection, naming: &mut Naming) -> Self {
+        let (name, description) = naming.channel(
+            "ratio",
+            Some("Output ratio of calibration load compared to current load"),
+        );
         let ratio_signal = tfc::ipc::Signal::new(
             dbus.clone(),
-            tfc::ipc::Base::new(
-                format!("{prefix}/ratio").as_str(),
-                Some("Output ratio of calibration load compared to current load"),
-            ),
+            tfc::ipc::Base::new(name.as_str(), description.as_deref()),
         );
         tfc::ipc::dbus::SignalInterface::register(
             ratio_signal.base(),
@@ -395,6 +415,16 @@ mod my_avg {
     }
 }
 
+pub fn config_schema() -> schemars::schema::RootSchema {
+    schemars::schema_for!(Config)
+}
+
+pub fn register(registry: &mut DeviceRegistry) {
+    registry.register(Registration::new::<El3356>(|dbus, number, alias| {
+        Box::new(El3356::new(dbus, number, alias))
+    }));
+}
+
 pub struct El3356 {
     cnt: u128,
     config: ConfMan<Config>,
@@ -405,6 +435,7 @@ pub struct El3356 {
     zero_calibrate_cmd: Arc<std::sync::atomic::AtomicBool>,
     calibrate_slot: tfc::ipc::Slot<bool>,
     zero_calibrate_slot: tfc::ipc::Slot<bool>,
+    _naming: Naming,
 }
 
 impl El3356 {
@@ -414,21 +445,23 @@ impl El3356 {
             prefix = format!("el3356/alias/{alias_address}");
         }
         let config: ConfMan<Config> = ConfMan::new(dbus.clone(), &prefix);
+        let mut naming = Naming::new(&prefix, &config.read().names);
 
         let mode = match config.read().mode {
-            Mode::Scale => ModeImpl::Scale(Scale::new(dbus.clone(), prefix.clone())),
+            Mode::Scale => ModeImpl::Scale(Scale::new(dbus.clone(), &mut naming)),
             Mode::Reference => {
-                ModeImpl::Reference(ReferenceScale::new(dbus.clone(), prefix.clone()))
+                ModeImpl::Reference(ReferenceScale::new(dbus.clone(), &mut naming))
             }
         };
         let filter = my_avg::AvgFilter::new(config.read().filter_window as usize);
 
+        let (name, description) = naming.channel(
+            "calibrate",
+            Some("Take current weight as calibration point, according to calibration load in config"),
+        );
         let mut calibrate_slot = tfc::ipc::Slot::new(
             dbus.clone(),
-            tfc::ipc::Base::new(
-                format!("{prefix}/calibrate").as_str(),
-                Some("Take current weight as calibration point, according to calibration load in config"),
-            ),
+            tfc::ipc::Base::new(name.as_str(), description.as_deref()),
         );
         tfc::ipc::dbus::SlotInterface::register(
             calibrate_slot.base(),
@@ -441,12 +474,11 @@ impl El3356 {
             calibrate_cmd_cp.store(*new_calibrate, std::sync::atomic::Ordering::Relaxed);
         }));
         // zero calibrate slot
+        let (name, description) =
+            naming.channel("zero_calibrate", Some("Offset current weight on cell as zero"));
         let mut zero_calibrate_slot = tfc::ipc::Slot::new(
             dbus.clone(),
-            tfc::ipc::Base::new(
-                format!("{prefix}/zero_calibrate").as_str(),
-                Some("Offset current weight on cell as zero"),
-            ),
+            tfc::ipc::Base::new(name.as_str(), description.as_deref()),
         );
         tfc::ipc::dbus::SlotInterface::register(
             zero_calibrate_slot.base(),
@@ -458,6 +490,7 @@ impl El3356 {
         zero_calibrate_slot.recv(Box::new(move |new_zero_calibrate| {
             zero_calibrate_cmd_cp.store(*new_zero_calibrate, std::sync::atomic::Ordering::Relaxed);
         }));
+        naming.check_unused();
 
         Self {
             cnt: 0,
@@ -469,6 +502,7 @@ impl El3356 {
             zero_calibrate_cmd,
             calibrate_slot,
             zero_calibrate_slot,
+            _naming: naming,
         }
     }
     /// Zero calibration
@@ -547,7 +581,7 @@ impl El3356 {
 impl Device for El3356 {
     async fn setup<'maindevice, 'group>(
         &mut self,
-        device: &mut SubDeviceRef<'maindevice, AtomicRefMut<'group, SubDevice>>,
+        device: &mut SetupRef<'_, 'maindevice, 'group>,
     ) -> Result<(), Box<dyn Error + Send + Sync>> {
         if let Some(nominal_value) = self.config.read().nominal_value {
             device.sdo_write_value_index(nominal_value).await?;
@@ -571,6 +605,15 @@ impl Device for El3356 {
         // device.sdo_write(TX_PDO_ASSIGN, 0x02, 0x1A01 as u16).await?; // use int from 0x1A01pdo mapping
         device.sdo_write(TX_PDO_ASSIGN, 0x00, 0x02 as u8).await?;
 
+        if self.config.read().dc_sync {
+            device
+                .sdo_write(SM_OUTPUT_PARAMETER, 0x01, SYNC_MODE_DC_SYNC0)
+                .await?;
+            device
+                .sdo_write(SM_INPUT_PARAMETER, 0x01, SYNC_MODE_DC_SYNC0)
+                .await?;
+        }
+
         Ok(())
     }
     async fn process_data<'maindevice, 'group>(
@@ -721,6 +764,19 @@ impl Device for El3356 {
     fn product_id(&self) -> u32 {
         Self::PRODUCT_ID
     }
+    fn name(&self) -> &'static str {
+        Self::NAME
+    }
+    fn flush_config(&self) {
+        self.config.write().value_mut();
+    }
+    fn dc_sync(&self) -> DcSync {
+        if self.config.read().dc_sync {
+            DcSync::Sync0
+        } else {
+            DcSync::Disabled
+        }
+    }
     #[cfg(feature = "opcua-expose")]
     fn opcua_register(
         &mut self,
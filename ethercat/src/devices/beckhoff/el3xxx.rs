@@ -0,0 +1,574 @@
+use crate::devices::device::{DeviceRegistry, Registration};
+use crate::devices::device_trait::{Device, DeviceInfo, SetupRef};
+use crate::devices::naming::{Names, Naming};
+use crate::{channel_signal, send_signal};
+use async_trait::async_trait;
+use ethercrab::{SubDevicePdi, SubDeviceRef};
+use ethercrab_wire::{EtherCrabWireRead, EtherCrabWireSized};
+use log::{error, warn};
+#[cfg(feature = "opcua-expose")]
+use opcua::server::{
+    node_manager::memory::{InMemoryNodeManager, SimpleNodeManagerImpl},
+    SubscriptionCache,
+};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::marker::PhantomData;
+#[cfg(feature = "opcua-expose")]
+use std::sync::Arc;
+use tfc::confman::ConfMan;
+use tfc::ipc::Signal;
+
+pub type El3001 = El3xxx<El3001Info, 1>;
+pub type El3002 = El3xxx<El3002Info, 2>;
+pub type El3004 = El3xxx<El3004Info, 4>;
+pub type El3042 = El3xxx<El3042Info, 2>;
+pub type El3044 = El3xxx<El3044Info, 4>;
+pub type El3052 = El3xxx<El3052Info, 2>;
+pub type El3054 = El3xxx<El3054Info, 4>;
+pub type El3062 = El3xxx<El3062Info, 2>;
+pub type El3064 = El3xxx<El3064Info, 4>;
+pub type El3102 = El3xxx<El3102Info, 2>;
+pub type El3162 = El3xxx<El3162Info, 2>;
+
+pub fn register(registry: &mut DeviceRegistry) {
+    registry.register(Registration::new::<El3001Info>(|dbus, number, alias| {
+        Box::new(El3001::new(dbus, number, alias))
+    }));
+    registry.register(Registration::new::<El3002Info>(|dbus, number, alias| {
+        Box::new(El3002::new(dbus, number, alias))
+    }));
+    registry.register(Registration::new::<El3004Info>(|dbus, number, alias| {
+        Box::new(El3004::new(dbus, number, alias))
+    }));
+    registry.register(Registration::new::<El3042Info>(|dbus, number, alias| {
+        Box::new(El3042::new(dbus, number, alias))
+    }));
+    registry.register(Registration::new::<El3044Info>(|dbus, number, alias| {
+        Box::new(El3044::new(dbus, number, alias))
+    }));
+    registry.register(Registration::new::<El3052Info>(|dbus, number, alias| {
+        Box::new(El3052::new(dbus, number, alias))
+    }));
+    registry.register(Registration::new::<El3054Info>(|dbus, number, alias| {
+        Box::new(El3054::new(dbus, number, alias))
+    }));
+    registry.register(Registration::new::<El3062Info>(|dbus, number, alias| {
+        Box::new(El3062::new(dbus, number, alias))
+    }));
+    registry.register(Registration::new::<El3064Info>(|dbus, number, alias| {
+        Box::new(El3064::new(dbus, number, alias))
+    }));
+    registry.register(Registration::new::<El3102Info>(|dbus, number, alias| {
+        Box::new(El3102::new(dbus, number, alias))
+    }));
+    registry.register(Registration::new::<El3162Info>(|dbus, number, alias| {
+        Box::new(El3162::new(dbus, number, alias))
+    }));
+}
+
+/// Status word of the standard PDO of a channel
+#[derive(Debug, EtherCrabWireRead)]
+#[wire(bytes = 2)]
+struct StatusWord {
+    #[wire(bits = 1)]
+    underrange: bool,
+    #[wire(bits = 1)]
+    overrange: bool,
+    #[wire(bits = 2)]
+    /// 0 not active, 1 value below the limit, 2 value above the limit, 3 value equals the limit
+    limit1: u8,
+    #[wire(bits = 2)]
+    limit2: u8,
+    #[wire(bits = 1, post_skip = 9)]
+    error: bool,
+}
+
+#[derive(Debug, EtherCrabWireRead)]
+#[wire(bytes = 4)]
+struct ChannelPdo {
+    #[wire(bits = 16)]
+    status_word: StatusWord,
+    #[wire(bits = 16)]
+    value: i16,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Copy)]
+struct Scaling {
+    #[serde(default)]
+    #[schemars(description = "Electrical value mapped to low, e.g. 4 for a 4-20 mA sensor")]
+    input_low: f64,
+    #[serde(default = "default_one")]
+    #[schemars(description = "Electrical value mapped to high, e.g. 20 for a 4-20 mA sensor")]
+    input_high: f64,
+    #[serde(default)]
+    #[schemars(description = "Value in engineering units at input_low")]
+    low: f64,
+    #[serde(default = "default_one")]
+    #[schemars(description = "Value in engineering units at input_high")]
+    high: f64,
+    #[serde(default)]
+    #[schemars(
+        description = "Smallest change of the scaled value which is published, in engineering units"
+    )]
+    deadband: f64,
+}
+fn default_one() -> f64 {
+    1.0
+}
+impl Default for Scaling {
+    fn default() -> Self {
+        Self {
+            input_low: 0.0,
+            input_high: default_one(),
+            low: 0.0,
+            high: default_one(),
+            deadband: 0.0,
+        }
+    }
+}
+
+impl Scaling {
+    fn apply(&self, input: f64) -> f64 {
+        if self.input_high == self.input_low {
+            return self.low;
+        }
+        let gain = (self.high - self.low) / (self.input_high - self.input_low);
+        self.low + (input - self.input_low) * gain
+    }
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Default)]
+struct Config {
+    #[serde(default)]
+    #[schemars(
+        description = "Signal names by channel, e.g. AI1 or AI1/error, takes effect on restart"
+    )]
+    names: Names,
+    #[serde(default)]
+    #[schemars(
+        description = "Linear scaling by channel, e.g. AI1, channels not listed publish the electrical value. Takes effect when the terminal is set up, on restart or re-init"
+    )]
+    scaling: BTreeMap<String, Scaling>,
+}
+
+pub fn config_schema() -> schemars::schema::RootSchema {
+    schemars::schema_for!(Config)
+}
+
+/// Signals of one input and the values last published on them
+struct Channel {
+    key: String,
+    scaling: Scaling,
+    value: Signal<f64>,
+    underrange: Signal<bool>,
+    overrange: Signal<bool>,
+    limit1: Signal<u64>,
+    limit2: Signal<u64>,
+    error: Signal<bool>,
+    last_value: Option<f64>,
+    last_status: Option<[u8; 5]>,
+}
+
+impl Channel {
+    fn new(dbus: zbus::Connection, naming: &mut Naming, key: String, unit: &str) -> Self {
+        Self {
+            value: channel_signal!(
+                dbus,
+                naming,
+                key,
+                &format!("Scaled value of the input, {unit} unless scaling is configured")
+            ),
+            underrange: channel_signal!(dbus, naming, format!("{key}/underrange"), "Underrange"),
+            overrange: channel_signal!(dbus, naming, format!("{key}/overrange"), "Overrange"),
+            limit1: channel_signal!(
+                dbus,
+                naming,
+                format!("{key}/limit1"),
+                "Limit 1, 0 not active, 1 value below, 2 value above, 3 value equals the limit"
+            ),
+            limit2: channel_signal!(
+                dbus,
+                naming,
+                format!("{key}/limit2"),
+                "Limit 2, 0 not active, 1 value below, 2 value above, 3 value equals the limit"
+            ),
+            error: channel_signal!(
+                dbus,
+                naming,
+                format!("{key}/error"),
+                "Error, e.g. over or underrange or a broken wire"
+            ),
+            key,
+            scaling: Scaling::default(),
+            last_value: None,
+            last_status: None,
+        }
+    }
+
+    async fn publish(&mut self, pdo: &ChannelPdo, input: f64, log_key: &str) {
+        let value = self.scaling.apply(input);
+        let changed = match self.last_value {
+            Some(last) => (value - last).abs() > self.scaling.deadband,
+            None => true,
+        };
+        if changed {
+            send_signal!(self.value, value, log_key, self.key);
+            self.last_value = Some(value);
+        }
+        let status = &pdo.status_word;
+        let current = [
+            status.underrange as u8,
+            status.overrange as u8,
+            status.limit1,
+            status.limit2,
+            status.error as u8,
+        ];
+        let last = self.last_status;
+        let changed = |index: usize| match last {
+            Some(last) => last[index] != current[index],
+            None => true,
+        };
+        if changed(0) {
+            send_signal!(self.underrange, status.underrange, log_key, self.key);
+        }
+        if changed(1) {
+            send_signal!(self.overrange, status.overrange, log_key, self.key);
+        }
+        if changed(2) {
+            send_signal!(self.limit1, status.limit1 as u64, log_key, self.key);
+        }
+        if changed(3) {
+            send_signal!(self.limit2, status.limit2 as u64, log_key, self.key);
+        }
+        if changed(4) {
+            if status.error {
+                warn!(target: log_key, "Error on input {}", self.key);
+            }
+            send_signal!(self.error, status.error, log_key, self.key);
+        }
+        self.last_status = Some(current);
+    }
+}
+
+// todo use this: https://github.com/rust-lang/rust/issues/76560
+pub struct El3xxx<D: DeviceInfo + InputRange, const N: usize> {
+    channels: [Channel; N],
+    config: ConfMan<Config>,
+    log_key: String,
+    _naming: Naming,
+    _marker: PhantomData<D>,
+    error: bool,
+}
+
+impl<D: DeviceInfo + InputRange, const N: usize> El3xxx<D, N> {
+    pub fn new(dbus: zbus::Connection, subdevice_number: u16, subdevice_alias: u16) -> Self {
+        let log_key = format!("{}:{}", D::NAME, subdevice_number);
+        let mut prefix = format!("{}/{subdevice_number}", D::NAME);
+        if subdevice_alias != 0 {
+            prefix = format!("{}/alias/{subdevice_alias}", D::NAME);
+        }
+        let config: ConfMan<Config> = ConfMan::new(dbus.clone(), &prefix);
+        let mut naming = Naming::new(&prefix, &config.read().names);
+        let channels = core::array::from_fn(|idx| {
+            Channel::new(dbus.clone(), &mut naming, format!("AI{}", idx + 1), D::UNIT)
+        });
+        naming.check_unused();
+        for key in config.read().scaling.keys() {
+            if !(1..=N).any(|channel| *key == format!("AI{channel}")) {
+                warn!(target: &log_key, "Scaling configured for unknown input {}", key);
+            }
+        }
+        let mut el3xxx = Self {
+            channels,
+            config,
+            log_key,
+            _naming: naming,
+            _marker: PhantomData,
+            error: false,
+        };
+        el3xxx.read_scaling();
+        el3xxx
+    }
+
+    /// Resolves the configured scaling of each channel, so the cycle does not look it up
+    fn read_scaling(&mut self) {
+        let config = self.config.read();
+        for channel in self.channels.iter_mut() {
+            channel.scaling = config
+                .scaling
+                .get(&channel.key)
+                .copied()
+                .unwrap_or_default();
+        }
+    }
+}
+
+/// Electrical value of a raw input value
+fn electrical<D: InputRange>(raw: i16) -> f64 {
+    D::ZERO + raw as f64 / i16::MAX as f64 * (D::FULL_SCALE - D::ZERO)
+}
+
+#[async_trait]
+impl<D: DeviceInfo + InputRange + Send + Sync, const N: usize> Device for El3xxx<D, N> {
+    async fn setup<'maindevice, 'group>(
+        &mut self,
+        _device: &mut SetupRef<'_, 'maindevice, 'group>,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        self.read_scaling();
+        Ok(())
+    }
+    async fn process_data<'maindevice, 'group>(
+        &mut self,
+        device: &mut SubDeviceRef<'maindevice, SubDevicePdi<'group>>,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let input_data = device.inputs_raw();
+
+        // the standard PDO of each channel, the compact PDOs without status are not supported
+        if input_data.len() != N * ChannelPdo::PACKED_LEN {
+            if !self.error {
+                error!(
+                    "Input data length mismatch: {} != {}",
+                    input_data.len(),
+                    N * ChannelPdo::PACKED_LEN
+                );
+                self.error = true;
+            }
+            return Err("Input data length mismatch".into());
+        }
+        self.error = false;
+
+        for (channel, data) in self
+            .channels
+            .iter_mut()
+            .zip(input_data.chunks(ChannelPdo::PACKED_LEN))
+        {
+            let pdo = ChannelPdo::unpack_from_slice(data)?;
+            let input = electrical::<D>(pdo.value);
+            channel.publish(&pdo, input, &self.log_key).await;
+        }
+        Ok(())
+    }
+    fn vendor_id(&self) -> u32 {
+        D::VENDOR_ID
+    }
+    fn product_id(&self) -> u32 {
+        D::PRODUCT_ID
+    }
+    fn name(&self) -> &'static str {
+        D::NAME
+    }
+    fn flush_config(&self) {
+        self.config.write().value_mut();
+    }
+    #[cfg(feature = "opcua-expose")]
+    fn opcua_register(
+        &mut self,
+        manager: Arc<InMemoryNodeManager<SimpleNodeManagerImpl>>,
+        subscriptions: Arc<SubscriptionCache>,
+        namespace: u16,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        for channel in self.channels.iter() {
+            tfc::ipc::opcua::SignalInterface::new(
+                channel.value.base(),
+                channel.value.subscribe(),
+                manager.clone(),
+                subscriptions.clone(),
+                namespace,
+            )
+            .register();
+            for signal in [&channel.underrange, &channel.overrange, &channel.error] {
+                tfc::ipc::opcua::SignalInterface::new(
+                    signal.base(),
+                    signal.subscribe(),
+                    manager.clone(),
+                    subscriptions.clone(),
+                    namespace,
+                )
+                .register();
+            }
+            for signal in [&channel.limit1, &channel.limit2] {
+                tfc::ipc::opcua::SignalInterface::new(
+                    signal.base(),
+                    signal.subscribe(),
+                    manager.clone(),
+                    subscriptions.clone(),
+                    namespace,
+                )
+                .register();
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Measuring range of a terminal
+pub trait InputRange {
+    /// Electrical value at the raw value 0
+    const ZERO: f64 = 0.0;
+    /// Electrical value at the raw value 0x7FFF
+    const FULL_SCALE: f64;
+    const UNIT: &'static str;
+}
+
+pub struct El3001Info;
+pub struct El3002Info;
+pub struct El3004Info;
+pub struct El3042Info;
+pub struct El3044Info;
+pub struct El3052Info;
+pub struct El3054Info;
+pub struct El3062Info;
+pub struct El3064Info;
+pub struct El3102Info;
+pub struct El3162Info;
+
+impl DeviceInfo for El3001Info {
+    const VENDOR_ID: u32 = 0x2;
+    const PRODUCT_ID: u32 = 0xbb93052;
+    const NAME: &'static str = "el3001";
+}
+impl DeviceInfo for El3002Info {
+    const VENDOR_ID: u32 = 0x2;
+    const PRODUCT_ID: u32 = 0xbba3052;
+    const NAME: &'static str = "el3002";
+}
+impl DeviceInfo for El3004Info {
+    const VENDOR_ID: u32 = 0x2;
+    const PRODUCT_ID: u32 = 0xbbc3052;
+    const NAME: &'static str = "el3004";
+}
+impl DeviceInfo for El3042Info {
+    const VENDOR_ID: u32 = 0x2;
+    const PRODUCT_ID: u32 = 0xbe23052;
+    const NAME: &'static str = "el3042";
+}
+impl DeviceInfo for El3044Info {
+    const VENDOR_ID: u32 = 0x2;
+    const PRODUCT_ID: u32 = 0xbe43052;
+    const NAME: &'static str = "el3044";
+}
+impl DeviceInfo for El3052Info {
+    const VENDOR_ID: u32 = 0x2;
+    const PRODUCT_ID: u32 = 0xbec3052;
+    const NAME: &'static str = "el3052";
+}
+impl DeviceInfo for El3054Info {
+    const VENDOR_ID: u32 = 0x2;
+    const PRODUCT_ID: u32 = 0xbee3052;
+    const NAME: &'static str = "el3054";
+}
+impl DeviceInfo for El3062Info {
+    const VENDOR_ID: u32 = 0x2;
+    const PRODUCT_ID: u32 = 0xbf63052;
+    const NAME: &'static str = "el3062";
+}
+impl DeviceInfo for El3064Info {
+    const VENDOR_ID: u32 = 0x2;
+    const PRODUCT_ID: u32 = 0xbf83052;
+    const NAME: &'static str = "el3064";
+}
+impl DeviceInfo for El3102Info {
+    const VENDOR_ID: u32 = 0x2;
+    const PRODUCT_ID: u32 = 0xc1e3052;
+    const NAME: &'static str = "el3102";
+}
+impl DeviceInfo for El3162Info {
+    const VENDOR_ID: u32 = 0x2;
+    const PRODUCT_ID: u32 = 0xc5a3052;
+    const NAME: &'static str = "el3162";
+}
+
+// -10..10 V
+impl InputRange for El3001Info {
+    const FULL_SCALE: f64 = 10.0;
+    const UNIT: &'static str = "V";
+}
+impl InputRange for El3002Info {
+    const FULL_SCALE: f64 = 10.0;
+    const UNIT: &'static str = "V";
+}
+impl InputRange for El3004Info {
+    const FULL_SCALE: f64 = 10.0;
+    const UNIT: &'static str = "V";
+}
+impl InputRange for El3102Info {
+    const FULL_SCALE: f64 = 10.0;
+    const UNIT: &'static str = "V";
+}
+// 0..10 V
+impl InputRange for El3062Info {
+    const FULL_SCALE: f64 = 10.0;
+    const UNIT: &'static str = "V";
+}
+impl InputRange for El3064Info {
+    const FULL_SCALE: f64 = 10.0;
+    const UNIT: &'static str = "V";
+}
+impl InputRange for El3162Info {
+    const FULL_SCALE: f64 = 10.0;
+    const UNIT: &'static str = "V";
+}
+// 0..20 mA
+impl InputRange for El3042Info {
+    const FULL_SCALE: f64 = 20.0;
+    const UNIT: &'static str = "mA";
+}
+impl InputRange for El3044Info {
+    const FULL_SCALE: f64 = 20.0;
+    const UNIT: &'static str = "mA";
+}
+// 4..20 mA, the raw value starts at 4 mA
+impl InputRange for El3052Info {
+    const ZERO: f64 = 4.0;
+    const FULL_SCALE: f64 = 20.0;
+    const UNIT: &'static str = "mA";
+}
+impl InputRange for El3054Info {
+    const ZERO: f64 = 4.0;
+    const FULL_SCALE: f64 = 20.0;
+    const UNIT: &'static str = "mA";
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_decode() {
+        // overrange, limit 1 above, error, value 0x7fff
+        let pdo = ChannelPdo::unpack_from_slice(&[0b0100_1010, 0x00, 0xff, 0x7f]).unwrap();
+        assert!(!pdo.status_word.underrange);
+        assert!(pdo.status_word.overrange);
+        assert_eq!(pdo.status_word.limit1, 2);
+        assert_eq!(pdo.status_word.limit2, 0);
+        assert!(pdo.status_word.error);
+        assert_eq!(pdo.value, i16::MAX);
+    }
+
+    #[test]
+    fn test_scaling() {
+        // 4-20 mA to 0-6 bar
+        let scaling = Scaling {
+            input_low: 4.0,
+            input_high: 20.0,
+            low: 0.0,
+            high: 6.0,
+            deadband: 0.0,
+        };
+        assert_eq!(scaling.apply(4.0), 0.0);
+        assert_eq!(scaling.apply(12.0), 3.0);
+        assert_eq!(scaling.apply(20.0), 6.0);
+        assert_eq!(Scaling::default().apply(7.5), 7.5);
+    }
+
+    #[test]
+    fn test_electrical() {
+        assert_eq!(electrical::<El3002Info>(i16::MAX), 10.0);
+        assert_eq!(electrical::<El3002Info>(-i16::MAX), -10.0);
+        assert_eq!(electrical::<El3054Info>(0), 4.0);
+        assert_eq!(electrical::<El3054Info>(i16::MAX), 20.0);
+    }
+}
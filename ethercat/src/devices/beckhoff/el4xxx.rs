@@ -0,0 +1,387 @@
+use crate::devices::device::{DeviceRegistry, Registration};
+use crate::devices::device_trait::{Device, DeviceInfo, SetupRef};
+use crate::devices::naming::{Names, Naming};
+use async_trait::async_trait;
+use ethercrab::{SubDevicePdi, SubDeviceRef};
+use log::{error, warn};
+#[cfg(feature = "opcua-expose")]
+use opcua::server::{
+    node_manager::memory::{InMemoryNodeManager, SimpleNodeManagerImpl},
+    SubscriptionCache,
+};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::marker::PhantomData;
+#[cfg(feature = "opcua-expose")]
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tfc::confman::ConfMan;
+use tfc::ipc::{Base, Slot};
+use tokio::sync::watch;
+
+pub type El4002 = El4xxx<El4002Info, 2>;
+pub type El4004 = El4xxx<El4004Info, 4>;
+pub type El4102 = El4xxx<El4102Info, 2>;
+pub type El4132 = El4xxx<El4132Info, 2>;
+
+pub fn register(registry: &mut DeviceRegistry) {
+    registry.register(Registration::new::<El4002Info>(|dbus, number, alias| {
+        Box::new(El4002::new(dbus, number, alias))
+    }));
+    registry.register(Registration::new::<El4004Info>(|dbus, number, alias| {
+        Box::new(El4004::new(dbus, number, alias))
+    }));
+    registry.register(Registration::new::<El4102Info>(|dbus, number, alias| {
+        Box::new(El4102::new(dbus, number, alias))
+    }));
+    registry.register(Registration::new::<El4132Info>(|dbus, number, alias| {
+        Box::new(El4132::new(dbus, number, alias))
+    }));
+}
+
+/// Size of the output value of a channel in the process image
+const CHANNEL_LEN: usize = 2;
+
+#[derive(Serialize, Deserialize, JsonSchema, Clone)]
+struct OutputConfig {
+    #[serde(default)]
+    #[schemars(description = "Value in engineering units mapped to output_low")]
+    low: f64,
+    #[serde(default = "default_one")]
+    #[schemars(description = "Value in engineering units mapped to output_high")]
+    high: f64,
+    #[serde(default)]
+    #[schemars(description = "Electrical value at low, e.g. 0 for a 0-10 V input of a drive")]
+    output_low: f64,
+    #[serde(default = "default_one")]
+    #[schemars(description = "Electrical value at high, e.g. 10 for a 0-10 V input of a drive")]
+    output_high: f64,
+    #[serde(default)]
+    #[schemars(description = "Smallest value in engineering units, lower values are clamped")]
+    min: Option<f64>,
+    #[serde(default)]
+    #[schemars(description = "Largest value in engineering units, higher values are clamped")]
+    max: Option<f64>,
+    #[serde(default)]
+    #[schemars(
+        description = "Largest change of the output per second in engineering units, none changes at once"
+    )]
+    ramp: Option<f64>,
+    #[serde(default)]
+    #[schemars(
+        description = "Value in engineering units written while the slot has no value and when the bus leaves operational"
+    )]
+    safe_value: f64,
+}
+fn default_one() -> f64 {
+    1.0
+}
+impl Default for OutputConfig {
+    fn default() -> Self {
+        Self {
+            low: 0.0,
+            high: default_one(),
+            output_low: 0.0,
+            output_high: default_one(),
+            min: None,
+            max: None,
+            ramp: None,
+            safe_value: 0.0,
+        }
+    }
+}
+
+impl OutputConfig {
+    fn clamp(&self, value: f64) -> f64 {
+        let value = self.min.map_or(value, |min| value.max(min));
+        self.max.map_or(value, |max| value.min(max))
+    }
+    /// Raw output of a value in engineering units, limited to the range of the terminal
+    fn raw<R: OutputRange>(&self, value: f64) -> i16 {
+        let electrical = if self.high == self.low {
+            self.output_low
+        } else {
+            let gain = (self.output_high - self.output_low) / (self.high - self.low);
+            self.output_low + (value - self.low) * gain
+        };
+        let electrical = electrical.clamp(R::MIN, R::FULL_SCALE);
+        (electrical / R::FULL_SCALE * i16::MAX as f64).round() as i16
+    }
+}
+
+/// Moves from the current value towards the target by at most rate per second
+fn ramp(current: f64, target: f64, rate: Option<f64>, elapsed: Duration) -> f64 {
+    match rate {
+        Some(rate) if rate > 0.0 => {
+            let step = rate * elapsed.as_secs_f64();
+            current + (target - current).clamp(-step, step)
+        }
+        _ => target,
+    }
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Default)]
+struct Config {
+    #[serde(default)]
+    #[schemars(description = "Slot names by channel, e.g. AO1, takes effect on restart")]
+    names: Names,
+    #[serde(default)]
+    #[schemars(
+        description = "Scaling, limits, ramp and safe value by channel, e.g. AO1, channels not listed take the electrical value"
+    )]
+    outputs: BTreeMap<String, OutputConfig>,
+}
+
+pub fn config_schema() -> schemars::schema::RootSchema {
+    schemars::schema_for!(Config)
+}
+
+struct Channel {
+    key: String,
+    slot: Slot<f64>,
+    value: watch::Receiver<Option<f64>>,
+    /// Value written in the last cycle, in engineering units
+    current: Option<f64>,
+}
+
+// todo use this: https://github.com/rust-lang/rust/issues/76560
+pub struct El4xxx<D: DeviceInfo + OutputRange, const N: usize> {
+    channels: [Channel; N],
+    config: ConfMan<Config>,
+    last_cycle: Option<Instant>,
+    _naming: Naming,
+    _marker: PhantomData<D>,
+    error: bool,
+}
+
+impl<D: DeviceInfo + OutputRange, const N: usize> El4xxx<D, N> {
+    pub fn new(dbus: zbus::Connection, subdevice_number: u16, subdevice_alias: u16) -> Self {
+        let log_key = format!("{}:{}", D::NAME, subdevice_number);
+        let mut prefix = format!("{}/{subdevice_number}", D::NAME);
+        if subdevice_alias != 0 {
+            prefix = format!("{}/alias/{subdevice_alias}", D::NAME);
+        }
+        let config: ConfMan<Config> = ConfMan::new(dbus.clone(), &prefix);
+        let mut naming = Naming::new(&prefix, &config.read().names);
+        let channels = core::array::from_fn(|idx| {
+            let key = format!("AO{}", idx + 1);
+            let description = format!("Output value, {} unless scaling is configured", D::UNIT);
+            let (name, description) = naming.channel(&key, Some(&description));
+            let slot = Slot::new(dbus.clone(), Base::new(name.as_str(), description.as_deref()));
+            #[cfg(feature = "dbus-expose")]
+            tfc::ipc::dbus::SlotInterface::register(
+                slot.base(),
+                dbus.clone(),
+                slot.channel("dbus"),
+            );
+            Channel {
+                key,
+                value: slot.subscribe(),
+                slot,
+                current: None,
+            }
+        });
+        naming.check_unused();
+        for key in config.read().outputs.keys() {
+            if !(1..=N).any(|channel| *key == format!("AO{channel}")) {
+                warn!(target: &log_key, "Output configured for unknown channel {}", key);
+            }
+        }
+        Self {
+            channels,
+            config,
+            last_cycle: None,
+            _naming: naming,
+            _marker: PhantomData,
+            error: false,
+        }
+    }
+}
+
+#[async_trait]
+impl<D: DeviceInfo + OutputRange + Send + Sync, const N: usize> Device for El4xxx<D, N> {
+    async fn setup<'maindevice, 'group>(
+        &mut self,
+        _device: &mut SetupRef<'_, 'maindevice, 'group>,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        Ok(())
+    }
+    async fn process_data<'maindevice, 'group>(
+        &mut self,
+        device: &mut SubDeviceRef<'maindevice, SubDevicePdi<'group>>,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let output_data = device.outputs_raw_mut();
+
+        if output_data.len() != N * CHANNEL_LEN {
+            if !self.error {
+                error!(
+                    "Output data length mismatch: {} != {}",
+                    output_data.len(),
+                    N * CHANNEL_LEN
+                );
+                self.error = true;
+            }
+            return Err("Output data length mismatch".into());
+        }
+        self.error = false;
+
+        let now = Instant::now();
+        let elapsed = self.last_cycle.map(|last| now.duration_since(last)).unwrap_or_default();
+        self.last_cycle = Some(now);
+
+        // read every cycle, so changes apply right away
+        let config = self.config.read();
+        let default = OutputConfig::default();
+        for (channel, raw) in self.channels.iter_mut().zip(output_data.chunks_mut(CHANNEL_LEN)) {
+            let output = config.outputs.get(&channel.key).unwrap_or(&default);
+            let target = output.clamp(channel.value.borrow().unwrap_or(output.safe_value));
+            let value = match channel.current {
+                Some(current) => ramp(current, target, output.ramp, elapsed),
+                None => target,
+            };
+            channel.current = Some(value);
+            raw.copy_from_slice(&output.raw::<D>(value).to_le_bytes());
+        }
+        Ok(())
+    }
+    fn safe_outputs<'maindevice, 'group>(
+        &mut self,
+        device: &mut SubDeviceRef<'maindevice, SubDevicePdi<'group>>,
+    ) {
+        let output_data = device.outputs_raw_mut();
+        let config = self.config.read();
+        let default = OutputConfig::default();
+        for (channel, raw) in self.channels.iter_mut().zip(output_data.chunks_mut(CHANNEL_LEN)) {
+            let output = config.outputs.get(&channel.key).unwrap_or(&default);
+            let value = output.clamp(output.safe_value);
+            // the ramp starts from the safe value when the bus is back
+            channel.current = Some(value);
+            if raw.len() == CHANNEL_LEN {
+                raw.copy_from_slice(&output.raw::<D>(value).to_le_bytes());
+            }
+        }
+        self.last_cycle = None;
+    }
+    fn vendor_id(&self) -> u32 {
+        D::VENDOR_ID
+    }
+    fn product_id(&self) -> u32 {
+        D::PRODUCT_ID
+    }
+    fn name(&self) -> &'static str {
+        D::NAME
+    }
+    fn flush_config(&self) {
+        self.config.write().value_mut();
+    }
+    #[cfg(feature = "opcua-expose")]
+    fn opcua_register(
+        &mut self,
+        manager: Arc<InMemoryNodeManager<SimpleNodeManagerImpl>>,
+        subscriptions: Arc<SubscriptionCache>,
+        namespace: u16,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        for channel in self.channels.iter() {
+            tfc::ipc::opcua::SlotInterface::new(
+                channel.slot.base(),
+                channel.slot.channel("opcua"),
+                manager.clone(),
+                subscriptions.clone(),
+                namespace,
+            )
+            .register();
+        }
+        Ok(())
+    }
+}
+
+/// Output range of a terminal
+pub trait OutputRange {
+    /// Lowest electrical value
+    const MIN: f64;
+    /// Electrical value at the raw value 0x7FFF
+    const FULL_SCALE: f64;
+    const UNIT: &'static str;
+}
+
+pub struct El4002Info;
+pub struct El4004Info;
+pub struct El4102Info;
+pub struct El4132Info;
+
+impl DeviceInfo for El4002Info {
+    const VENDOR_ID: u32 = 0x2;
+    const PRODUCT_ID: u32 = 0xfa23052;
+    const NAME: &'static str = "el4002";
+}
+impl DeviceInfo for El4004Info {
+    const VENDOR_ID: u32 = 0x2;
+    const PRODUCT_ID: u32 = 0xfa43052;
+    const NAME: &'static str = "el4004";
+}
+impl DeviceInfo for El4102Info {
+    const VENDOR_ID: u32 = 0x2;
+    const PRODUCT_ID: u32 = 0x10063052;
+    const NAME: &'static str = "el4102";
+}
+impl DeviceInfo for El4132Info {
+    const VENDOR_ID: u32 = 0x2;
+    const PRODUCT_ID: u32 = 0x10243052;
+    const NAME: &'static str = "el4132";
+}
+
+// 0..10 V
+impl OutputRange for El4002Info {
+    const MIN: f64 = 0.0;
+    const FULL_SCALE: f64 = 10.0;
+    const UNIT: &'static str = "V";
+}
+impl OutputRange for El4004Info {
+    const MIN: f64 = 0.0;
+    const FULL_SCALE: f64 = 10.0;
+    const UNIT: &'static str = "V";
+}
+impl OutputRange for El4102Info {
+    const MIN: f64 = 0.0;
+    const FULL_SCALE: f64 = 10.0;
+    const UNIT: &'static str = "V";
+}
+// -10..10 V
+impl OutputRange for El4132Info {
+    const MIN: f64 = -10.0;
+    const FULL_SCALE: f64 = 10.0;
+    const UNIT: &'static str = "V";
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_raw() {
+        // 0-50 Hz to 0-10 V, at most 40 Hz
+        let output = OutputConfig {
+            high: 50.0,
+            output_high: 10.0,
+            max: Some(40.0),
+            ..OutputConfig::default()
+        };
+        assert_eq!(output.raw::<El4002Info>(25.0), 16384);
+        assert_eq!(output.raw::<El4002Info>(output.clamp(50.0)), 26214);
+        // below the range of the terminal
+        assert_eq!(output.raw::<El4002Info>(-10.0), 0);
+        assert_eq!(OutputConfig::default().raw::<El4132Info>(-10.0), -i16::MAX);
+        assert_eq!(OutputConfig::default().raw::<El4132Info>(20.0), i16::MAX);
+    }
+
+    #[test]
+    fn test_ramp() {
+        let second = Duration::from_secs(1);
+        assert_eq!(ramp(0.0, 10.0, None, second), 10.0);
+        assert_eq!(ramp(0.0, 10.0, Some(4.0), second), 4.0);
+        assert_eq!(ramp(10.0, 0.0, Some(4.0), Duration::from_millis(500)), 8.0);
+        assert_eq!(ramp(9.0, 10.0, Some(4.0), second), 10.0);
+    }
+}
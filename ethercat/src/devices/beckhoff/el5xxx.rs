@@ -0,0 +1,714 @@
+use crate::devices::device::{DeviceRegistry, Registration};
+use crate::devices::device_trait::{Device, DeviceInfo, SetupRef};
+use crate::devices::naming::{Names, Naming};
+use crate::{channel_signal, channel_slot, send_signal};
+use async_trait::async_trait;
+use ethercrab::{SubDevicePdi, SubDeviceRef};
+use log::{error, info, warn};
+#[cfg(feature = "opcua-expose")]
+use opcua::server::{
+    node_manager::memory::{InMemoryNodeManager, SimpleNodeManagerImpl},
+    SubscriptionCache,
+};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::marker::PhantomData;
+#[cfg(feature = "opcua-expose")]
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tfc::confman::ConfMan;
+use tfc::ipc::{Signal, Slot};
+use tfc::time::MilliDuration;
+use tokio::sync::watch;
+
+static RX_PDO_ASSIGN: u16 = 0x1C12;
+static TX_PDO_ASSIGN: u16 = 0x1C13;
+
+pub type El5101 = El5xxx<El5101Info, 1>;
+pub type El5151 = El5xxx<El5151Info, 1>;
+
+pub fn register(registry: &mut DeviceRegistry) {
+    registry.register(Registration::new::<El5101Info>(|dbus, number, alias| {
+        Box::new(El5101::new(dbus, number, alias))
+    }));
+    registry.register(Registration::new::<El5151Info>(|dbus, number, alias| {
+        Box::new(El5151::new(dbus, number, alias))
+    }));
+}
+
+// control word
+const ENABLE_LATCH_C: u16 = 1 << 0;
+const ENABLE_LATCH_EXTERN: u16 = 1 << 1;
+const SET_COUNTER: u16 = 1 << 2;
+// status word
+const LATCH_C_VALID: u16 = 1 << 0;
+const LATCH_EXTERN_VALID: u16 = 1 << 1;
+const SET_COUNTER_DONE: u16 = 1 << 2;
+
+/// Control word and set counter value of a channel
+const OUTPUT_LEN: usize = 6;
+
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Copy)]
+struct ChannelConfig {
+    #[serde(default = "default_counts_per_unit")]
+    #[schemars(
+        description = "Counts per engineering unit of position, e.g. counts per meter of belt, must be positive"
+    )]
+    counts_per_unit: f64,
+    #[serde(default = "default_velocity_filter")]
+    #[schemars(
+        description = "Time constant of the low pass filter of the velocity, 0 is unfiltered. Milliseconds"
+    )]
+    velocity_filter: MilliDuration,
+}
+fn default_counts_per_unit() -> f64 {
+    1.0
+}
+fn default_velocity_filter() -> MilliDuration {
+    Duration::from_millis(100).into()
+}
+impl Default for ChannelConfig {
+    fn default() -> Self {
+        Self {
+            counts_per_unit: default_counts_per_unit(),
+            velocity_filter: default_velocity_filter(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, JsonSchema)]
+struct Config {
+    #[serde(default)]
+    #[schemars(
+        description = "Signal and slot names by channel, e.g. C1/counter or C1/set, takes effect on restart"
+    )]
+    names: Names,
+    #[serde(default)]
+    #[schemars(
+        description = "Scaling and filtering by channel, e.g. C1, takes effect when the terminal is set up, on restart or re-init"
+    )]
+    channels: BTreeMap<String, ChannelConfig>,
+    #[serde(default = "default_publish_interval")]
+    #[schemars(
+        description = "Shortest time between publishing counter, position and velocity, latches are published at once, takes effect when the terminal is set up. Milliseconds"
+    )]
+    publish_interval: MilliDuration,
+}
+fn default_publish_interval() -> MilliDuration {
+    Duration::from_millis(100).into()
+}
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            names: Names::default(),
+            channels: BTreeMap::new(),
+            publish_interval: default_publish_interval(),
+        }
+    }
+}
+
+pub fn config_schema() -> schemars::schema::RootSchema {
+    schemars::schema_for!(Config)
+}
+
+/// 64 bit count from the 32 bit counter of the terminal, which rolls over
+#[derive(Default)]
+struct Extended {
+    count: i64,
+    last: Option<u32>,
+}
+
+impl Extended {
+    fn update(&mut self, raw: u32) -> i64 {
+        self.count = self.extend(raw);
+        self.last = Some(raw);
+        self.count
+    }
+    /// Count of a raw value, e.g. a latch, taken within half the counter range of the last update
+    fn extend(&self, raw: u32) -> i64 {
+        match self.last {
+            Some(last) => self.count + raw.wrapping_sub(last) as i32 as i64,
+            None => raw as i32 as i64,
+        }
+    }
+    fn set(&mut self, count: i64, raw: u32) {
+        self.count = count;
+        self.last = Some(raw);
+    }
+}
+
+/// Low pass filtered velocity
+#[derive(Default)]
+struct Velocity {
+    value: f64,
+}
+
+impl Velocity {
+    fn update(&mut self, distance: f64, elapsed: Duration, time_constant: Duration) -> f64 {
+        let dt = elapsed.as_secs_f64();
+        if dt > 0.0 {
+            let alpha = dt / (time_constant.as_secs_f64() + dt);
+            self.value += alpha * (distance / dt - self.value);
+        }
+        self.value
+    }
+}
+
+/// Progress of setting the counter, the terminal sets on the rising edge of the control bit
+#[derive(Clone, Copy)]
+enum SetCounter {
+    Idle,
+    /// Waiting for the done bit of the last set to clear
+    Requested(u32),
+    Setting(u32),
+}
+
+struct Latch {
+    signal: Signal<f64>,
+    c_slot: Slot<bool>,
+    c: watch::Receiver<Option<bool>>,
+    extern_slot: Slot<bool>,
+    extern_: watch::Receiver<Option<bool>>,
+    last_status: u16,
+}
+
+struct Channel {
+    key: String,
+    settings: ChannelConfig,
+    counter: Signal<i64>,
+    position: Signal<f64>,
+    velocity: Signal<f64>,
+    set_slot: Slot<i64>,
+    set: watch::Receiver<Option<i64>>,
+    reset_slot: Slot<bool>,
+    reset: watch::Receiver<Option<bool>>,
+    latch: Option<Latch>,
+    extended: Extended,
+    filter: Velocity,
+    set_counter: SetCounter,
+    /// Count at the previous cycle, for the velocity
+    last_count: Option<i64>,
+    /// Count and velocity last published
+    published: Option<(i64, f64)>,
+}
+
+impl Channel {
+    fn new(dbus: zbus::Connection, naming: &mut Naming, key: String, latch: bool) -> Self {
+        let set_slot: Slot<i64> = channel_slot!(
+            dbus,
+            naming,
+            format!("{key}/set"),
+            "Set the counter to this value, from 0 to 4294967295"
+        );
+        let reset_slot: Slot<bool> = channel_slot!(
+            dbus,
+            naming,
+            format!("{key}/reset"),
+            "Set the counter to 0 on true"
+        );
+        let latch = latch.then(|| {
+            let c_slot: Slot<bool> = channel_slot!(
+                dbus,
+                naming,
+                format!("{key}/latch_c"),
+                "Latch the counter on the next C (index) pulse"
+            );
+            let extern_slot: Slot<bool> = channel_slot!(
+                dbus,
+                naming,
+                format!("{key}/latch_extern"),
+                "Latch the counter on the next rising edge of the external latch input"
+            );
+            Latch {
+                signal: channel_signal!(
+                    dbus,
+                    naming,
+                    format!("{key}/latch"),
+                    "Latched position in engineering units"
+                ),
+                c: c_slot.subscribe(),
+                c_slot,
+                extern_: extern_slot.subscribe(),
+                extern_slot,
+                last_status: 0,
+            }
+        });
+        Self {
+            counter: channel_signal!(
+                dbus,
+                naming,
+                format!("{key}/counter"),
+                "Counter value, extended to 64 bits over rollovers"
+            ),
+            position: channel_signal!(
+                dbus,
+                naming,
+                format!("{key}/position"),
+                "Counter value in engineering units"
+            ),
+            velocity: channel_signal!(
+                dbus,
+                naming,
+                format!("{key}/velocity"),
+                "Filtered velocity in engineering units per second"
+            ),
+            set: set_slot.subscribe(),
+            set_slot,
+            reset: reset_slot.subscribe(),
+            reset_slot,
+            latch,
+            key,
+            settings: ChannelConfig::default(),
+            extended: Extended::default(),
+            filter: Velocity::default(),
+            set_counter: SetCounter::Idle,
+            last_count: None,
+            published: None,
+        }
+    }
+
+    /// Next set request, from the set or the reset slot
+    fn set_request(&mut self) -> Option<i64> {
+        if self.set.has_changed().unwrap_or(false) {
+            if let Some(value) = *self.set.borrow_and_update() {
+                return Some(value);
+            }
+        }
+        let reset = self.reset.has_changed().unwrap_or(false);
+        if reset && *self.reset.borrow_and_update() == Some(true) {
+            return Some(0);
+        }
+        None
+    }
+
+    /// Control word and set value to write, advancing the set counter sequence by the status
+    fn control(&mut self, status: u16, raw: u32, log_key: &str) -> (u16, u32) {
+        let mut control = 0;
+        let mut set_value = 0;
+        let state = self.set_counter;
+        self.set_counter = match state {
+            SetCounter::Idle => match self.set_request().map(u32::try_from) {
+                Some(Ok(value)) => SetCounter::Requested(value),
+                Some(Err(_)) => {
+                    warn!(
+                        target: log_key,
+                        "Set value of counter {} rejected, the terminal counts 0 to {}",
+                        self.key,
+                        u32::MAX
+                    );
+                    SetCounter::Idle
+                }
+                None => SetCounter::Idle,
+            },
+            SetCounter::Requested(value) if status & SET_COUNTER_DONE == 0 => {
+                SetCounter::Setting(value)
+            }
+            SetCounter::Setting(value) if status & SET_COUNTER_DONE != 0 => {
+                info!(target: log_key, "Counter {} set to {}", self.key, value);
+                self.extended.set(value.into(), raw);
+                self.last_count = None;
+                SetCounter::Idle
+            }
+            state => state,
+        };
+        if let SetCounter::Setting(value) = self.set_counter {
+            control |= SET_COUNTER;
+            set_value = value;
+        }
+        if let Some(latch) = self.latch.as_ref() {
+            if latch.c.borrow().unwrap_or(false) {
+                control |= ENABLE_LATCH_C;
+            }
+            if latch.extern_.borrow().unwrap_or(false) {
+                control |= ENABLE_LATCH_EXTERN;
+            }
+        }
+        (control, set_value)
+    }
+}
+
+// todo use this: https://github.com/rust-lang/rust/issues/76560
+pub struct El5xxx<D: DeviceInfo + CounterInfo, const N: usize> {
+    channels: [Channel; N],
+    config: ConfMan<Config>,
+    log_key: String,
+    publish_interval: Duration,
+    last_cycle: Option<Instant>,
+    last_publish: Option<Instant>,
+    _naming: Naming,
+    _marker: PhantomData<D>,
+    error: bool,
+}
+
+impl<D: DeviceInfo + CounterInfo, const N: usize> El5xxx<D, N> {
+    /// Status word, counter value and, with latch, latch value of a channel
+    const INPUT_LEN: usize = if D::LATCH { 10 } else { 6 };
+
+    pub fn new(dbus: zbus::Connection, subdevice_number: u16, subdevice_alias: u16) -> Self {
+        let log_key = format!("{}:{}", D::NAME, subdevice_number);
+        let mut prefix = format!("{}/{subdevice_number}", D::NAME);
+        if subdevice_alias != 0 {
+            prefix = format!("{}/alias/{subdevice_alias}", D::NAME);
+        }
+        let config: ConfMan<Config> = ConfMan::new(dbus.clone(), &prefix);
+        let mut naming = Naming::new(&prefix, &config.read().names);
+        let channels = core::array::from_fn(|idx| {
+            Channel::new(dbus.clone(), &mut naming, format!("C{}", idx + 1), D::LATCH)
+        });
+        naming.check_unused();
+        for key in config.read().channels.keys() {
+            if !(1..=N).any(|channel| *key == format!("C{channel}")) {
+                warn!(target: &log_key, "Configuration for unknown channel {}", key);
+            }
+        }
+        let mut el5xxx = Self {
+            channels,
+            config,
+            log_key,
+            publish_interval: default_publish_interval().into(),
+            last_cycle: None,
+            last_publish: None,
+            _naming: naming,
+            _marker: PhantomData,
+            error: false,
+        };
+        el5xxx.read_config();
+        el5xxx
+    }
+
+    /// Resolves the settings of each channel, so the cycle does not look them up
+    fn read_config(&mut self) {
+        let config = self.config.read();
+        self.publish_interval = config.publish_interval.into();
+        for channel in self.channels.iter_mut() {
+            let mut settings = config
+                .channels
+                .get(&channel.key)
+                .copied()
+                .unwrap_or_default();
+            if settings.counts_per_unit.is_nan() || settings.counts_per_unit <= 0.0 {
+                error!(
+                    target: &self.log_key,
+                    "Counts per unit of {} must be positive, not {}, using 1",
+                    channel.key,
+                    settings.counts_per_unit
+                );
+                settings.counts_per_unit = default_counts_per_unit();
+            }
+            channel.settings = settings;
+        }
+    }
+}
+
+#[async_trait]
+impl<D: DeviceInfo + CounterInfo + Send + Sync, const N: usize> Device for El5xxx<D, N> {
+    async fn setup<'maindevice, 'group>(
+        &mut self,
+        device: &mut SetupRef<'_, 'maindevice, 'group>,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        self.read_config();
+        assign_pdos::<D>(device).await
+    }
+    async fn process_data<'maindevice, 'group>(
+        &mut self,
+        device: &mut SubDeviceRef<'maindevice, SubDevicePdi<'group>>,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let (input_data, output_data) = device.io_raw_mut();
+
+        // the 32 bit standard PDOs, the 16 bit compact PDOs are not supported
+        if input_data.len() != N * Self::INPUT_LEN || output_data.len() != N * OUTPUT_LEN {
+            if !self.error {
+                error!(
+                    "Process data length mismatch: {}/{} != {}/{}",
+                    input_data.len(),
+                    output_data.len(),
+                    N * Self::INPUT_LEN,
+                    N * OUTPUT_LEN
+                );
+                self.error = true;
+            }
+            return Err("Process data length mismatch".into());
+        }
+        self.error = false;
+
+        let now = Instant::now();
+        let elapsed = self
+            .last_cycle
+            .map(|last| now.duration_since(last))
+            .unwrap_or_default();
+        self.last_cycle = Some(now);
+        let publish = match self.last_publish {
+            Some(last) => now.duration_since(last) >= self.publish_interval,
+            None => true,
+        };
+        if publish {
+            self.last_publish = Some(now);
+        }
+
+        let inputs = input_data.chunks(Self::INPUT_LEN);
+        let outputs = output_data.chunks_mut(OUTPUT_LEN);
+        for ((channel, input), output) in self.channels.iter_mut().zip(inputs).zip(outputs) {
+            let settings = channel.settings;
+            let status = u16::from_le_bytes([input[0], input[1]]);
+            let raw = u32::from_le_bytes([input[2], input[3], input[4], input[5]]);
+
+            let (control, set_value) = channel.control(status, raw, &self.log_key);
+            output[0..2].copy_from_slice(&control.to_le_bytes());
+            output[2..6].copy_from_slice(&set_value.to_le_bytes());
+
+            let count = channel.extended.update(raw);
+            let distance = (count - channel.last_count.unwrap_or(count)) as f64;
+            channel.last_count = Some(count);
+            let velocity = channel.filter.update(
+                distance / settings.counts_per_unit,
+                elapsed,
+                settings.velocity_filter.into(),
+            );
+
+            if let Some(latch) = channel.latch.as_mut() {
+                let valid = status & (LATCH_C_VALID | LATCH_EXTERN_VALID);
+                if valid & !latch.last_status != 0 {
+                    let raw_latch = u32::from_le_bytes([input[6], input[7], input[8], input[9]]);
+                    let latched = channel.extended.extend(raw_latch) as f64;
+                    send_signal!(
+                        latch.signal,
+                        latched / settings.counts_per_unit,
+                        &self.log_key,
+                        channel.key
+                    );
+                }
+                latch.last_status = valid;
+            }
+
+            if publish && channel.published != Some((count, velocity)) {
+                send_signal!(channel.counter, count, &self.log_key, channel.key);
+                send_signal!(
+                    channel.position,
+                    count as f64 / settings.counts_per_unit,
+                    &self.log_key,
+                    channel.key
+                );
+                send_signal!(channel.velocity, velocity, &self.log_key, channel.key);
+                channel.published = Some((count, velocity));
+            }
+        }
+        Ok(())
+    }
+    fn safe_outputs<'maindevice, 'group>(
+        &mut self,
+        device: &mut SubDeviceRef<'maindevice, SubDevicePdi<'group>>,
+    ) {
+        device.outputs_raw_mut().fill(0);
+        for channel in self.channels.iter_mut() {
+            // an unfinished set is requested again when the bus is back
+            if let SetCounter::Setting(value) = channel.set_counter {
+                channel.set_counter = SetCounter::Requested(value);
+            }
+            channel.last_count = None;
+        }
+        self.last_cycle = None;
+    }
+    fn vendor_id(&self) -> u32 {
+        D::VENDOR_ID
+    }
+    fn product_id(&self) -> u32 {
+        D::PRODUCT_ID
+    }
+    fn name(&self) -> &'static str {
+        D::NAME
+    }
+    fn flush_config(&self) {
+        self.config.write().value_mut();
+    }
+    #[cfg(feature = "opcua-expose")]
+    fn opcua_register(
+        &mut self,
+        manager: Arc<InMemoryNodeManager<SimpleNodeManagerImpl>>,
+        subscriptions: Arc<SubscriptionCache>,
+        namespace: u16,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        for channel in self.channels.iter() {
+            tfc::ipc::opcua::SignalInterface::new(
+                channel.counter.base(),
+                channel.counter.subscribe(),
+                manager.clone(),
+                subscriptions.clone(),
+                namespace,
+            )
+            .register();
+            let latch = channel.latch.as_ref().map(|latch| &latch.signal);
+            for signal in [Some(&channel.position), Some(&channel.velocity), latch]
+                .into_iter()
+                .flatten()
+            {
+                tfc::ipc::opcua::SignalInterface::new(
+                    signal.base(),
+                    signal.subscribe(),
+                    manager.clone(),
+                    subscriptions.clone(),
+                    namespace,
+                )
+                .register();
+            }
+            tfc::ipc::opcua::SlotInterface::new(
+                channel.set_slot.base(),
+                channel.set_slot.channel("opcua"),
+                manager.clone(),
+                subscriptions.clone(),
+                namespace,
+            )
+            .register();
+            let latch_slots = channel
+                .latch
+                .as_ref()
+                .map(|latch| [&latch.c_slot, &latch.extern_slot]);
+            for slot in [&channel.reset_slot]
+                .into_iter()
+                .chain(latch_slots.into_iter().flatten())
+            {
+                tfc::ipc::opcua::SlotInterface::new(
+                    slot.base(),
+                    slot.channel("opcua"),
+                    manager.clone(),
+                    subscriptions.clone(),
+                    namespace,
+                )
+                .register();
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The PDOs `process_data` expects, as the default assignment differs between terminals and
+/// revisions, e.g. with the period of the EL5151
+async fn assign_pdos<D: CounterInfo>(
+    device: &SetupRef<'_, '_, '_>,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    device.assign_pdos(RX_PDO_ASSIGN, D::RX_PDOS).await?;
+    device.assign_pdos(TX_PDO_ASSIGN, D::TX_PDOS).await?;
+    Ok(())
+}
+
+/// Process data of a counter terminal
+pub trait CounterInfo {
+    /// Whether the channels have a latch value
+    const LATCH: bool;
+    /// Control word and 32 bit set value per channel
+    const RX_PDOS: &'static [u16];
+    /// Status word, 32 bit counter and, with latch, latch value per channel
+    const TX_PDOS: &'static [u16];
+}
+
+pub struct El5101Info;
+pub struct El5151Info;
+
+impl DeviceInfo for El5101Info {
+    const VENDOR_ID: u32 = 0x2;
+    const PRODUCT_ID: u32 = 0x13ed3052;
+    const NAME: &'static str = "el5101";
+}
+impl DeviceInfo for El5151Info {
+    const VENDOR_ID: u32 = 0x2;
+    const PRODUCT_ID: u32 = 0x141f3052;
+    const NAME: &'static str = "el5151";
+}
+
+impl CounterInfo for El5101Info {
+    const LATCH: bool = true;
+    const RX_PDOS: &'static [u16] = &[0x1601];
+    const TX_PDOS: &'static [u16] = &[0x1A01];
+}
+impl CounterInfo for El5151Info {
+    const LATCH: bool = true;
+    const RX_PDOS: &'static [u16] = &[0x1601];
+    const TX_PDOS: &'static [u16] = &[0x1A01];
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sim::{self, Segment, SimulatedSubDevice};
+    use ethercrab::std::ethercat_now;
+    use std::sync::Arc;
+
+    #[test]
+    fn test_extended() {
+        let mut extended = Extended::default();
+        assert_eq!(extended.update(u32::MAX - 1), -2);
+        assert_eq!(extended.update(3), 3);
+        assert_eq!(extended.update(u32::MAX), -1);
+        // a latch slightly ahead of the counter, across the rollover
+        assert_eq!(extended.extend(2), 2);
+
+        extended.set(1 << 40, 0);
+        assert_eq!(extended.update(10), (1 << 40) + 10);
+    }
+
+    #[test]
+    fn test_velocity() {
+        let mut velocity = Velocity::default();
+        let ms = Duration::from_millis;
+        assert_eq!(velocity.update(0.5, ms(10), Duration::ZERO), 50.0);
+        assert_eq!(velocity.update(0.0, Duration::ZERO, Duration::ZERO), 50.0);
+        // half way with a time constant of one cycle
+        assert_eq!(velocity.update(1.0, ms(10), ms(10)), 75.0);
+    }
+
+    #[tokio::test]
+    async fn test_process_data() {
+        // the EL5151 assigns its period by default, setup leaves it out
+        let segment = Arc::new(std::sync::Mutex::new(Segment::new(&[
+            SimulatedSubDevice::El5101,
+            SimulatedSubDevice::El5151,
+        ])));
+        let main_device = sim::main_device(&segment);
+        let dbus = sim::dbus().await;
+        let mut el5101 = El5101::new(dbus.clone(), 0, 0);
+        let mut el5151 = El5151::new(dbus, 1, 0);
+        let group = main_device
+            .init_single_group::<16, 64>(ethercat_now)
+            .await
+            .expect("init");
+        {
+            let mut subdevices = group.iter(&main_device);
+            let mut subdevice = subdevices.next().expect("el5101");
+            el5101
+                .setup(&mut SetupRef::PreOp(&mut subdevice))
+                .await
+                .expect("setup");
+            let mut subdevice = subdevices.next().expect("el5151");
+            el5151
+                .setup(&mut SetupRef::PreOp(&mut subdevice))
+                .await
+                .expect("setup");
+        }
+        assert_eq!(
+            segment.lock().unwrap().object(1, TX_PDO_ASSIGN, 0x00),
+            Some(&[1u8][..])
+        );
+        let group = group.into_op(&main_device).await.expect("op");
+
+        // status word and a counter which rolled over backwards
+        let mut inputs = vec![0; El5101::INPUT_LEN];
+        inputs[2..6].copy_from_slice(&(-5i32).to_le_bytes());
+        segment.lock().unwrap().set_inputs(0, &inputs);
+        group.tx_rx(&main_device).await.expect("tx/rx");
+        {
+            let mut subdevice = group.subdevice(&main_device, 0).expect("el5101");
+            el5101
+                .process_data(&mut subdevice)
+                .await
+                .expect("process data");
+            let mut subdevice = group.subdevice(&main_device, 1).expect("el5151");
+            el5151
+                .process_data(&mut subdevice)
+                .await
+                .expect("process data");
+        }
+        assert_eq!(el5101.channels[0].published, Some((-5, 0.0)));
+        assert_eq!(el5151.channels[0].published, Some((0, 0.0)));
+    }
+}
@@ -2,3 +2,6 @@ pub mod ek1xxx;
 pub mod el1xxx;
 pub mod el2xxx;
 pub mod el3356;
+pub mod el3xxx;
+pub mod el4xxx;
+pub mod el5xxx;
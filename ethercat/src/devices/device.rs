@@ -1,48 +1,174 @@
-use crate::devices::beckhoff::{ek1xxx::Ek1100, el1xxx::*, el2xxx::*, el3356::*};
+use crate::devices::beckhoff::{
+    ek1xxx, el1xxx, el2xxx, el3356, el3356::El3356, el3xxx, el4xxx, el5xxx,
+};
 use crate::devices::device_trait::{Device, DeviceInfo, UnimplementedDevice};
-use crate::devices::lenze::i550::I550;
-use log::warn;
-
-pub fn make_device(
-    dbus: zbus::Connection,
-    vendor_id: u32,
-    product_id: u32,
-    slave_number: u16,
-    alias_address: u16,
-    name: &str,
-) -> Box<dyn Device + Send + Sync> {
-    match (vendor_id, product_id) {
-        (Ek1100::VENDOR_ID, Ek1100::PRODUCT_ID) => Box::new(Ek1100),
-        (El1002Info::VENDOR_ID, El1002Info::PRODUCT_ID) => {
-            Box::new(El1002::new(dbus, slave_number, alias_address))
-        }
-        (El1008Info::VENDOR_ID, El1008Info::PRODUCT_ID) => {
-            Box::new(El1008::new(dbus, slave_number, alias_address))
-        }
-        (El1809Info::VENDOR_ID, El1809Info::PRODUCT_ID) => {
-            Box::new(El1809::new(dbus, slave_number, alias_address))
-        }
-        (El2794Info::VENDOR_ID, El2794Info::PRODUCT_ID) => {
-            Box::new(El2794::new(dbus, slave_number, alias_address))
-        }
-        (El2004Info::VENDOR_ID, El2004Info::PRODUCT_ID) => {
-            Box::new(El2004::new(dbus, slave_number, alias_address))
-        }
-        (El2008Info::VENDOR_ID, El2008Info::PRODUCT_ID) => {
-            Box::new(El2008::new(dbus, slave_number, alias_address))
-        }
-        (El2809Info::VENDOR_ID, El2809Info::PRODUCT_ID) => {
-            Box::new(El2809::new(dbus, slave_number, alias_address))
-        }
-        (I550::VENDOR_ID, I550::PRODUCT_ID) => {
-            Box::new(I550::new(dbus, slave_number, alias_address))
+use crate::devices::esi::{self, EsiDevice, EsiLibrary};
+use crate::devices::lenze::i550::{self, I550};
+use log::{info, warn};
+use std::ops::RangeInclusive;
+
+/// Creates a driver from the dbus connection, the segment position and the station alias
+pub type Factory = fn(zbus::Connection, u16, u16) -> Box<dyn Device + Send + Sync>;
+
+/// Driver for the subdevices with a vendor and product id in a range of revisions
+pub struct Registration {
+    pub vendor_id: u32,
+    pub product_id: u32,
+    pub revisions: RangeInclusive<u32>,
+    /// Name used in signal names and to bind the driver to a position in the configuration
+    pub name: &'static str,
+    pub factory: Factory,
+}
+
+impl Registration {
+    /// Every revision of the device
+    pub fn new<D: DeviceInfo>(factory: Factory) -> Self {
+        Self {
+            vendor_id: D::VENDOR_ID,
+            product_id: D::PRODUCT_ID,
+            revisions: 0..=u32::MAX,
+            name: D::NAME,
+            factory,
         }
-        (El3356::VENDOR_ID, El3356::PRODUCT_ID) => {
-            Box::new(El3356::new(dbus, slave_number, alias_address))
+    }
+    pub fn revisions(mut self, revisions: RangeInclusive<u32>) -> Self {
+        self.revisions = revisions;
+        self
+    }
+    fn matches(&self, vendor_id: u32, product_id: u32, revision: u32) -> bool {
+        self.vendor_id == vendor_id
+            && self.product_id == product_id
+            && self.revisions.contains(&revision)
+    }
+}
+
+/// Drivers by identity. Later registrations take precedence, so a driver registered after the
+/// built in ones replaces them for the revisions it covers
+#[derive(Default)]
+pub struct DeviceRegistry {
+    registrations: Vec<Registration>,
+    /// Fallback for subdevices without a registered driver
+    esi: EsiLibrary,
+}
+
+impl DeviceRegistry {
+    /// The drivers of this crate
+    pub fn with_builtin() -> Self {
+        let mut registry = Self::default();
+        ek1xxx::register(&mut registry);
+        el1xxx::register(&mut registry);
+        el2xxx::register(&mut registry);
+        el3356::register(&mut registry);
+        el3xxx::register(&mut registry);
+        el4xxx::register(&mut registry);
+        el5xxx::register(&mut registry);
+        i550::register(&mut registry);
+        registry
+    }
+    pub fn register(&mut self, registration: Registration) {
+        self.registrations.push(registration);
+    }
+    pub fn set_esi(&mut self, esi: EsiLibrary) {
+        self.esi = esi;
+    }
+    pub fn find(&self, vendor_id: u32, product_id: u32, revision: u32) -> Option<&Registration> {
+        self.registrations
+            .iter()
+            .rev()
+            .find(|r| r.matches(vendor_id, product_id, revision))
+    }
+    pub fn by_name(&self, name: &str) -> Option<&Registration> {
+        self.registrations.iter().rev().find(|r| r.name == name)
+    }
+
+    /// The driver named by the configuration, else the one registered for the identity, else one
+    /// from the ESI description
+    pub fn make_device(
+        &self,
+        dbus: zbus::Connection,
+        identity: (u32, u32, u32),
+        slave_number: u16,
+        alias_address: u16,
+        name: &str,
+        driver: Option<&str>,
+    ) -> Box<dyn Device + Send + Sync> {
+        let (vendor_id, product_id, revision) = identity;
+        let bound = driver.and_then(|driver| {
+            let registration = self.by_name(driver);
+            if registration.is_none() {
+                warn!("No driver named {driver} for device {name} at {slave_number}, matching by identity");
+            }
+            registration
+        });
+        if let Some(registration) = bound.or_else(|| self.find(vendor_id, product_id, revision)) {
+            return (registration.factory)(dbus, slave_number, alias_address);
         }
-        _ => {
-            warn!("Unimplemented device {name}: {vendor_id}:{product_id}");
-            Box::new(UnimplementedDevice)
+        match self.esi.find(vendor_id, product_id, revision) {
+            Some(description) => {
+                info!(
+                    "Using ESI description revision {:#010x} for device {name}: {vendor_id}:{product_id}:{revision}",
+                    description.revision
+                );
+                Box::new(EsiDevice::new(
+                    dbus,
+                    description,
+                    slave_number,
+                    alias_address,
+                ))
+            }
+            None => {
+                warn!("Unimplemented device {name}: {vendor_id}:{product_id}:{revision}");
+                Box::new(UnimplementedDevice)
+            }
         }
     }
 }
+
+/// Configuration schema of every driver which has a configuration, by driver name
+pub fn config_schemas() -> Vec<(&'static str, schemars::schema::RootSchema)> {
+    vec![
+        ("el1xxx", el1xxx::config_schema()),
+        ("el2xxx", el2xxx::config_schema()),
+        (El3356::NAME, el3356::config_schema()),
+        ("el3xxx", el3xxx::config_schema()),
+        ("el4xxx", el4xxx::config_schema()),
+        ("el5xxx", el5xxx::config_schema()),
+        ("esi", esi::config_schema()),
+        (I550::NAME, i550::config_schema()),
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::devices::beckhoff::el1xxx::El1008Info;
+
+    #[test]
+    fn test_find() {
+        let mut registry = DeviceRegistry::with_builtin();
+        let el1008 = (El1008Info::VENDOR_ID, El1008Info::PRODUCT_ID);
+        assert_eq!(
+            registry.find(el1008.0, el1008.1, 0x0011_0000).unwrap().name,
+            "el1008"
+        );
+        assert!(registry.find(el1008.0, 0, 0).is_none());
+
+        registry.register(Registration {
+            name: "el1008_v2",
+            ..Registration::new::<El1008Info>(|_, _, _| Box::new(UnimplementedDevice))
+                .revisions(0x0012_0000..=u32::MAX)
+        });
+        assert_eq!(
+            registry.find(el1008.0, el1008.1, 0x0011_0000).unwrap().name,
+            "el1008"
+        );
+        assert_eq!(
+            registry.find(el1008.0, el1008.1, 0x0012_0000).unwrap().name,
+            "el1008_v2"
+        );
+        assert_eq!(
+            registry.by_name("i550").unwrap().product_id,
+            I550::PRODUCT_ID
+        );
+    }
+}
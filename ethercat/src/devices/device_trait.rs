@@ -1,6 +1,6 @@
 use async_trait::async_trait;
 use atomic_refcell::AtomicRefMut;
-use ethercrab::{SubDevice, SubDevicePdi, SubDeviceRef};
+use ethercrab::{DcSync, SubDevice, SubDevicePdi, SubDeviceRef, SubIndex};
 #[cfg(feature = "opcua-expose")]
 use opcua::server::{
     node_manager::memory::{InMemoryNodeManager, SimpleNodeManagerImpl},
@@ -9,11 +9,47 @@ use opcua::server::{
 use std::error::Error;
 use std::sync::Arc;
 
+/// Subdevice given to `Device::setup`, in pre-op while the bus is initialized or in a running
+/// group when a re-powered subdevice is brought back to operational
+pub enum SetupRef<'a, 'maindevice, 'group> {
+    PreOp(&'a mut SubDeviceRef<'maindevice, AtomicRefMut<'group, SubDevice>>),
+    Running(&'a mut SubDeviceRef<'maindevice, SubDevicePdi<'group>>),
+}
+
+impl SetupRef<'_, '_, '_> {
+    pub async fn sdo_write<T>(
+        &self,
+        index: u16,
+        sub_index: impl Into<SubIndex>,
+        value: T,
+    ) -> Result<(), ethercrab::error::Error>
+    where
+        T: ethercrab_wire::EtherCrabWireWrite,
+    {
+        match self {
+            Self::PreOp(device) => device.sdo_write(index, sub_index, value).await,
+            Self::Running(device) => device.sdo_write(index, sub_index, value).await,
+        }
+    }
+    /// Assigns the PDOs to a sync manager through its assign object, e.g. 0x1C13 for the inputs
+    pub async fn assign_pdos(
+        &self,
+        assign: u16,
+        pdos: &[u16],
+    ) -> Result<(), ethercrab::error::Error> {
+        self.sdo_write(assign, 0x00, 0u8).await?;
+        for (subindex, pdo) in pdos.iter().enumerate() {
+            self.sdo_write(assign, subindex as u8 + 1, *pdo).await?;
+        }
+        self.sdo_write(assign, 0x00, pdos.len() as u8).await
+    }
+}
+
 #[async_trait]
 pub trait Device {
     async fn setup<'maindevice, 'group>(
         &mut self,
-        device: &mut SubDeviceRef<'maindevice, AtomicRefMut<'group, SubDevice>>,
+        device: &mut SetupRef<'_, 'maindevice, 'group>,
     ) -> Result<(), Box<dyn Error + Send + Sync>>;
     async fn process_data<'maindevice, 'group>(
         &mut self,
@@ -21,6 +57,25 @@ pub trait Device {
     ) -> Result<(), Box<dyn Error + Send + Sync>>;
     fn vendor_id(&self) -> u32;
     fn product_id(&self) -> u32;
+    /// Name used in signal names
+    fn name(&self) -> &'static str;
+    /// Writes the outputs the subdevice is left with when the bus stops or is lost,
+    /// the default turns every output off
+    fn safe_outputs<'maindevice, 'group>(
+        &mut self,
+        device: &mut SubDeviceRef<'maindevice, SubDevicePdi<'group>>,
+    ) {
+        device.outputs_raw_mut().fill(0);
+    }
+    /// Publishes the state `safe_outputs` wrote, called by the group runner once the safe outputs
+    /// are sent, and every cycle while a faulted device holds them
+    async fn publish_safe_outputs(&mut self) {}
+    /// Saves the driver configuration, called when the bus stops on shutdown
+    fn flush_config(&self) {}
+    /// SYNC0 configuration applied when distributed clocks are enabled on the bus
+    fn dc_sync(&self) -> DcSync {
+        DcSync::Disabled
+    }
     #[cfg(feature = "opcua-expose")]
     fn opcua_register(
         &mut self,
@@ -42,7 +97,7 @@ pub struct UnimplementedDevice;
 impl Device for UnimplementedDevice {
     async fn setup<'maindevice, 'group>(
         &mut self,
-        _device: &mut SubDeviceRef<'maindevice, AtomicRefMut<'group, SubDevice>>,
+        _device: &mut SetupRef<'_, 'maindevice, 'group>,
     ) -> Result<(), Box<dyn Error + Send + Sync>> {
         Ok(())
     }
@@ -58,6 +113,9 @@ impl Device for UnimplementedDevice {
     fn product_id(&self) -> u32 {
         0
     }
+    fn name(&self) -> &'static str {
+        "unimplemented"
+    }
     #[cfg(feature = "opcua-expose")]
     fn opcua_register(
         &mut self,
@@ -92,6 +150,15 @@ where
     }
 }
 
+impl WriteValueIndex for SetupRef<'_, '_, '_> {
+    async fn sdo_write_value_index<T>(&mut self, value: T) -> Result<(), ethercrab::error::Error>
+    where
+        T: Index + ethercrab_wire::EtherCrabWireWrite,
+    {
+        self.sdo_write(T::INDEX, T::SUBINDEX, value).await
+    }
+}
+
 #[macro_export]
 /// Define a value type for a device.
 /// This macro defines a new struct with a single field of the given type.
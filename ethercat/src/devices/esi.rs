@@ -0,0 +1,648 @@
+//! Generic driver for subdevices described by an ESI XML file. Every entry of the PDOs assigned by
+//! default is published as a signal for inputs or a slot for outputs, named `<pdo>/<entry>` unless
+//! configured otherwise.
+
+use crate::devices::device_trait::{Device, SetupRef};
+use crate::devices::naming::{Names, Naming};
+use async_trait::async_trait;
+use bitvec::{field::BitField, order::Lsb0, view::BitView};
+use ethercrab::{SubDevicePdi, SubDeviceRef};
+use log::{debug, info, warn};
+#[cfg(feature = "opcua-expose")]
+use opcua::server::{
+    node_manager::memory::{InMemoryNodeManager, SimpleNodeManagerImpl},
+    SubscriptionCache,
+};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::error::Error;
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use tfc::confman::ConfMan;
+use tfc::ipc::{Base, Signal, Slot};
+
+#[derive(Serialize, Deserialize, JsonSchema, Default)]
+struct Config {
+    #[serde(default)]
+    #[schemars(
+        description = "Signal names by PDO entry, e.g. ai_standard_channel_1/value, takes effect on restart"
+    )]
+    names: Names,
+}
+
+pub fn config_schema() -> schemars::schema::RootSchema {
+    schemars::schema_for!(Config)
+}
+
+/// Devices of every ESI file in a directory
+#[derive(Default)]
+pub struct EsiLibrary {
+    devices: Vec<Arc<EsiDescription>>,
+}
+
+impl EsiLibrary {
+    /// Files which fail to parse are logged and skipped, a missing directory gives an empty library
+    pub fn load(directory: &Path) -> Self {
+        let mut library = Self::default();
+        let entries = match std::fs::read_dir(directory) {
+            Ok(entries) => entries,
+            Err(e) => {
+                info!(target: "ethercat", "No ESI files loaded from {}: {}", directory.display(), e);
+                return library;
+            }
+        };
+        for path in entries
+            .filter_map(|entry| entry.ok())
+            .map(|entry| entry.path())
+        {
+            if !path
+                .extension()
+                .is_some_and(|extension| extension.eq_ignore_ascii_case("xml"))
+            {
+                continue;
+            }
+            let parsed = std::fs::read_to_string(&path)
+                .map_err(|e| -> Box<dyn Error + Send + Sync> { e.into() })
+                .and_then(|xml| Self::parse(&xml));
+            match parsed {
+                Ok(devices) => library.devices.extend(devices.into_iter().map(Arc::new)),
+                Err(e) => {
+                    warn!(target: "ethercat", "Failed to load ESI file {}: {}", path.display(), e)
+                }
+            }
+        }
+        info!(target: "ethercat", "Loaded {} ESI device descriptions from {}", library.devices.len(), directory.display());
+        library
+    }
+
+    pub fn parse(xml: &str) -> Result<Vec<EsiDescription>, Box<dyn Error + Send + Sync>> {
+        let document = roxmltree::Document::parse(xml)?;
+        let root = document.root_element();
+        let vendor_id = child(root, "Vendor")
+            .and_then(|vendor| child_text(vendor, "Id"))
+            .ok_or("Missing vendor id")
+            .and_then(|id| number(id).ok_or("Invalid vendor id"))?;
+        let mut devices = Vec::new();
+        let Some(list) = child(root, "Descriptions").and_then(|d| child(d, "Devices")) else {
+            return Ok(devices);
+        };
+        for device in list.children().filter(|n| n.has_tag_name("Device")) {
+            let Some(device_type) = child(device, "Type") else {
+                continue;
+            };
+            let (Some(product_id), Some(name)) = (
+                device_type.attribute("ProductCode").and_then(number),
+                device_type.text(),
+            ) else {
+                continue;
+            };
+            let pdos = |tag: &str| {
+                device
+                    .children()
+                    .filter(|n| n.has_tag_name(tag))
+                    // PDOs with a sync manager are the default assignment
+                    .filter(|pdo| pdo.attribute("Sm").is_some())
+                    .filter_map(pdo)
+                    .collect()
+            };
+            devices.push(EsiDescription {
+                vendor_id,
+                product_id,
+                revision: device_type
+                    .attribute("RevisionNo")
+                    .and_then(number)
+                    .unwrap_or(0),
+                name: name.trim().to_lowercase().leak(),
+                rx_pdos: pdos("RxPdo"),
+                tx_pdos: pdos("TxPdo"),
+            });
+        }
+        Ok(devices)
+    }
+
+    /// The description of the revision, else of the closest earlier revision
+    pub fn find(
+        &self,
+        vendor_id: u32,
+        product_id: u32,
+        revision: u32,
+    ) -> Option<Arc<EsiDescription>> {
+        self.devices
+            .iter()
+            .filter(|d| d.vendor_id == vendor_id && d.product_id == product_id)
+            .filter(|d| d.revision <= revision)
+            .max_by_key(|d| d.revision)
+            .cloned()
+    }
+}
+
+fn child<'a, 'input>(
+    node: roxmltree::Node<'a, 'input>,
+    tag: &str,
+) -> Option<roxmltree::Node<'a, 'input>> {
+    node.children().find(|n| n.has_tag_name(tag))
+}
+
+fn child_text<'a>(node: roxmltree::Node<'a, '_>, tag: &str) -> Option<&'a str> {
+    child(node, tag)
+        .and_then(|n| n.text())
+        .map(|text| text.trim())
+}
+
+/// ESI numbers are decimal or hex with a `#x` prefix
+fn number<T: TryFrom<u64>>(text: &str) -> Option<T> {
+    let text = text.trim();
+    match text.strip_prefix("#x").or_else(|| text.strip_prefix("#X")) {
+        Some(hex) => u64::from_str_radix(hex, 16).ok(),
+        None => text.parse::<u64>().ok(),
+    }
+    .and_then(|value| T::try_from(value).ok())
+}
+
+fn pdo(node: roxmltree::Node) -> Option<EsiPdo> {
+    Some(EsiPdo {
+        index: number(child_text(node, "Index")?)?,
+        name: child_text(node, "Name").unwrap_or_default().to_string(),
+        entries: node
+            .children()
+            .filter(|n| n.has_tag_name("Entry"))
+            .filter_map(|entry| {
+                Some(EsiEntry {
+                    index: number(child_text(entry, "Index")?)?,
+                    subindex: child_text(entry, "SubIndex").and_then(number).unwrap_or(0),
+                    bits: number(child_text(entry, "BitLen")?)?,
+                    name: child_text(entry, "Name").unwrap_or_default().to_string(),
+                    data_type: child_text(entry, "DataType")
+                        .unwrap_or_default()
+                        .to_string(),
+                })
+            })
+            .collect(),
+    })
+}
+
+pub struct EsiDescription {
+    pub vendor_id: u32,
+    pub product_id: u32,
+    pub revision: u32,
+    /// Type of the device in lower case, like the names of the hand written drivers. Leaked once
+    /// when the library loads, the device trait hands out static names
+    pub name: &'static str,
+    rx_pdos: Vec<EsiPdo>,
+    tx_pdos: Vec<EsiPdo>,
+}
+
+struct EsiPdo {
+    index: u16,
+    name: String,
+    entries: Vec<EsiEntry>,
+}
+
+struct EsiEntry {
+    /// 0 for padding
+    index: u16,
+    subindex: u8,
+    bits: usize,
+    name: String,
+    data_type: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Kind {
+    Bool,
+    Unsigned,
+    Signed,
+    Real,
+}
+
+impl EsiEntry {
+    fn kind(&self) -> Kind {
+        match self.data_type.as_str() {
+            _ if self.bits == 1 => Kind::Bool,
+            "BOOL" => Kind::Bool,
+            "SINT" | "INT" | "DINT" | "LINT" | "INT24" | "INT40" | "INT48" | "INT56" => {
+                Kind::Signed
+            }
+            "REAL" | "LREAL" if self.bits == 32 || self.bits == 64 => Kind::Real,
+            _ => Kind::Unsigned,
+        }
+    }
+}
+
+/// Bit offset in the process data of every entry which is published, padding is skipped
+fn layout(pdos: &[EsiPdo]) -> Vec<(usize, &EsiPdo, &EsiEntry)> {
+    let mut offset = 0;
+    let mut entries = Vec::new();
+    for pdo in pdos {
+        for entry in pdo.entries.iter() {
+            if entry.index != 0 && (1..=64).contains(&entry.bits) {
+                entries.push((offset, pdo, entry));
+            } else if entry.index != 0 {
+                debug!(target: "ethercat", "Skipping {} bit entry {:#06x}:{:#04x}", entry.bits, entry.index, entry.subindex);
+            }
+            offset += entry.bits;
+        }
+    }
+    entries
+}
+
+fn total_bits(pdos: &[EsiPdo]) -> usize {
+    pdos.iter()
+        .flat_map(|pdo| pdo.entries.iter())
+        .map(|entry| entry.bits)
+        .sum()
+}
+
+/// Lower case with everything but letters and digits replaced, for signal names
+fn signal_name(name: &str) -> String {
+    name.trim()
+        .chars()
+        .map(|c| match c.is_ascii_alphanumeric() {
+            true => c.to_ascii_lowercase(),
+            false => '_',
+        })
+        .collect()
+}
+
+fn signed(raw: u64, bits: usize) -> i64 {
+    ((raw << (64 - bits)) as i64) >> (64 - bits)
+}
+
+fn real(raw: u64, bits: usize) -> f64 {
+    match bits {
+        32 => f32::from_bits(raw as u32) as f64,
+        _ => f64::from_bits(raw),
+    }
+}
+
+enum InputSignal {
+    Bool(Signal<bool>),
+    Unsigned(Signal<u64>),
+    Signed(Signal<i64>),
+    Real(Signal<f64>),
+}
+
+struct Input {
+    offset: usize,
+    bits: usize,
+    signal: InputSignal,
+    last: Option<u64>,
+}
+
+enum OutputSlot {
+    Bool(Slot<bool>),
+    Unsigned(Slot<u64>),
+    Signed(Slot<i64>),
+    Real(Slot<f64>),
+}
+
+struct Output {
+    offset: usize,
+    bits: usize,
+    /// Encoded value, truncated to the entry's width when written
+    value: Arc<AtomicU64>,
+    // held so the slot keeps receiving, otherwise only read to register with OPC UA
+    #[cfg_attr(not(feature = "opcua-expose"), allow(dead_code))]
+    slot: OutputSlot,
+}
+
+macro_rules! signal {
+    ($dbus:expr, $name:expr, $description:expr) => {{
+        let signal = Signal::new($dbus.clone(), Base::new($name, Some($description)));
+        #[cfg(feature = "dbus-expose")]
+        tfc::ipc::dbus::SignalInterface::register(signal.base(), $dbus.clone(), signal.subscribe());
+        signal
+    }};
+}
+
+macro_rules! slot {
+    ($dbus:expr, $name:expr, $description:expr, $value:expr, $encode:expr) => {{
+        let mut slot = Slot::new($dbus.clone(), Base::new($name, Some($description)));
+        #[cfg(feature = "dbus-expose")]
+        tfc::ipc::dbus::SlotInterface::register(slot.base(), $dbus.clone(), slot.channel("dbus"));
+        let value = $value.clone();
+        let encode = $encode;
+        slot.recv(Box::new(move |new_value| {
+            value.store(encode(*new_value), Ordering::Relaxed);
+        }));
+        slot
+    }};
+}
+
+pub struct EsiDevice {
+    description: Arc<EsiDescription>,
+    inputs: Vec<Input>,
+    outputs: Vec<Output>,
+    input_bits: usize,
+    output_bits: usize,
+    log_key: String,
+    // the configuration and names are held so they stay registered
+    config: ConfMan<Config>,
+    _naming: Naming,
+    error: bool,
+}
+
+impl EsiDevice {
+    pub fn new(
+        dbus: zbus::Connection,
+        description: Arc<EsiDescription>,
+        subdevice_number: u16,
+        subdevice_alias: u16,
+    ) -> Self {
+        let name = description.name;
+        let log_key = format!("{}:{}", name, subdevice_number);
+        let mut prefix = format!("{}/{subdevice_number}", name);
+        if subdevice_alias != 0 {
+            prefix = format!("{}/alias/{subdevice_alias}", name);
+        }
+        let config: ConfMan<Config> = ConfMan::new(dbus.clone(), &prefix);
+        let mut naming = Naming::new(&prefix, &config.read().names);
+        // name and description of an entry's signal or slot
+        let channel = |naming: &mut Naming, pdo: &EsiPdo, entry: &EsiEntry| {
+            let text = format!(
+                "{} {:#06x}:{:#04x} {} in PDO {:#06x}",
+                entry.name, entry.index, entry.subindex, entry.data_type, pdo.index
+            );
+            let channel = format!("{}/{}", signal_name(&pdo.name), signal_name(&entry.name));
+            let (name, description) = naming.channel(&channel, Some(&text));
+            (name, description.unwrap_or(text))
+        };
+
+        let inputs = layout(&description.tx_pdos)
+            .into_iter()
+            .map(|(offset, pdo, entry)| {
+                let (name, text) = channel(&mut naming, pdo, entry);
+                let signal = match entry.kind() {
+                    Kind::Bool => InputSignal::Bool(signal!(dbus, name.as_str(), text.as_str())),
+                    Kind::Unsigned => {
+                        InputSignal::Unsigned(signal!(dbus, name.as_str(), text.as_str()))
+                    }
+                    Kind::Signed => {
+                        InputSignal::Signed(signal!(dbus, name.as_str(), text.as_str()))
+                    }
+                    Kind::Real => InputSignal::Real(signal!(dbus, name.as_str(), text.as_str())),
+                };
+                Input {
+                    offset,
+                    bits: entry.bits,
+                    signal,
+                    last: None,
+                }
+            })
+            .collect();
+
+        let outputs = layout(&description.rx_pdos)
+            .into_iter()
+            .map(|(offset, pdo, entry)| {
+                let (name, text) = channel(&mut naming, pdo, entry);
+                let value = Arc::new(AtomicU64::new(0));
+                let bits = entry.bits;
+                let slot = match entry.kind() {
+                    Kind::Bool => OutputSlot::Bool(slot!(
+                        dbus,
+                        name.as_str(),
+                        text.as_str(),
+                        value,
+                        |v: bool| v as u64
+                    )),
+                    Kind::Unsigned => OutputSlot::Unsigned(slot!(
+                        dbus,
+                        name.as_str(),
+                        text.as_str(),
+                        value,
+                        |v: u64| v
+                    )),
+                    Kind::Signed => OutputSlot::Signed(slot!(
+                        dbus,
+                        name.as_str(),
+                        text.as_str(),
+                        value,
+                        |v: i64| v as u64
+                    )),
+                    Kind::Real => OutputSlot::Real(slot!(
+                        dbus,
+                        name.as_str(),
+                        text.as_str(),
+                        value,
+                        move |v: f64| match bits {
+                            32 => (v as f32).to_bits() as u64,
+                            _ => v.to_bits(),
+                        }
+                    )),
+                };
+                Output {
+                    offset,
+                    bits,
+                    value,
+                    slot,
+                }
+            })
+            .collect();
+        naming.check_unused();
+
+        Self {
+            input_bits: total_bits(&description.tx_pdos),
+            output_bits: total_bits(&description.rx_pdos),
+            description,
+            inputs,
+            outputs,
+            log_key,
+            config,
+            _naming: naming,
+            error: false,
+        }
+    }
+}
+
+#[async_trait]
+impl Device for EsiDevice {
+    async fn setup<'maindevice, 'group>(
+        &mut self,
+        _device: &mut SetupRef<'_, 'maindevice, 'group>,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        // the default PDO assignment is used as is
+        Ok(())
+    }
+    async fn process_data<'maindevice, 'group>(
+        &mut self,
+        device: &mut SubDeviceRef<'maindevice, SubDevicePdi<'group>>,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let (input_data, output_data) = device.io_raw_mut();
+        if input_data.len() * 8 < self.input_bits || output_data.len() * 8 < self.output_bits {
+            if !self.error {
+                warn!(target: &self.log_key, "Process data is smaller than the ESI PDOs, inputs: {} bytes, outputs: {} bytes", input_data.len(), output_data.len());
+            }
+            self.error = true;
+            return Err("Process data length mismatch".into());
+        }
+        self.error = false;
+
+        let input_bits = input_data.view_bits::<Lsb0>();
+        for input in self.inputs.iter_mut() {
+            let raw: u64 = input_bits[input.offset..input.offset + input.bits].load_le();
+            if input.last == Some(raw) {
+                continue;
+            }
+            input.last = Some(raw);
+            let bits = input.bits;
+            let result = match &mut input.signal {
+                InputSignal::Bool(signal) => {
+                    signal.async_send(raw != 0).await.map_err(|e| e.to_string())
+                }
+                InputSignal::Unsigned(signal) => {
+                    signal.async_send(raw).await.map_err(|e| e.to_string())
+                }
+                InputSignal::Signed(signal) => signal
+                    .async_send(signed(raw, bits))
+                    .await
+                    .map_err(|e| e.to_string()),
+                InputSignal::Real(signal) => signal
+                    .async_send(real(raw, bits))
+                    .await
+                    .map_err(|e| e.to_string()),
+            };
+            if let Err(e) = result {
+                warn!(target: &self.log_key, "Error sending signal at bit {}: {}", input.offset, e);
+            }
+        }
+
+        let output_bits = output_data.view_bits_mut::<Lsb0>();
+        for output in self.outputs.iter() {
+            let raw = output.value.load(Ordering::Relaxed);
+            output_bits[output.offset..output.offset + output.bits].store_le(raw);
+        }
+        Ok(())
+    }
+    fn vendor_id(&self) -> u32 {
+        self.description.vendor_id
+    }
+    fn product_id(&self) -> u32 {
+        self.description.product_id
+    }
+    fn name(&self) -> &'static str {
+        self.description.name
+    }
+    fn flush_config(&self) {
+        self.config.write().value_mut();
+    }
+    #[cfg(feature = "opcua-expose")]
+    fn opcua_register(
+        &mut self,
+        manager: Arc<InMemoryNodeManager<SimpleNodeManagerImpl>>,
+        subscriptions: Arc<SubscriptionCache>,
+        namespace: u16,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        macro_rules! register {
+            ($interface:ident, $ipc:expr, $channel:expr) => {
+                tfc::ipc::opcua::$interface::new(
+                    $ipc.base(),
+                    $channel,
+                    manager.clone(),
+                    subscriptions.clone(),
+                    namespace,
+                )
+                .register()
+            };
+        }
+        for input in self.inputs.iter() {
+            match &input.signal {
+                InputSignal::Bool(s) => register!(SignalInterface, s, s.subscribe()),
+                InputSignal::Unsigned(s) => register!(SignalInterface, s, s.subscribe()),
+                InputSignal::Signed(s) => register!(SignalInterface, s, s.subscribe()),
+                InputSignal::Real(s) => register!(SignalInterface, s, s.subscribe()),
+            }
+        }
+        for output in self.outputs.iter() {
+            match &output.slot {
+                OutputSlot::Bool(s) => register!(SlotInterface, s, s.channel("opcua")),
+                OutputSlot::Unsigned(s) => register!(SlotInterface, s, s.channel("opcua")),
+                OutputSlot::Signed(s) => register!(SlotInterface, s, s.channel("opcua")),
+                OutputSlot::Real(s) => register!(SlotInterface, s, s.channel("opcua")),
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const EL_TEST: &str = r##"<?xml version="1.0" encoding="ISO-8859-1"?>
+<EtherCATInfo>
+  <Vendor><Id>2</Id><Name>Beckhoff Automation GmbH &amp; Co. KG</Name></Vendor>
+  <Descriptions>
+    <Devices>
+      <Device Physics="YY">
+        <Type ProductCode="#x0c1e3052" RevisionNo="#x00100000">EL3102</Type>
+        <RxPdo Sm="2"><Index>#x1600</Index><Name>Control</Name>
+          <Entry><Index>#x7000</Index><SubIndex>1</SubIndex><BitLen>1</BitLen><Name>Enable</Name><DataType>BOOL</DataType></Entry>
+          <Entry><Index>0</Index><BitLen>7</BitLen></Entry>
+          <Entry><Index>#x7000</Index><SubIndex>#x11</SubIndex><BitLen>16</BitLen><Name>Setpoint</Name><DataType>INT</DataType></Entry>
+        </RxPdo>
+        <TxPdo Sm="3"><Index>#x1a00</Index><Name>AI Standard Channel 1</Name>
+          <Entry><Index>#x6000</Index><SubIndex>#x11</SubIndex><BitLen>16</BitLen><Name>Value</Name><DataType>INT</DataType></Entry>
+        </TxPdo>
+        <TxPdo><Index>#x1a01</Index><Name>AI Compact Channel 1</Name>
+          <Entry><Index>#x6000</Index><SubIndex>#x11</SubIndex><BitLen>16</BitLen><Name>Value</Name><DataType>INT</DataType></Entry>
+        </TxPdo>
+      </Device>
+      <Device Physics="YY">
+        <Type ProductCode="#x0c1e3052" RevisionNo="#x00110000">EL3102</Type>
+      </Device>
+    </Devices>
+  </Descriptions>
+</EtherCATInfo>"##;
+
+    #[test]
+    fn test_parse() {
+        let devices = EsiLibrary::parse(EL_TEST).expect("parse");
+        assert_eq!(devices.len(), 2);
+        let device = &devices[0];
+        assert_eq!(
+            (device.vendor_id, device.product_id, device.revision),
+            (2, 0x0c1e3052, 0x00100000)
+        );
+        assert_eq!(device.name, "el3102");
+        // only the assigned input PDO
+        assert_eq!(device.tx_pdos.len(), 1);
+
+        let outputs = layout(&device.rx_pdos);
+        assert_eq!(
+            outputs
+                .iter()
+                .map(|(offset, _, entry)| (*offset, entry.kind()))
+                .collect::<Vec<_>>(),
+            vec![(0, Kind::Bool), (8, Kind::Signed)]
+        );
+        assert_eq!(total_bits(&device.rx_pdos), 24);
+        assert_eq!(
+            signal_name(&device.tx_pdos[0].name),
+            "ai_standard_channel_1"
+        );
+
+        let library = EsiLibrary {
+            devices: devices.into_iter().map(Arc::new).collect(),
+        };
+        let exact = library.find(2, 0x0c1e3052, 0x00100000).unwrap();
+        assert_eq!(exact.revision, 0x00100000);
+        assert_eq!(exact.tx_pdos[0].index, 0x1a00);
+        assert_eq!(exact.rx_pdos[0].entries.len(), 3);
+        // a newer revision without its own description gets the closest earlier one
+        assert_eq!(
+            library.find(2, 0x0c1e3052, 0x00120000).unwrap().revision,
+            0x00110000
+        );
+        assert!(library.find(2, 0x0c1e3052, 0x000f0000).is_none());
+        assert!(library.find(2, 0, 0x00100000).is_none());
+    }
+
+    #[test]
+    fn test_decode() {
+        assert_eq!(signed(0xFFFE, 16), -2);
+        assert_eq!(signed(0x7FFE, 16), 0x7FFE);
+        assert_eq!(signed(u64::MAX, 64), -1);
+        assert_eq!(real(1.5f32.to_bits() as u64, 32), 1.5);
+    }
+}
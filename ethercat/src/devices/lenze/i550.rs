@@ -1,11 +1,12 @@
 use crate::devices::device_trait::Index;
 use crate::devices::device_trait::WriteValueIndex;
-use crate::devices::device_trait::{Device, DeviceInfo};
+use crate::devices::device::{DeviceRegistry, Registration};
+use crate::devices::device_trait::{Device, DeviceInfo, SetupRef};
+use crate::devices::naming::{Names, Naming};
 use async_trait::async_trait;
-use atomic_refcell::AtomicRefMut;
 use bitvec::view::BitView;
 use ethercrab::EtherCrabWireSized;
-use ethercrab::{SubDevice, SubDevicePdi, SubDeviceRef};
+use ethercrab::{DcSync, SubDevicePdi, SubDeviceRef};
 use ethercrab_wire::EtherCrabWireRead;
 use ethercrab_wire::EtherCrabWireWrite;
 use log::warn;
@@ -27,6 +28,9 @@ static RX_PDO_ASSIGN: u16 = 0x1C12;
 static TX_PDO_ASSIGN: u16 = 0x1C13;
 static RX_PDO_MAPPING: u16 = 0x1605;
 static TX_PDO_MAPPING: u16 = 0x1A05;
+static SM_OUTPUT_PARAMETER: u16 = 0x1C32;
+static SM_INPUT_PARAMETER: u16 = 0x1C33;
+static SYNC_MODE_DC_SYNC0: u16 = 0x02;
 static BASIC_MOTOR_CONTROL: u16 = 0x2631;
 
 #[derive(EtherCrabWireRead, PartialEq, Eq)]
@@ -743,6 +747,26 @@ struct Config {
     analog_input_1: AnalogInput1,
     #[schemars(description = "Default speed ratio, -100.0% to 100.0%")]
     speedratio: f32,
+    #[serde(default)]
+    #[schemars(
+        description = "Synchronize to the SYNC0 pulse, only has effect when distributed clocks are enabled on the bus"
+    )]
+    dc_sync: bool,
+    #[serde(default)]
+    #[schemars(
+        description = "Signal and slot names by channel, speedratio, run or DI1 to DI7, takes effect on restart"
+    )]
+    names: Names,
+}
+
+pub fn config_schema() -> schemars::schema::RootSchema {
+    schemars::schema_for!(Config)
+}
+
+pub fn register(registry: &mut DeviceRegistry) {
+    registry.register(Registration::new::<I550>(|dbus, number, alias| {
+        Box::new(I550::new(dbus, number, alias))
+    }));
 }
 
 pub struct I550 {
@@ -757,6 +781,7 @@ pub struct I550 {
     log_key: String,
     speedratio_handle: tokio::task::JoinHandle<()>,
     run_handle: tokio::task::JoinHandle<()>,
+    _naming: Naming,
 }
 
 fn map(value: f64, in_min: f64, in_max: f64, out_min: f64, out_max: f64) -> f64 {
@@ -789,9 +814,11 @@ impl I550 {
             prefix = format!("i550/alias/{alias_address}");
         }
         let config: ConfMan<Config> = ConfMan::new(dbus.clone(), &prefix);
+        let mut naming = Naming::new(&prefix, &config.read().names);
+        let (name, description) = naming.channel("speedratio", None);
         let speedratio: tfc::ipc::Slot<f64> = tfc::ipc::Slot::new(
             dbus.clone(),
-            tfc::ipc::Base::new(format!("{prefix}/speedratio").as_str(), None),
+            tfc::ipc::Base::new(name.as_str(), description.as_deref()),
         );
         #[cfg(feature = "dbus-expose")]
         tfc::ipc::dbus::SlotInterface::register(
@@ -822,9 +849,10 @@ impl I550 {
             warn!(target: &log_key_cp, "speedratio channel closed");
         });
 
+        let (name, description) = naming.channel("run", None);
         let run = tfc::ipc::Slot::new(
             dbus.clone(),
-            tfc::ipc::Base::new(format!("{prefix}/run").as_str(), None),
+            tfc::ipc::Base::new(name.as_str(), description.as_deref()),
         );
         #[cfg(feature = "dbus-expose")]
         tfc::ipc::dbus::SlotInterface::register(run.base(), dbus.clone(), run.channel("dbus"));
@@ -841,22 +869,26 @@ impl I550 {
             warn!(target: &log_key_cp, "run channel closed");
         });
 
+        let inputs = std::array::from_fn(|idx| {
+            let (name, description) = naming.channel(&format!("DI{}", idx + 1), None);
+            let signal = tfc::ipc::Signal::new(
+                dbus.clone(),
+                tfc::ipc::Base::new(name.as_str(), description.as_deref()),
+            );
+            #[cfg(feature = "dbus-expose")]
+            tfc::ipc::dbus::SignalInterface::register(
+                signal.base(),
+                dbus.clone(),
+                signal.subscribe(),
+            );
+            signal
+        });
+        naming.check_unused();
+
         Self {
             cnt: 0,
             config,
-            inputs: std::array::from_fn(|idx| {
-                let signal = tfc::ipc::Signal::new(
-                    dbus.clone(),
-                    tfc::ipc::Base::new(format!("{prefix}/DI{}", idx + 1).as_str(), None),
-                );
-                #[cfg(feature = "dbus-expose")]
-                tfc::ipc::dbus::SignalInterface::register(
-                    signal.base(),
-                    dbus.clone(),
-                    signal.subscribe(),
-                );
-                signal
-            }),
+            inputs,
             last_inputs: [None; 7],
             speedratio,
             rpm_setpoint,
@@ -865,6 +897,7 @@ impl I550 {
             log_key: prefix,
             speedratio_handle,
             run_handle,
+            _naming: naming,
         }
     }
 }
@@ -880,7 +913,7 @@ impl Drop for I550 {
 impl Device for I550 {
     async fn setup<'maindevice, 'group>(
         &mut self,
-        device: &mut SubDeviceRef<'maindevice, AtomicRefMut<'group, SubDevice>>,
+        device: &mut SetupRef<'_, 'maindevice, 'group>,
     ) -> Result<(), Box<dyn Error + Send + Sync>> {
         warn!("Setting up I550");
 
@@ -1008,6 +1041,15 @@ impl Device for I550 {
         // analog input 1 no response on error
         device.sdo_write(0x2636, 10, 0 as u8).await?;
 
+        if self.config.read().dc_sync {
+            device
+                .sdo_write(SM_OUTPUT_PARAMETER, 0x01, SYNC_MODE_DC_SYNC0)
+                .await?;
+            device
+                .sdo_write(SM_INPUT_PARAMETER, 0x01, SYNC_MODE_DC_SYNC0)
+                .await?;
+        }
+
         warn!("I550 setup complete");
         Ok(())
     }
@@ -1092,12 +1134,42 @@ impl Device for I550 {
 
         Ok(())
     }
+    /// Quick stop with zero speed, the drive ramps down and stays in quick stop active
+    fn safe_outputs<'maindevice, 'group>(
+        &mut self,
+        device: &mut SubDeviceRef<'maindevice, SubDevicePdi<'group>>,
+    ) {
+        let output = device.outputs_raw_mut();
+        if output.len() != OutputPdo::PACKED_LEN {
+            return;
+        }
+        let output_pdo = OutputPdo {
+            control_word: CiA402::ControlWord::quick_stop(),
+            speed: 0,
+        };
+        output_pdo
+            .pack_to_slice(&mut *output)
+            .expect("Error packing output PDO");
+    }
     fn vendor_id(&self) -> u32 {
         Self::VENDOR_ID
     }
     fn product_id(&self) -> u32 {
         Self::PRODUCT_ID
     }
+    fn name(&self) -> &'static str {
+        Self::NAME
+    }
+    fn flush_config(&self) {
+        self.config.write().value_mut();
+    }
+    fn dc_sync(&self) -> DcSync {
+        if self.config.read().dc_sync {
+            DcSync::Sync0
+        } else {
+            DcSync::Disabled
+        }
+    }
     #[cfg(feature = "opcua-expose")]
     fn opcua_register(
         &mut self,
@@ -2,4 +2,7 @@ pub mod CiA402;
 pub mod beckhoff;
 pub mod device;
 pub mod device_trait;
+pub mod esi;
 pub mod lenze;
+pub mod naming;
+pub mod signals;
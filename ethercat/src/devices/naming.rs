@@ -0,0 +1,149 @@
+//! User defined names of device signals and slots, configured per device by the default channel
+//! name like `I3`. Names are unique in the process, a duplicate falls back to the default name.
+//! They are stored with the device configuration under the segment position or the station alias,
+//! serial numbers are not used as most terminals leave theirs at 0.
+
+use log::{error, warn};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+use std::sync::Mutex;
+
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Default, Debug)]
+#[schemars(
+    description = "Name of a channel. The names are stored with the device configuration, which is keyed by segment position, or by station alias when the subdevice has one. Re-ordering terminals without an alias moves their names to whichever terminal takes their old position, give terminals that may move a station alias to keep their names"
+)]
+pub struct ChannelName {
+    #[schemars(
+        description = "Signal name replacing the default `<device>/<position>/<channel>`, e.g. conveyor/photo_eye_in"
+    )]
+    pub name: String,
+    #[schemars(description = "Description replacing the default")]
+    pub description: Option<String>,
+}
+
+/// Channel names by default channel name, for the `names` entry of a device configuration
+pub type Names = BTreeMap<String, ChannelName>;
+
+/// Names held by the drivers of this process
+static CLAIMED: Mutex<BTreeSet<String>> = Mutex::new(BTreeSet::new());
+
+/// Names of one driver's channels, released when the driver is dropped
+pub struct Naming {
+    prefix: String,
+    names: Names,
+    claimed: Vec<String>,
+}
+
+impl Naming {
+    pub fn new(prefix: &str, names: &Names) -> Self {
+        Self {
+            prefix: prefix.to_string(),
+            names: names.clone(),
+            claimed: Vec::new(),
+        }
+    }
+
+    /// Name and description of a channel
+    pub fn channel(
+        &mut self,
+        channel: &str,
+        description: Option<&str>,
+    ) -> (String, Option<String>) {
+        let default = format!("{}/{}", self.prefix, channel);
+        let configured = self.names.remove(channel);
+        let mut claimed = CLAIMED.lock().unwrap_or_else(|e| e.into_inner());
+        let mut description = description.map(str::to_string);
+        if let Some(configured) = configured {
+            let name = configured.name.trim().trim_matches('/');
+            if name.is_empty() {
+                error!(target: "ethercat", "Empty name configured for {}, using the default", default);
+            } else if name == default {
+                // the default configured explicitly, only its description applies
+                description = configured.description.or(description);
+            } else if claimed.contains(name) {
+                error!(target: "ethercat", "Name {} configured for {} is already in use, using the default", name, default);
+            } else {
+                claimed.insert(name.to_string());
+                self.claimed.push(name.to_string());
+                return (name.to_string(), configured.description.or(description));
+            }
+        }
+        if !claimed.insert(default.clone()) {
+            warn!(target: "ethercat", "Signal name {} is used twice", default);
+        } else {
+            self.claimed.push(default.clone());
+        }
+        (default, description)
+    }
+
+    /// Warns about configured names of channels the driver does not have
+    pub fn check_unused(&self) {
+        for channel in self.names.keys() {
+            warn!(target: "ethercat", "Name configured for unknown channel {}/{}", self.prefix, channel);
+        }
+    }
+}
+
+impl Drop for Naming {
+    fn drop(&mut self) {
+        let mut claimed = CLAIMED.lock().unwrap_or_else(|e| e.into_inner());
+        for name in self.claimed.iter() {
+            claimed.remove(name);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn names(entries: &[(&str, &str)]) -> Names {
+        entries
+            .iter()
+            .map(|(channel, name)| {
+                (
+                    channel.to_string(),
+                    ChannelName {
+                        name: name.to_string(),
+                        description: None,
+                    },
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_channel() {
+        let mut first = Naming::new(
+            "test_el1008/3",
+            &names(&[
+                ("I3", "conveyor/photo_eye_in"),
+                ("I4", "conveyor/photo_eye_in"),
+            ]),
+        );
+        assert_eq!(
+            first.channel("I3", Some("input")),
+            (
+                "conveyor/photo_eye_in".to_string(),
+                Some("input".to_string())
+            )
+        );
+        // duplicate within the device
+        assert_eq!(first.channel("I4", None).0, "test_el1008/3/I4");
+
+        // the default configured explicitly
+        let mut explicit = Naming::new("test_el1008/6", &names(&[("I1", "test_el1008/6/I1")]));
+        assert_eq!(explicit.channel("I1", None).0, "test_el1008/6/I1");
+        assert!(CLAIMED.lock().unwrap().contains("test_el1008/6/I1"));
+
+        // duplicate of another device
+        let mut second = Naming::new("test_el1008/4", &names(&[("I1", "conveyor/photo_eye_in")]));
+        assert_eq!(second.channel("I1", None).0, "test_el1008/4/I1");
+
+        // released when the first driver is dropped
+        drop(first);
+        let mut third = Naming::new("test_el1008/5", &names(&[("I1", "conveyor/photo_eye_in")]));
+        assert_eq!(third.channel("I1", None).0, "conveyor/photo_eye_in");
+    }
+}
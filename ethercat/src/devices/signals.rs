@@ -0,0 +1,43 @@
+//! Signals and slots of driver channels, named through the driver's `Naming`. Macros as the
+//! tfc signal and slot types are generic over their value.
+
+#[macro_export]
+/// Signal of a channel, registered on D-Bus with the configured or default name
+macro_rules! channel_signal {
+    ($dbus:expr, $naming:expr, $channel:expr, $description:expr) => {{
+        let (name, description) = $naming.channel(&$channel, Some($description));
+        let signal = tfc::ipc::Signal::new(
+            $dbus.clone(),
+            tfc::ipc::Base::new(name.as_str(), description.as_deref()),
+        );
+        #[cfg(feature = "dbus-expose")]
+        tfc::ipc::dbus::SignalInterface::register(signal.base(), $dbus.clone(), signal.subscribe());
+        signal
+    }};
+}
+
+#[macro_export]
+/// Slot of a channel, registered on D-Bus with the configured or default name
+macro_rules! channel_slot {
+    ($dbus:expr, $naming:expr, $channel:expr, $description:expr) => {{
+        let (name, description) = $naming.channel(&$channel, Some($description));
+        let slot = tfc::ipc::Slot::new(
+            $dbus.clone(),
+            tfc::ipc::Base::new(name.as_str(), description.as_deref()),
+        );
+        #[cfg(feature = "dbus-expose")]
+        tfc::ipc::dbus::SlotInterface::register(slot.base(), $dbus.clone(), slot.channel("dbus"));
+        slot
+    }};
+}
+
+#[macro_export]
+/// Publishes a value from the cycle. A failed send is logged and the cycle carries on, it is no
+/// fault of the subdevice
+macro_rules! send_signal {
+    ($signal:expr, $value:expr, $log_key:expr, $key:expr) => {
+        if let Err(e) = $signal.async_send($value).await {
+            log::error!(target: $log_key, "Error sending signal of {}: {}", $key, e);
+        }
+    };
+}
@@ -0,0 +1,453 @@
+use crate::bus::DeviceSlot;
+use crate::dc::DcSignals;
+use crate::devices::device_trait::{Device, SetupRef, UnimplementedDevice};
+use crate::recovery::{WkcRecovery, AL_STATUS};
+use crate::sdo::SdoRequest;
+use crate::stats::CycleStats;
+use crate::topology::Identity;
+use ethercrab::{
+    subdevice_group::{CycleInfo, HasDc, Op},
+    MainDevice, SubDeviceGroup, SubDevicePdi, SubDeviceRef,
+};
+use log::{info, warn};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::error::Error;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tfc::time::MicroDuration;
+use tokio::sync::{watch, Mutex};
+
+/// Maximum number of groups including the default group, ethercrab takes the groups as one array at init.
+pub const MAX_GROUPS: usize = 4;
+
+#[derive(Deserialize, Serialize, JsonSchema, Clone)]
+pub struct GroupConfig {
+    #[schemars(description = "Name of the group, used in logs")]
+    pub name: String,
+    #[schemars(description = "Cycle time of the group. Microseconds")]
+    pub cycle_time: MicroDuration,
+    #[schemars(
+        description = "Positions of the subdevices in the segment belonging to this group, starting from 0"
+    )]
+    pub positions: Vec<usize>,
+}
+
+/// Index of the group the subdevice at a position belongs to,
+/// 0 is the default group of all subdevices not listed in a configured group
+pub fn group_index(groups: &[GroupConfig], position: usize) -> usize {
+    groups
+        .iter()
+        .position(|group| group.positions.contains(&position))
+        .map_or(0, |index| index + 1)
+}
+
+pub fn validate(groups: &[GroupConfig]) -> Result<(), Box<dyn Error + Send + Sync>> {
+    if groups.len() >= MAX_GROUPS {
+        return Err(format!(
+            "At most {} groups can be configured, got: {}",
+            MAX_GROUPS - 1,
+            groups.len()
+        )
+        .into());
+    }
+    for (index, group) in groups.iter().enumerate() {
+        for position in group.positions.iter() {
+            if let Some(other) = groups[..index]
+                .iter()
+                .find(|other| other.positions.contains(position))
+            {
+                return Err(format!(
+                    "Subdevice at position {} is assigned to both group {} and {}",
+                    position, other.name, group.name
+                )
+                .into());
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Group in operational, with distributed clocks the cycle is paced by the reference clock
+pub(crate) enum OpGroup<const MAX_SUBDEVICES: usize, const PDI_LEN: usize> {
+    NoDc(SubDeviceGroup<MAX_SUBDEVICES, PDI_LEN, Op>),
+    Dc(SubDeviceGroup<MAX_SUBDEVICES, PDI_LEN, Op, HasDc>),
+}
+
+impl<const MAX_SUBDEVICES: usize, const PDI_LEN: usize> OpGroup<MAX_SUBDEVICES, PDI_LEN> {
+    pub fn len(&self) -> usize {
+        match self {
+            Self::NoDc(group) => group.len(),
+            Self::Dc(group) => group.len(),
+        }
+    }
+    pub fn subdevice<'maindevice, 'group>(
+        &'group self,
+        main_device: &'maindevice MainDevice<'maindevice>,
+        index: usize,
+    ) -> Result<SubDeviceRef<'maindevice, SubDevicePdi<'group>>, ethercrab::error::Error> {
+        match self {
+            Self::NoDc(group) => group.subdevice(main_device, index),
+            Self::Dc(group) => group.subdevice(main_device, index),
+        }
+    }
+    /// Walks the group back through safe-op and pre-op to init
+    pub async fn into_init(
+        self,
+        main_device: &MainDevice<'_>,
+    ) -> Result<(), ethercrab::error::Error> {
+        match self {
+            Self::NoDc(group) => {
+                group
+                    .into_safe_op(main_device)
+                    .await?
+                    .into_pre_op(main_device)
+                    .await?
+                    .into_init(main_device)
+                    .await?;
+            }
+            Self::Dc(group) => {
+                group
+                    .into_safe_op(main_device)
+                    .await?
+                    .into_pre_op(main_device)
+                    .await?
+                    .into_init(main_device)
+                    .await?;
+            }
+        }
+        Ok(())
+    }
+    /// Returns the working counter and, with distributed clocks, the timing of the next cycle
+    pub async fn tx_rx(
+        &self,
+        main_device: &MainDevice<'_>,
+    ) -> Result<(u16, Option<CycleInfo>), ethercrab::error::Error> {
+        match self {
+            Self::NoDc(group) => Ok((group.tx_rx(main_device).await?, None)),
+            Self::Dc(group) => {
+                let (wkc, cycle_info) = group.tx_rx_dc(main_device).await?;
+                Ok((wkc, Some(cycle_info)))
+            }
+        }
+    }
+}
+
+/// Bus wide settings read once before the groups are started
+#[derive(Clone, Copy)]
+pub(crate) struct RunSettings {
+    pub wkc_error_tolerance: u32,
+    pub recovery_timeout: Duration,
+    pub dc_publish_interval: Duration,
+    pub stats_publish_interval: Duration,
+}
+
+/// Operational group together with the drivers of its subdevices, runs the cyclic exchange in its own task.
+/// The drivers are handed back to the bus when the task stops.
+pub(crate) struct GroupRunner<const MAX_SUBDEVICES: usize, const PDI_LEN: usize> {
+    name: String,
+    group: OpGroup<MAX_SUBDEVICES, PDI_LEN>,
+    /// Segment position of each subdevice, in group order
+    positions: Vec<usize>,
+    /// Drivers, in group order
+    slots: Vec<DeviceSlot>,
+    expected_working_counter: u16,
+    cycle_time: Duration,
+    recovery: WkcRecovery,
+    stats: CycleStats,
+    /// Next subdevice to have its AL state read for the status report
+    status_index: usize,
+    /// Next subdevice to have its SDO queue checked
+    sdo_index: usize,
+    log_key: String,
+}
+
+impl<const MAX_SUBDEVICES: usize, const PDI_LEN: usize> GroupRunner<MAX_SUBDEVICES, PDI_LEN> {
+    pub fn new(
+        name: String,
+        group: OpGroup<MAX_SUBDEVICES, PDI_LEN>,
+        positions: Vec<usize>,
+        slots: Vec<DeviceSlot>,
+        expected_working_counter: u16,
+        cycle_time: Duration,
+        stats: CycleStats,
+        log_key: &str,
+    ) -> Self {
+        Self {
+            name,
+            group,
+            positions,
+            slots,
+            expected_working_counter,
+            cycle_time,
+            recovery: WkcRecovery::new(log_key),
+            stats,
+            status_index: 0,
+            sdo_index: 0,
+            log_key: log_key.to_string(),
+        }
+    }
+
+    /// Name, statistics, the group and the drivers by segment position
+    pub fn into_parts(
+        self,
+    ) -> (
+        String,
+        CycleStats,
+        OpGroup<MAX_SUBDEVICES, PDI_LEN>,
+        impl Iterator<Item = (usize, DeviceSlot)>,
+    ) {
+        (
+            self.name,
+            self.stats,
+            self.group,
+            self.positions.into_iter().zip(self.slots),
+        )
+    }
+
+    /// Runs until an error or until stop is set, the runner is returned in both cases.
+    /// The drivers' safe outputs are sent before returning
+    pub async fn run(
+        mut self,
+        main_device: Arc<MainDevice<'static>>,
+        settings: RunSettings,
+        dc_signals: Arc<Mutex<DcSignals>>,
+        stop: watch::Receiver<bool>,
+    ) -> (Self, Result<(), Box<dyn Error + Send + Sync>>) {
+        let result = self.cycle(&main_device, settings, &dc_signals, &stop).await;
+        self.safe_outputs(&main_device).await;
+        (self, result)
+    }
+
+    /// Best effort, after a bus error the exchange is likely to fail as well and the
+    /// subdevices are left to their watchdogs
+    async fn safe_outputs(&mut self, main_device: &MainDevice<'_>) {
+        for (device_index, slot) in self.slots.iter_mut().enumerate() {
+            if let Ok(mut subdevice) = self.group.subdevice(main_device, device_index) {
+                slot.device.safe_outputs(&mut subdevice);
+            }
+        }
+        match self.group.tx_rx(main_device).await {
+            Ok(_) => {
+                info!(target: &self.log_key, "Group {} outputs set to safe state", self.name);
+                for slot in self.slots.iter_mut() {
+                    slot.device.publish_safe_outputs().await;
+                }
+            }
+            Err(e) => {
+                warn!(target: &self.log_key, "Group {} failed to send safe outputs: {}", self.name, e)
+            }
+        }
+    }
+
+    async fn cycle(
+        &mut self,
+        main_device: &MainDevice<'_>,
+        settings: RunSettings,
+        dc_signals: &Mutex<DcSignals>,
+        stop: &watch::Receiver<bool>,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let mut tick_interval = tokio::time::interval(self.cycle_time);
+        info!(target: &self.log_key, "Group {} tick interval: {:?}", self.name, self.cycle_time);
+        tick_interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Skip);
+        self.stats.begin(&self.positions);
+        self.recovery.snapshot(&self.group, main_device).await;
+        // driver of a power cycled subdevice being set up, it is polled while waiting for the next cycle
+        let mut setup: Option<(usize, Option<Identity>, SetupFuture<'_>)> = None;
+        let result = loop {
+            if *stop.borrow() {
+                break Ok(());
+            }
+            let cycle_instant = Instant::now();
+            self.stats.cycle_start(self.cycle_time);
+            let (wc, cycle_info) = match self.group.tx_rx(main_device).await {
+                Ok(exchanged) => exchanged,
+                Err(e) => break Err(e.into()),
+            };
+            self.stats.tx_rx(cycle_instant.elapsed());
+
+            // subdevices which dropped out of op or were power cycled are brought back while the rest keep running,
+            // if that is not possible the error propagates and the group is re-initialized
+            // https://github.com/ethercrab-rs/ethercrab/discussions/253
+            if let Err(e) = self
+                .recovery
+                .cycle(
+                    settings.wkc_error_tolerance,
+                    settings.recovery_timeout,
+                    wc,
+                    self.expected_working_counter,
+                    &self.group,
+                    &self.positions,
+                    main_device,
+                )
+                .await
+            {
+                break Err(e);
+            }
+            if setup.is_none() {
+                if let Some(index) = self.recovery.setup_due() {
+                    // without its identity the bus binds a new driver if the group stops before it is handed back
+                    let slot = &mut self.slots[index];
+                    let identity = slot.identity.take();
+                    let device = std::mem::replace(&mut slot.device, Box::new(UnimplementedDevice));
+                    setup = Some((
+                        index,
+                        identity,
+                        setup_future(&self.group, main_device, index, device),
+                    ));
+                }
+            }
+
+            for (device_index, slot) in self.slots.iter_mut().enumerate() {
+                if setup
+                    .as_ref()
+                    .is_some_and(|(index, _, _)| *index == device_index)
+                {
+                    continue;
+                }
+                let Ok(mut subdevice) = self.group.subdevice(main_device, device_index) else {
+                    continue;
+                };
+                let process_data_instant = Instant::now();
+                let result = slot.process_data(&mut subdevice).await;
+                if let Some(status) = slot.status.as_mut() {
+                    if status.record(&result) {
+                        if let Err(e) = &result {
+                            warn!(target: &self.log_key, "Failed to process data for subdevice {}: {}", self.positions[device_index], e);
+                        }
+                    }
+                }
+                self.stats
+                    .process_data(device_index, process_data_instant.elapsed());
+            }
+            self.stats
+                .cycle_end(cycle_instant.elapsed(), self.cycle_time);
+            self.stats.publish(settings.stats_publish_interval).await;
+
+            // one subdevice per cycle, so the extra register read does not add up on long lines
+            if !self.slots.is_empty() {
+                let index = self.status_index % self.slots.len();
+                self.status_index = index + 1;
+                if let Some(status) = self.slots[index]
+                    .status
+                    .as_mut()
+                    .filter(|status| status.is_due(settings.stats_publish_interval))
+                {
+                    let al_status = match self.group.subdevice(main_device, index) {
+                        Ok(subdevice) => subdevice.register_read::<u16>(AL_STATUS).await.ok(),
+                        Err(_) => None,
+                    };
+                    status.publish_al_status(al_status).await;
+                }
+            }
+
+            // at most one SDO request per cycle, the mailbox round trip delays this cycle only
+            if let Some((index, request)) = next_sdo_request(&mut self.slots, &mut self.sdo_index) {
+                if let Ok(subdevice) = self.group.subdevice(main_device, index) {
+                    request
+                        .execute(&subdevice, self.positions[index], &self.log_key)
+                        .await;
+                }
+            }
+
+            let wait = async {
+                match cycle_info {
+                    Some(cycle_info) => {
+                        // the offset is the same for every group, whichever gets the lock publishes it
+                        if let Ok(mut dc_signals) = dc_signals.try_lock() {
+                            dc_signals
+                                .cycle(&cycle_info, settings.dc_publish_interval)
+                                .await;
+                        }
+                        tokio::time::sleep(cycle_info.next_cycle_wait).await;
+                    }
+                    None => {
+                        tick_interval.tick().await;
+                    }
+                }
+            };
+            tokio::pin!(wait);
+            let finished = match setup.as_mut() {
+                Some((_, _, future)) => tokio::select! {
+                    _ = &mut wait => None,
+                    finished = future => Some(finished),
+                },
+                None => {
+                    (&mut wait).await;
+                    None
+                }
+            };
+            if let Some((device, result)) = finished {
+                if let Some((index, identity, _)) = setup.take() {
+                    let slot = &mut self.slots[index];
+                    slot.device = device;
+                    slot.identity = identity;
+                    slot.needs_setup = result.is_err();
+                    if let Err(e) = self.recovery.setup_done(index, result) {
+                        break Err(e);
+                    }
+                }
+                wait.await;
+            }
+        };
+        // the driver being set up is waited for, so its own safe outputs are sent and it is
+        // handed back to the bus
+        if let Some((index, identity, future)) = setup.take() {
+            let (device, setup_result) = future.await;
+            if let Err(e) = &setup_result {
+                warn!(target: &self.log_key, "Setup of subdevice {} failed while stopping: {}", self.positions[index], e);
+            }
+            let slot = &mut self.slots[index];
+            slot.device = device;
+            slot.identity = identity;
+            slot.needs_setup = setup_result.is_err();
+        }
+        result
+    }
+}
+
+type SetupFuture<'a> = Pin<
+    Box<
+        dyn Future<
+                Output = (
+                    Box<dyn Device + Send + Sync>,
+                    Result<(), Box<dyn Error + Send + Sync>>,
+                ),
+            > + Send
+            + 'a,
+    >,
+>;
+
+/// Sets up the driver of a power cycled subdevice in pre-op, the driver is handed back with the result
+fn setup_future<'a, const MAX_SUBDEVICES: usize, const PDI_LEN: usize>(
+    group: &'a OpGroup<MAX_SUBDEVICES, PDI_LEN>,
+    main_device: &'a MainDevice<'a>,
+    index: usize,
+    mut device: Box<dyn Device + Send + Sync>,
+) -> SetupFuture<'a> {
+    Box::pin(async move {
+        let result = match group.subdevice(main_device, index) {
+            Ok(mut subdevice) => device.setup(&mut SetupRef::Running(&mut subdevice)).await,
+            Err(e) => Err(e.into()),
+        };
+        (device, result)
+    })
+}
+
+/// Queued SDO request of the next subdevice in turn which has one, with its group index
+fn next_sdo_request(
+    slots: &mut [DeviceSlot],
+    sdo_index: &mut usize,
+) -> Option<(usize, SdoRequest)> {
+    let len = slots.len();
+    for offset in 0..len {
+        let index = (*sdo_index + offset) % len;
+        if let Some(request) = slots[index].sdo.as_mut().and_then(|sdo| sdo.try_next()) {
+            *sdo_index = index + 1;
+            return Some((index, request));
+        }
+    }
+    None
+}
@@ -1,3 +1,13 @@
 pub mod bus;
-mod devices;
+mod dc;
+pub mod devices;
+mod group;
 pub mod opcua;
+mod recovery;
+pub mod scan;
+mod sdo;
+#[cfg(any(test, feature = "simulation"))]
+pub mod sim;
+mod stats;
+mod status;
+mod topology;
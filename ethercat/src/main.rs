@@ -1,20 +1,129 @@
-use log::debug;
+use clap::{CommandFactory, Parser};
+use ethercrab::MainDeviceConfig;
+use log::{debug, error, info, warn};
+use std::path::PathBuf;
+use std::time::Duration;
 use tfc::logger;
 use tfc::progbase;
 
 mod bus;
+mod dc;
 mod devices;
+mod group;
 #[cfg(feature = "opcua-expose")]
 mod opcua;
+mod recovery;
+mod scan;
+mod sdo;
+#[cfg(any(test, feature = "simulation"))]
+mod sim;
+mod stats;
+mod status;
+mod topology;
 
-use crate::bus::Bus;
+use crate::devices::device::DeviceRegistry;
 #[cfg(feature = "opcua-expose")]
 use crate::opcua::OpcuaServer;
 
+/// Options of the ethercat service
+#[derive(Parser, Debug)]
+#[command(version, about = "TFC ethercat service")]
+struct Cli {
+    /// Network interface of the segment, overrides the bus configuration
+    #[arg(long)]
+    interface: Option<String>,
+    /// OPC UA server configuration file
+    #[arg(long, default_value = "/etc/tfc/ethercat/def/server.conf")]
+    opcua_config: PathBuf,
+    /// Cycle time of the default group in microseconds, overrides the bus configuration
+    #[arg(long)]
+    cycle_time: Option<u64>,
+    /// Dump the subdevices of the segment as JSON and exit
+    #[arg(long)]
+    scan: bool,
+    /// Dump the object dictionary with --scan, all of it or the index ranges given like
+    /// 0x1000-0x1fff,0x8000-0x8fff
+    #[arg(long, num_args = 0.., value_parser = scan::parse_range, value_delimiter = ',')]
+    objects: Option<Vec<std::ops::RangeInclusive<u16>>>,
+    /// File to write the --scan JSON to instead of stdout
+    #[arg(long)]
+    output: Option<PathBuf>,
+    /// Set up the subdevices but leave them in pre-op
+    #[arg(long)]
+    dry_run: bool,
+    /// Print the JSON schema of the bus and every driver configuration and exit
+    #[arg(long)]
+    print_schema: bool,
+}
+
+/// Arguments for the strict parse of the service options. progbase reads its own options from
+/// the command line itself, so every option `Cli` does not know is left out with its values
+fn service_args(args: impl IntoIterator<Item = String>) -> Vec<String> {
+    let mut command = Cli::command();
+    command.build();
+    let mut args = args.into_iter();
+    let mut service_args: Vec<String> = args.next().into_iter().collect();
+    let mut own = false;
+    for arg in args {
+        let option = match arg.strip_prefix("--") {
+            Some(long) => {
+                let name = long.split('=').next().unwrap_or(long);
+                Some(command.get_arguments().any(|a| a.get_long() == Some(name)))
+            }
+            None => arg
+                .strip_prefix('-')
+                .and_then(|short| short.chars().next())
+                .map(|short| {
+                    command
+                        .get_arguments()
+                        .any(|a| a.get_short() == Some(short))
+                }),
+        };
+        // values follow the option they belong to
+        if let Some(known) = option {
+            own = known;
+        }
+        if own {
+            service_args.push(arg);
+        }
+    }
+    service_args
+}
+
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let cli = Cli::parse_from(service_args(std::env::args()));
+    if cli.print_schema {
+        let mut schemas = serde_json::Map::new();
+        for (name, schema) in bus::config_schemas() {
+            schemas.insert(name.to_string(), serde_json::to_value(schema)?);
+        }
+        println!("{}", serde_json::to_string_pretty(&schemas)?);
+        return Ok(());
+    }
+
     // console_subscriber::init();
     progbase::init();
+    // stdout only gets the JSON of a scan, so it can be redirected to a file
+    if cli.scan {
+        // the interface from the configuration is read without claiming the service name,
+        // so the configuration of a running service can be used
+        let source = match cli.interface {
+            Some(interface) => bus::SegmentSource::new(interface),
+            None => bus::configured_segment(zbus::connection::Builder::system()?.build().await?),
+        };
+        let main_device = bus::main_device(&source, MainDeviceConfig::default());
+        let scanned = scan::scan(&main_device, cli.objects.as_deref())
+            .await
+            .map_err(|e| format!("Scan failed: {}", e))?;
+        let json = serde_json::to_string_pretty(&scanned)?;
+        match cli.output {
+            Some(output) => std::fs::write(output, json + "\n")?,
+            None => println!("{}", json),
+        }
+        return Ok(());
+    }
+
     println!("Starting ethercat");
     println!("{}", progbase::exe_name());
     println!("{}", progbase::proc_name());
@@ -27,16 +136,12 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         progbase::proc_name()
     );
     let dbus = zbus::connection::Builder::system()?
-        .name(formatted_name)?
+        .name(formatted_name.clone())?
         .build()
         .await?;
 
     #[cfg(feature = "opcua-expose")]
-    let opcua_server = OpcuaServer::new(
-        "/etc/tfc/ethercat/def/server.conf", // todo as command line arg ?
-        "urn:Ethercat",
-        "Ethercat",
-    );
+    let opcua_server = OpcuaServer::new(cli.opcua_config, "urn:Ethercat", "Ethercat");
     #[cfg(feature = "opcua-expose")]
     let opcua_handle = opcua_server.make_handle(
         opcua_server
@@ -45,17 +150,100 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
             .expect("Failed to get namespace index"),
     );
 
-    let mut bus = Bus::new(
-        dbus.clone(),
-        #[cfg(feature = "opcua-expose")]
-        opcua_handle,
-    );
+    let overrides = bus::Overrides {
+        interface: cli.interface,
+        cycle_time: cli.cycle_time.map(Duration::from_micros),
+        dry_run: cli.dry_run,
+    };
+    let (shutdown_tx, shutdown_rx) = tokio::sync::watch::channel(false);
+    let bus_dbus = dbus.clone();
+    let mut bus_handle = tokio::spawn(async move {
+        bus::init_and_run(
+            bus_dbus,
+            shutdown_rx,
+            overrides,
+            DeviceRegistry::with_builtin(),
+            #[cfg(feature = "opcua-expose")]
+            opcua_handle,
+        )
+        .await
+    });
 
-    tokio::spawn(async move { bus.init_and_run(dbus).await });
+    #[cfg(feature = "opcua-expose")]
+    let opcua_server_handle = opcua_server.handle.clone();
+    #[cfg(feature = "opcua-expose")]
+    let opcua_task = tokio::spawn(async move { opcua_server.server.run().await });
+
+    let mut sigterm = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())?;
+    tokio::select! {
+        _ = sigterm.recv() => info!(target: "ethercat", "Received SIGTERM"),
+        _ = tokio::signal::ctrl_c() => info!(target: "ethercat", "Received SIGINT"),
+        result = &mut bus_handle => {
+            // the bus only returns by itself when it fails, exit so the service gets restarted
+            error!(target: "ethercat", "Bus stopped unexpectedly: {:?}", result);
+            return Err("Bus stopped unexpectedly".into());
+        }
+    }
+
+    // the bus stops the cycle, sends safe outputs and takes the groups to init within its shutdown timeout
+    let _ = shutdown_tx.send(true);
+    match bus_handle.await {
+        Ok(Ok(())) => info!(target: "ethercat", "Bus stopped"),
+        Ok(Err(e)) => error!(target: "ethercat", "Bus stopped with error: {}", e),
+        Err(e) => error!(target: "ethercat", "Bus task failed: {}", e),
+    }
 
     #[cfg(feature = "opcua-expose")]
-    tokio::spawn(async move { opcua_server.server.run().await });
+    {
+        opcua_server_handle.cancel();
+        let _ = opcua_task.await;
+    }
 
-    std::future::pending::<()>().await;
+    // the bus saved the configuration when it stopped. Closing the connection takes down every
+    // object the service still serves, the signals, slots and status of the drivers included
+    if let Err(e) = dbus.release_name(formatted_name.as_str()).await {
+        warn!(target: "ethercat", "Failed to release {}: {}", formatted_name, e);
+    }
+    if let Err(e) = dbus.close().await {
+        warn!(target: "ethercat", "Failed to close the dbus connection: {}", e);
+    }
+    info!(target: "ethercat", "Shutdown complete");
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_service_args() {
+        let args = service_args(
+            [
+                "ethercat",
+                "--id",
+                "line1",
+                "--stdout",
+                "--scan",
+                "--objects",
+                "0x1000-0x1fff",
+                "--log-level=debug",
+                "--cycle-time=2000",
+            ]
+            .map(String::from),
+        );
+        assert_eq!(
+            args,
+            [
+                "ethercat",
+                "--scan",
+                "--objects",
+                "0x1000-0x1fff",
+                "--cycle-time=2000"
+            ]
+        );
+        let cli = Cli::try_parse_from(args).expect("parse");
+        assert!(cli.scan);
+        assert_eq!(cli.cycle_time, Some(2000));
+        assert_eq!(cli.objects, Some(vec![0x1000..=0x1fff]));
+    }
+}
@@ -19,8 +19,18 @@ impl OpcuaServer {
         namespace_uri: impl Into<String>,
         name: impl Into<String>,
     ) -> Self {
-        let (server, handle) = ServerBuilder::new()
-            .with_config_from(path)
+        Self::build(
+            ServerBuilder::new().with_config_from(path),
+            namespace_uri,
+            name,
+        )
+    }
+    fn build(
+        builder: ServerBuilder,
+        namespace_uri: impl Into<String>,
+        name: impl Into<String>,
+    ) -> Self {
+        let (server, handle) = builder
             .with_node_manager(simple_node_manager(
                 NamespaceMetadata {
                     namespace_uri: namespace_uri.into(),
@@ -49,3 +59,16 @@ pub struct OpcuaServerHandle {
     pub subscriptions: Arc<SubscriptionCache>,
     pub namespace: u16,
 }
+
+/// Handle of a server with the default configuration which is never run, for building the bus
+/// in tests
+#[cfg(test)]
+pub(crate) fn test_handle() -> OpcuaServerHandle {
+    let server = OpcuaServer::build(ServerBuilder::new(), "urn:Test", "Test");
+    server.make_handle(
+        server
+            .handle
+            .get_namespace_index("urn:Test")
+            .expect("Failed to get namespace index"),
+    )
+}
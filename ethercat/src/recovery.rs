@@ -0,0 +1,559 @@
+use crate::group::OpGroup;
+use crate::scan::Sii;
+use ethercrab::{Command, MainDevice, SubDevice, SubDeviceRef};
+use log::{info, warn};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::error::Error;
+use std::ops::Deref;
+use std::time::{Duration, Instant};
+use tfc::time::MilliDuration;
+
+// ESC registers, see EtherCAT slave controller section II
+const FMMU_COUNT: u16 = 0x0004;
+const SM_COUNT: u16 = 0x0005;
+const STATION_ADDRESS: u16 = 0x0010;
+const AL_CONTROL: u16 = 0x0120;
+pub const AL_STATUS: u16 = 0x0130;
+const AL_STATUS_CODE: u16 = 0x0134;
+const FMMU: u16 = 0x0600;
+const SM: u16 = 0x0800;
+const FMMU_LEN: usize = 16;
+const SM_LEN: usize = 8;
+/// Operation mode bits of the sync manager control byte, mailbox or buffered
+const SM_MODE_MASK: u8 = 0x03;
+const SM_MODE_MAILBOX: u8 = 0x02;
+
+// SII words of the identity
+const SII_VENDOR_ID: u16 = 0x0008;
+const SII_PRODUCT_ID: u16 = 0x000A;
+const SII_REVISION: u16 = 0x000C;
+
+const AL_STATE_MASK: u16 = 0x0F;
+const AL_ERROR_FLAG: u16 = 0x10; // in AL status, same bit in AL control acknowledges the error
+const AL_STATE_INIT: u16 = 0x01;
+const AL_STATE_PRE_OP: u16 = 0x02;
+const AL_STATE_SAFE_OP: u16 = 0x04;
+const AL_STATE_OP: u16 = 0x08;
+
+#[derive(Deserialize, Serialize, JsonSchema)]
+pub struct RecoveryConfig {
+    #[schemars(
+        description = "Number of consecutive cycles with working counter mismatch tolerated before subdevices are inspected for recovery"
+    )]
+    pub wkc_error_tolerance: u32,
+    #[schemars(
+        description = "Time allowed for dropped or power cycled subdevices to get back to operational before the whole segment is re-initialized. Milliseconds"
+    )]
+    pub recovery_timeout: MilliDuration,
+}
+impl Default for RecoveryConfig {
+    fn default() -> Self {
+        Self {
+            wkc_error_tolerance: 3,
+            recovery_timeout: Duration::from_millis(5000).into(),
+        }
+    }
+}
+
+/// Keeps track of working counter mismatches in the cyclic exchange.
+/// When the tolerance is exceeded the AL status of every subdevice is read and the ones
+/// that dropped out of operational are stepped back up, one register access per subdevice per cycle,
+/// so the rest of the group keeps exchanging process data in the meantime.
+/// Subdevices that fell back to INIT or lost their configured address have been power cycled,
+/// those get their sync managers and FMMUs written back from the snapshot taken when the group
+/// went operational and their driver set up again, see `Restore`. Groups with distributed clocks
+/// can not be restored this way, as the SYNC0 start time and the clock offsets would have to be
+/// computed again, so an error is returned and the segment is re-initialized.
+pub struct WkcRecovery {
+    log_key: String,
+    bad_cycles: u32,
+    pending: Vec<usize>,
+    restoring: Vec<(usize, Restore)>,
+    /// Registers by group index, None when they could not be read
+    snapshots: Vec<Option<EscSnapshot>>,
+    started: Option<Instant>,
+}
+
+/// Progress of bringing a power cycled subdevice back, one step per cycle
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Restore {
+    /// Answers neither at its configured address nor at its position, waiting for it to power up
+    Lost,
+    /// Answers at its position but has lost its configured address
+    Address,
+    /// Checked against the identity it had, then its mailbox is configured and pre-op requested
+    Identity,
+    PreOp,
+    /// Waiting for the group runner to set up its driver
+    Setup,
+    SettingUp,
+    /// Driver set up, the process data sync managers and FMMUs are written and safe-op requested
+    ProcessData,
+    SafeOp,
+}
+
+/// Sync manager and FMMU registers of a subdevice as configured by the main device
+#[derive(Debug, Clone)]
+pub struct EscSnapshot {
+    sync_managers: Vec<[u8; SM_LEN]>,
+    fmmus: Vec<[u8; FMMU_LEN]>,
+}
+
+impl EscSnapshot {
+    pub async fn read<S: Deref<Target = SubDevice>>(
+        subdevice: &SubDeviceRef<'_, S>,
+    ) -> Result<Self, ethercrab::error::Error> {
+        let sm_count: u8 = subdevice.register_read(SM_COUNT).await?;
+        let fmmu_count: u8 = subdevice.register_read(FMMU_COUNT).await?;
+        let mut sync_managers = Vec::with_capacity(sm_count as usize);
+        for sm in 0..sm_count as u16 {
+            sync_managers.push(subdevice.register_read(SM + sm * SM_LEN as u16).await?);
+        }
+        let mut fmmus = Vec::with_capacity(fmmu_count as usize);
+        for fmmu in 0..fmmu_count as u16 {
+            fmmus.push(
+                subdevice
+                    .register_read(FMMU + fmmu * FMMU_LEN as u16)
+                    .await?,
+            );
+        }
+        Ok(Self {
+            sync_managers,
+            fmmus,
+        })
+    }
+
+    /// Writes the mailbox sync managers, or the process data ones and the FMMUs
+    async fn write<S: Deref<Target = SubDevice>>(
+        &self,
+        subdevice: &SubDeviceRef<'_, S>,
+        mailbox: bool,
+    ) -> Result<(), ethercrab::error::Error> {
+        for (sm, registers) in self.sync_managers.iter().enumerate() {
+            if (registers[4] & SM_MODE_MASK == SM_MODE_MAILBOX) == mailbox {
+                subdevice
+                    .register_write(SM + (sm * SM_LEN) as u16, *registers)
+                    .await?;
+            }
+        }
+        if !mailbox {
+            for (fmmu, registers) in self.fmmus.iter().enumerate() {
+                subdevice
+                    .register_write(FMMU + (fmmu * FMMU_LEN) as u16, *registers)
+                    .await?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl WkcRecovery {
+    pub fn new(log_key: &str) -> Self {
+        Self {
+            log_key: log_key.to_string(),
+            bad_cycles: 0,
+            pending: Vec::new(),
+            restoring: Vec::new(),
+            snapshots: Vec::new(),
+            started: None,
+        }
+    }
+
+    pub fn is_recovering(&self) -> bool {
+        !self.pending.is_empty() || !self.restoring.is_empty()
+    }
+
+    pub fn reset(&mut self) {
+        self.bad_cycles = 0;
+        self.pending.clear();
+        self.restoring.clear();
+        self.started = None;
+    }
+
+    /// Reads the registers written back to power cycled subdevices, called once the group is operational
+    pub async fn snapshot<const MAX_SUBDEVICES: usize, const PDI_LEN: usize>(
+        &mut self,
+        group: &OpGroup<MAX_SUBDEVICES, PDI_LEN>,
+        main_device: &MainDevice<'_>,
+    ) {
+        self.snapshots.clear();
+        for index in 0..group.len() {
+            let snapshot = match group.subdevice(main_device, index) {
+                Ok(subdevice) => EscSnapshot::read(&subdevice).await.map_err(|e| e.into()),
+                Err(e) => Err::<EscSnapshot, Box<dyn Error + Send + Sync>>(e.into()),
+            };
+            if let Err(e) = &snapshot {
+                warn!(target: &self.log_key, "Failed to read sync managers and FMMUs of subdevice {}, it can not be restored after a power cycle: {}", index, e);
+            }
+            self.snapshots.push(snapshot.ok());
+        }
+    }
+
+    /// Group index of a restored subdevice whose driver is due to be set up, it is only returned once
+    pub fn setup_due(&mut self) -> Option<usize> {
+        let (index, restore) = self
+            .restoring
+            .iter_mut()
+            .find(|(_, restore)| *restore == Restore::Setup)?;
+        *restore = Restore::SettingUp;
+        Some(*index)
+    }
+
+    pub fn setup_done(
+        &mut self,
+        index: usize,
+        result: Result<(), Box<dyn Error + Send + Sync>>,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        result.map_err(|e| format!("Setup of power cycled subdevice {} failed: {}", index, e))?;
+        if let Some((_, restore)) = self.restoring.iter_mut().find(|(i, _)| *i == index) {
+            *restore = Restore::ProcessData;
+        }
+        Ok(())
+    }
+
+    /// Called once per cycle after tx/rx, positions are the segment positions of the group's subdevices
+    #[allow(clippy::too_many_arguments)]
+    pub async fn cycle<const MAX_SUBDEVICES: usize, const PDI_LEN: usize>(
+        &mut self,
+        tolerance: u32,
+        timeout: Duration,
+        working_counter: u16,
+        expected_working_counter: u16,
+        group: &OpGroup<MAX_SUBDEVICES, PDI_LEN>,
+        positions: &[usize],
+        main_device: &MainDevice<'_>,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        // subdevices being recovered may answer their process data before they are back in operational
+        if working_counter == expected_working_counter && !self.is_recovering() {
+            if self.bad_cycles > 0 {
+                info!(target: &self.log_key, "Working counter recovered after {} bad cycles", self.bad_cycles);
+            }
+            self.reset();
+            return Ok(());
+        }
+        self.bad_cycles += 1;
+        if self.bad_cycles == 1 {
+            warn!(target: &self.log_key, "Working counter mismatch, expected: {}, got: {}", expected_working_counter, working_counter);
+        }
+
+        if !self.is_recovering() {
+            if self.bad_cycles <= tolerance {
+                return Ok(());
+            }
+            self.identify(group, main_device).await?;
+            if !self.is_recovering() {
+                return Err(format!(
+                    "Working counter mismatch for {} cycles, expected: {}, got: {}, but all subdevices report operational",
+                    self.bad_cycles, expected_working_counter, working_counter
+                )
+                .into());
+            }
+            self.started = Some(Instant::now());
+            return Ok(());
+        }
+
+        if let Some(started) = self.started {
+            if started.elapsed() > timeout {
+                return Err(format!(
+                    "Subdevices {:?} did not get back to operational within {:?}",
+                    self.pending
+                        .iter()
+                        .chain(self.restoring.iter().map(|(index, _)| index))
+                        .collect::<Vec<_>>(),
+                    timeout
+                )
+                .into());
+            }
+        }
+        let recovering = self.pending.len() + self.restoring.len();
+        self.step(group, main_device).await?;
+        self.restore(group, positions, main_device).await?;
+        if !self.is_recovering() {
+            info!(target: &self.log_key, "{} subdevices back in operational, working counter: {}", recovering, working_counter);
+        }
+        Ok(())
+    }
+
+    /// Find the subdevices which are not in operational, and the power cycled ones
+    async fn identify<const MAX_SUBDEVICES: usize, const PDI_LEN: usize>(
+        &mut self,
+        group: &OpGroup<MAX_SUBDEVICES, PDI_LEN>,
+        main_device: &MainDevice<'_>,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        for index in 0..group.len() {
+            let subdevice = group.subdevice(main_device, index)?;
+            let restore = match subdevice.register_read::<u16>(AL_STATUS).await {
+                Ok(status) => {
+                    let state = status & AL_STATE_MASK;
+                    if state == AL_STATE_OP && status & AL_ERROR_FLAG == 0 {
+                        continue;
+                    }
+                    let code: u16 = subdevice.register_read(AL_STATUS_CODE).await.unwrap_or(0);
+                    warn!(target: &self.log_key, "Subdevice {} ({}) dropped out of operational, AL status: {:#06x}, AL status code: {:#06x}", index, subdevice.name(), status, code);
+                    if state != AL_STATE_INIT {
+                        self.pending.push(index);
+                        continue;
+                    }
+                    Restore::Identity
+                }
+                Err(e) => {
+                    warn!(target: &self.log_key, "Subdevice {} ({}) does not answer at its configured address: {}", index, subdevice.name(), e);
+                    Restore::Lost
+                }
+            };
+            if matches!(group, OpGroup::Dc(_)) {
+                return Err(format!(
+                    "Subdevice {} ({}) has been power cycled, subdevices of a group with distributed clocks need to be re-initialized",
+                    index,
+                    subdevice.name()
+                )
+                .into());
+            }
+            if self.snapshots.get(index).and_then(|s| s.as_ref()).is_none() {
+                return Err(format!(
+                    "Subdevice {} ({}) has been power cycled and its configuration is not known, it needs to be re-initialized",
+                    index,
+                    subdevice.name()
+                )
+                .into());
+            }
+            self.restoring.push((index, restore));
+        }
+        Ok(())
+    }
+
+    /// Move each power cycled subdevice one step closer to safe-op, from there on it is stepped
+    /// to operational with the dropped ones
+    async fn restore<const MAX_SUBDEVICES: usize, const PDI_LEN: usize>(
+        &mut self,
+        group: &OpGroup<MAX_SUBDEVICES, PDI_LEN>,
+        positions: &[usize],
+        main_device: &MainDevice<'_>,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let mut still_restoring = Vec::with_capacity(self.restoring.len());
+        for &(index, restore) in self.restoring.iter() {
+            if matches!(restore, Restore::Setup | Restore::SettingUp) {
+                // the runner has the subdevice while the driver is set up
+                still_restoring.push((index, restore));
+                continue;
+            }
+            let subdevice = group.subdevice(main_device, index)?;
+            let snapshot = self.snapshots[index]
+                .as_ref()
+                .ok_or_else(|| format!("No snapshot of subdevice {}", index))?;
+            let position = positions[index] as u16;
+            let next = match restore {
+                Restore::Lost => {
+                    match Command::aprd(position, STATION_ADDRESS)
+                        .receive::<u16>(main_device)
+                        .await
+                    {
+                        Ok(address) if address == subdevice.configured_address() => {
+                            Restore::Identity
+                        }
+                        // a powered up ESC starts without an address, any other address belongs to
+                        // a subdevice further down which moved up while this one is gone
+                        Ok(0) => Restore::Address,
+                        Ok(_) | Err(_) => Restore::Lost,
+                    }
+                }
+                Restore::Address => {
+                    // auto increment addressing, the position is negated by ethercrab
+                    Command::apwr(position, STATION_ADDRESS)
+                        .send(main_device, subdevice.configured_address())
+                        .await?;
+                    Restore::Identity
+                }
+                Restore::Identity => {
+                    let mut sii = Sii::new(main_device, subdevice.configured_address());
+                    let identity = subdevice.identity();
+                    for (word, expected, what) in [
+                        (SII_VENDOR_ID, identity.vendor_id, "vendor id"),
+                        (SII_PRODUCT_ID, identity.product_id, "product id"),
+                        (SII_REVISION, identity.revision, "revision"),
+                    ] {
+                        let found =
+                            sii.word(word).await? as u32 | (sii.word(word + 1).await? as u32) << 16;
+                        if found != expected {
+                            return Err(format!(
+                                "Subdevice {} ({}) has been replaced by one with {} {:#x} instead of {:#x}, it needs to be re-initialized",
+                                index,
+                                subdevice.name(),
+                                what,
+                                found,
+                                expected
+                            )
+                            .into());
+                        }
+                    }
+                    info!(target: &self.log_key, "Subdevice {} ({}) has been power cycled, restoring it", index, subdevice.name());
+                    snapshot.write(&subdevice, true).await?;
+                    subdevice
+                        .register_write(AL_CONTROL, AL_STATE_PRE_OP)
+                        .await?;
+                    Restore::PreOp
+                }
+                Restore::PreOp => {
+                    let status: u16 = subdevice.register_read(AL_STATUS).await?;
+                    Self::check_status(index, &subdevice, status).await?;
+                    match status & AL_STATE_MASK {
+                        AL_STATE_PRE_OP => Restore::Setup,
+                        _ => Restore::PreOp,
+                    }
+                }
+                Restore::ProcessData => {
+                    snapshot.write(&subdevice, false).await?;
+                    subdevice
+                        .register_write(AL_CONTROL, AL_STATE_SAFE_OP)
+                        .await?;
+                    Restore::SafeOp
+                }
+                Restore::SafeOp => {
+                    let status: u16 = subdevice.register_read(AL_STATUS).await?;
+                    Self::check_status(index, &subdevice, status).await?;
+                    if status & AL_STATE_MASK == AL_STATE_SAFE_OP {
+                        // stepped to operational like a dropped subdevice
+                        self.pending.push(index);
+                        continue;
+                    }
+                    Restore::SafeOp
+                }
+                Restore::Setup | Restore::SettingUp => restore,
+            };
+            still_restoring.push((index, next));
+        }
+        self.restoring = still_restoring;
+        Ok(())
+    }
+
+    /// Error when the subdevice refused the requested state
+    async fn check_status<S: Deref<Target = SubDevice>>(
+        index: usize,
+        subdevice: &SubDeviceRef<'_, S>,
+        status: u16,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        if status & AL_ERROR_FLAG == 0 {
+            return Ok(());
+        }
+        let code: u16 = subdevice.register_read(AL_STATUS_CODE).await.unwrap_or(0);
+        Err(format!(
+            "Power cycled subdevice {} ({}) refused the requested state, AL status: {:#06x}, AL status code: {:#06x}",
+            index,
+            subdevice.name(),
+            status,
+            code
+        )
+        .into())
+    }
+
+    /// Move each pending subdevice one state transition closer to operational
+    async fn step<const MAX_SUBDEVICES: usize, const PDI_LEN: usize>(
+        &mut self,
+        group: &OpGroup<MAX_SUBDEVICES, PDI_LEN>,
+        main_device: &MainDevice<'_>,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let mut still_pending = Vec::with_capacity(self.pending.len());
+        for &index in self.pending.iter() {
+            let subdevice = group.subdevice(main_device, index)?;
+            let status: u16 = subdevice.register_read(AL_STATUS).await?;
+            let state = status & AL_STATE_MASK;
+            if status & AL_ERROR_FLAG != 0 {
+                subdevice
+                    .register_write(AL_CONTROL, state | AL_ERROR_FLAG)
+                    .await?;
+                still_pending.push(index);
+                continue;
+            }
+            match state {
+                AL_STATE_OP => {
+                    info!(target: &self.log_key, "Subdevice {} ({}) back in operational", index, subdevice.name());
+                }
+                AL_STATE_SAFE_OP => {
+                    subdevice.register_write(AL_CONTROL, AL_STATE_OP).await?;
+                    still_pending.push(index);
+                }
+                AL_STATE_PRE_OP => {
+                    subdevice
+                        .register_write(AL_CONTROL, AL_STATE_SAFE_OP)
+                        .await?;
+                    still_pending.push(index);
+                }
+                _ => {
+                    return Err(format!(
+                        "Subdevice {} ({}) in state {:#04x} can not be recovered",
+                        index,
+                        subdevice.name(),
+                        state
+                    )
+                    .into());
+                }
+            }
+        }
+        self.pending = still_pending;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sim::{self, Segment, SimulatedSubDevice};
+    use ethercrab::std::ethercat_now;
+    use std::sync::{Arc, Mutex};
+
+    #[tokio::test]
+    async fn test_restore_power_cycled() {
+        let segment = Arc::new(Mutex::new(Segment::new(&[
+            SimulatedSubDevice::Ek1100,
+            SimulatedSubDevice::El1008,
+            SimulatedSubDevice::El2008,
+        ])));
+        let main_device = sim::main_device(&segment);
+        let group = main_device
+            .init_single_group::<16, 64>(ethercat_now)
+            .await
+            .expect("init")
+            .into_op(&main_device)
+            .await
+            .expect("op");
+        let group = OpGroup::NoDc(group);
+        let mut recovery = WkcRecovery::new("test");
+        recovery.snapshot(&group, &main_device).await;
+        let (expected, _) = group.tx_rx(&main_device).await.expect("tx/rx");
+
+        segment.lock().unwrap().power_cycle(2);
+        let mut setups = Vec::new();
+        for _ in 0..50 {
+            let (wkc, _) = group.tx_rx(&main_device).await.expect("tx/rx");
+            recovery
+                .cycle(
+                    1,
+                    Duration::from_secs(5),
+                    wkc,
+                    expected,
+                    &group,
+                    &[0, 1, 2],
+                    &main_device,
+                )
+                .await
+                .expect("recovery");
+            if let Some(index) = recovery.setup_due() {
+                setups.push(index);
+                recovery.setup_done(index, Ok(())).expect("setup");
+            }
+            if wkc == expected && !recovery.is_recovering() {
+                break;
+            }
+        }
+        assert_eq!(setups, vec![2]);
+        assert!(!recovery.is_recovering());
+
+        group
+            .subdevice(&main_device, 2)
+            .expect("el2008")
+            .outputs_raw_mut()[0] = 0x5A;
+        group.tx_rx(&main_device).await.expect("tx/rx");
+        assert_eq!(segment.lock().unwrap().outputs(2), &[0x5A]);
+    }
+}
@@ -0,0 +1,813 @@
+//! Dumps the subdevices of the segment as JSON: identity, SII strings and sync managers, the PDO
+//! layout and optionally the object dictionary. The object dictionary is listed and described with
+//! the CoE SDO information service, which ethercrab does not offer, so its requests go through
+//! the mailbox sync managers directly. The subdevices are left in pre-op
+
+use ethercrab::{std::ethercat_now, Command, MainDevice, SubDevice, SubDeviceRef};
+use serde::Serialize;
+use std::error::Error;
+use std::ops::{Deref, RangeInclusive};
+use std::time::Duration;
+
+use crate::topology::Identity;
+
+/// Largest segment that can be scanned, the group is never mapped so it needs no process data
+const MAX_SUBDEVICES: usize = 128;
+
+const REG_SII_CONTROL: u16 = 0x0502;
+const REG_SII_ADDRESS: u16 = 0x0504;
+const REG_SII_DATA: u16 = 0x0508;
+const SII_COMMAND_READ: u16 = 0x0100;
+const SII_BUSY: u16 = 0x8000;
+const SII_ERRORS: u16 = 0x7800;
+const SII_POLL_LIMIT: usize = 100;
+
+const SII_MAILBOX_PROTOCOLS: u16 = 0x1C;
+const SII_FIRST_CATEGORY: u16 = 0x40;
+/// Bounds the category walk on an EEPROM without end marker
+const SII_MAX_WORD: u16 = 0x2000;
+const MAILBOX_PROTOCOL_COE: u16 = 0x0004;
+
+const CATEGORY_STRINGS: u16 = 10;
+const CATEGORY_GENERAL: u16 = 30;
+const CATEGORY_SYNC_MANAGER: u16 = 41;
+const CATEGORY_TX_PDO: u16 = 50;
+const CATEGORY_RX_PDO: u16 = 51;
+const CATEGORY_END: u16 = 0xFFFF;
+
+const SM_TYPE_OUTPUTS: u8 = 3;
+const SM_TYPE_INPUTS: u8 = 4;
+const RX_PDO_ASSIGN: u16 = 0x1C12;
+const TX_PDO_ASSIGN: u16 = 0x1C13;
+
+const REG_SM: u16 = 0x0800;
+const SM_LEN: u16 = 8;
+const REG_SM1_STATUS: u16 = REG_SM + SM_LEN + 5;
+const SM_STATUS_MAILBOX_FULL: u8 = 0x08;
+const MAILBOX_HEADER_LEN: usize = 6;
+const MAILBOX_TYPE_COE: u8 = 3;
+const MAILBOX_POLL_LIMIT: usize = 1000;
+
+const COE_SERVICE_EMERGENCY: u16 = 1;
+const COE_SERVICE_SDO_REQUEST: u16 = 2;
+const COE_SERVICE_SDO_INFORMATION: u16 = 8;
+const SDO_UPLOAD_REQUEST: u8 = 0x40;
+const SDO_UPLOAD_RESPONSE: u8 = 0x40;
+const SDO_ABORT: u8 = 0x80;
+const SDO_EXPEDITED: u8 = 0x02;
+const SDO_SIZE_INDICATED: u8 = 0x01;
+/// CoE header plus the SDO header with index, subindex and 4 bytes of data
+const SDO_LEN: usize = 10;
+
+const INFO_GET_OD_LIST: u8 = 0x01;
+const INFO_GET_OBJECT_DESCRIPTION: u8 = 0x03;
+const INFO_GET_ENTRY_DESCRIPTION: u8 = 0x05;
+const INFO_ERROR: u8 = 0x07;
+const INFO_INCOMPLETE: u8 = 0x80;
+/// CoE header, opcode, reserved byte and fragments left
+const INFO_HEADER_LEN: usize = 6;
+const OD_LIST_ALL: u16 = 0x01;
+const OBJECT_CODE_VAR: u8 = 0x07;
+const ACCESS_READ_PRE_OP: u16 = 0x0001;
+
+const DATA_TYPE_BOOLEAN: u16 = 0x0001;
+const DATA_TYPE_INTEGER8: u16 = 0x0002;
+const DATA_TYPE_INTEGER16: u16 = 0x0003;
+const DATA_TYPE_INTEGER32: u16 = 0x0004;
+const DATA_TYPE_UNSIGNED8: u16 = 0x0005;
+const DATA_TYPE_UNSIGNED16: u16 = 0x0006;
+const DATA_TYPE_UNSIGNED32: u16 = 0x0007;
+const DATA_TYPE_REAL32: u16 = 0x0008;
+const DATA_TYPE_VISIBLE_STRING: u16 = 0x0009;
+const DATA_TYPE_REAL64: u16 = 0x0011;
+const DATA_TYPE_INTEGER64: u16 = 0x0015;
+const DATA_TYPE_UNSIGNED64: u16 = 0x001B;
+
+#[derive(Serialize, Debug)]
+pub struct ScannedSubDevice {
+    pub position: usize,
+    pub name: String,
+    pub identity: Identity,
+    pub alias: u16,
+    pub coe: bool,
+    /// SII strings, the name, group and order strings are referenced by index from general
+    pub strings: Vec<String>,
+    pub general: Option<General>,
+    pub sync_managers: Vec<SyncManager>,
+    /// Assigned output PDOs, read over CoE when supported otherwise from the SII
+    pub rx_pdos: Vec<Pdo>,
+    /// Assigned input PDOs, read over CoE when supported otherwise from the SII
+    pub tx_pdos: Vec<Pdo>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub objects: Vec<Object>,
+}
+
+/// String indices of the SII general category, 0 is no string
+#[derive(Serialize, Debug)]
+pub struct General {
+    pub group: u8,
+    pub image: u8,
+    pub order: u8,
+    pub name: u8,
+}
+
+#[derive(Serialize, Debug)]
+pub struct SyncManager {
+    pub start_address: u16,
+    pub length: u16,
+    pub control: u8,
+    pub enable: bool,
+    /// 1 mailbox out, 2 mailbox in, 3 outputs, 4 inputs
+    pub kind: u8,
+}
+
+#[derive(Serialize, Debug, PartialEq)]
+pub struct Pdo {
+    pub index: u16,
+    pub entries: Vec<PdoEntry>,
+}
+
+#[derive(Serialize, Debug, PartialEq)]
+pub struct PdoEntry {
+    pub index: u16,
+    pub subindex: u8,
+    pub bits: u8,
+}
+
+#[derive(Serialize, Debug)]
+pub struct Object {
+    pub index: u16,
+    pub name: String,
+    /// 7 variable, 8 array, 9 record
+    pub object_code: u8,
+    /// Subindex 0 of a variable, every described subindex of an array or record
+    pub entries: Vec<Entry>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct Entry {
+    pub subindex: u8,
+    pub name: String,
+    pub data_type: u16,
+    pub bits: u16,
+    pub access: u16,
+    /// None when the entry is not readable in pre-op or the upload failed
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub value: Option<Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// Value decoded by the data type of its entry, other types are left as bytes
+#[derive(Serialize, Debug, PartialEq)]
+#[serde(untagged)]
+pub enum Value {
+    Bool(bool),
+    Unsigned(u64),
+    Signed(i64),
+    Real(f64),
+    Text(String),
+    Bytes(Vec<u8>),
+}
+
+impl Value {
+    fn decode(data_type: u16, data: &[u8]) -> Self {
+        let mut bytes = [0; 8];
+        let len = data.len().min(8);
+        bytes[..len].copy_from_slice(&data[..len]);
+        let unsigned = u64::from_le_bytes(bytes);
+        match data_type {
+            DATA_TYPE_BOOLEAN => Value::Bool(unsigned != 0),
+            DATA_TYPE_UNSIGNED8 | DATA_TYPE_UNSIGNED16 | DATA_TYPE_UNSIGNED32
+            | DATA_TYPE_UNSIGNED64 => Value::Unsigned(unsigned),
+            DATA_TYPE_INTEGER8 | DATA_TYPE_INTEGER16 | DATA_TYPE_INTEGER32
+            | DATA_TYPE_INTEGER64
+                if len > 0 =>
+            {
+                // sign extended from the value's size
+                let shift = 64 - len as u32 * 8;
+                Value::Signed(((unsigned << shift) as i64) >> shift)
+            }
+            DATA_TYPE_REAL32 if len == 4 => Value::Real(f32::from_bits(unsigned as u32) as f64),
+            DATA_TYPE_REAL64 if len == 8 => Value::Real(f64::from_bits(unsigned)),
+            DATA_TYPE_VISIBLE_STRING => Value::Text(
+                String::from_utf8_lossy(data)
+                    .trim_end_matches('\0')
+                    .to_string(),
+            ),
+            _ => Value::Bytes(data.to_vec()),
+        }
+    }
+}
+
+/// Parses an object index range like `0x1000-0x1fff` or a single index
+pub fn parse_range(range: &str) -> Result<RangeInclusive<u16>, String> {
+    let index = |index: &str| {
+        let index = index.trim();
+        u16::from_str_radix(index.trim_start_matches("0x"), 16)
+            .map_err(|e| format!("Invalid object index {}: {}", index, e))
+    };
+    match range.split_once('-') {
+        Some((start, end)) => Ok(index(start)?..=index(end)?),
+        None => {
+            let index = index(range)?;
+            Ok(index..=index)
+        }
+    }
+}
+
+/// Objects are dumped when given, all of them when no range is given
+pub async fn scan(
+    main_device: &MainDevice<'_>,
+    objects: Option<&[RangeInclusive<u16>]>,
+) -> Result<Vec<ScannedSubDevice>, Box<dyn Error + Send + Sync>> {
+    let group = main_device
+        .init_single_group::<MAX_SUBDEVICES, 1>(ethercat_now)
+        .await?;
+    let mut scanned = Vec::with_capacity(group.len());
+    for (position, subdevice) in group.iter(main_device).enumerate() {
+        scanned.push(
+            scan_subdevice(main_device, position, &subdevice, objects)
+                .await
+                .map_err(|e| format!("Subdevice {}: {}", position, e))?,
+        );
+    }
+    Ok(scanned)
+}
+
+async fn scan_subdevice<S: Deref<Target = SubDevice>>(
+    main_device: &MainDevice<'_>,
+    position: usize,
+    subdevice: &SubDeviceRef<'_, S>,
+    objects: Option<&[RangeInclusive<u16>]>,
+) -> Result<ScannedSubDevice, Box<dyn Error + Send + Sync>> {
+    let mut sii = Sii::new(main_device, subdevice.configured_address());
+    let coe = sii.word(SII_MAILBOX_PROTOCOLS).await? & MAILBOX_PROTOCOL_COE != 0;
+    let mut scanned = ScannedSubDevice {
+        position,
+        name: subdevice.name().to_string(),
+        identity: Identity::from(subdevice.identity()),
+        alias: subdevice.alias_address(),
+        coe,
+        strings: Vec::new(),
+        general: None,
+        sync_managers: Vec::new(),
+        rx_pdos: Vec::new(),
+        tx_pdos: Vec::new(),
+        objects: Vec::new(),
+    };
+
+    let mut sii_pdos = Vec::new();
+    let mut address = SII_FIRST_CATEGORY;
+    while address < SII_MAX_WORD {
+        let category = sii.word(address).await?;
+        if category == CATEGORY_END {
+            break;
+        }
+        let len = sii.word(address + 1).await?;
+        let data = sii.bytes(address + 2, len).await?;
+        match category {
+            CATEGORY_STRINGS => scanned.strings = strings(&data),
+            CATEGORY_GENERAL if data.len() >= 4 => {
+                scanned.general = Some(General {
+                    group: data[0],
+                    image: data[1],
+                    order: data[2],
+                    name: data[3],
+                })
+            }
+            CATEGORY_SYNC_MANAGER => {
+                scanned.sync_managers = data
+                    .chunks_exact(8)
+                    .map(|sm| SyncManager {
+                        start_address: u16::from_le_bytes([sm[0], sm[1]]),
+                        length: u16::from_le_bytes([sm[2], sm[3]]),
+                        control: sm[4],
+                        enable: sm[6] & 0x01 != 0,
+                        kind: sm[7],
+                    })
+                    .collect()
+            }
+            CATEGORY_TX_PDO | CATEGORY_RX_PDO => sii_pdos.extend(pdos(&data)),
+            _ => {}
+        }
+        address += 2 + len;
+    }
+
+    if coe {
+        scanned.rx_pdos = assigned_pdos(subdevice, RX_PDO_ASSIGN).await?;
+        scanned.tx_pdos = assigned_pdos(subdevice, TX_PDO_ASSIGN).await?;
+    } else {
+        // PDOs of the SII are assigned when they name a sync manager
+        for (sync_manager, pdo) in sii_pdos {
+            match scanned
+                .sync_managers
+                .get(sync_manager as usize)
+                .map(|sm| sm.kind)
+            {
+                Some(SM_TYPE_OUTPUTS) => scanned.rx_pdos.push(pdo),
+                Some(SM_TYPE_INPUTS) => scanned.tx_pdos.push(pdo),
+                _ => {}
+            }
+        }
+    }
+
+    if let (true, Some(ranges)) = (coe, objects) {
+        let mut mailbox = Mailbox::new(main_device, subdevice).await?;
+        for index in mailbox.od_list().await? {
+            if ranges.is_empty() || ranges.iter().any(|range| range.contains(&index)) {
+                scanned.objects.push(mailbox.object(index).await?);
+            }
+        }
+    }
+    Ok(scanned)
+}
+
+fn strings(data: &[u8]) -> Vec<String> {
+    let mut strings = Vec::new();
+    let mut offset = 1;
+    for _ in 0..data.first().copied().unwrap_or(0) {
+        let Some(len) = data.get(offset).map(|len| *len as usize) else {
+            break;
+        };
+        let Some(string) = data.get(offset + 1..offset + 1 + len) else {
+            break;
+        };
+        strings.push(String::from_utf8_lossy(string).to_string());
+        offset += 1 + len;
+    }
+    strings
+}
+
+/// PDOs of a SII PDO category with the sync manager they are assigned to
+fn pdos(data: &[u8]) -> Vec<(u8, Pdo)> {
+    let mut pdos = Vec::new();
+    let mut offset = 0;
+    while let Some(header) = data.get(offset..offset + 8) {
+        let count = header[2] as usize;
+        let entries = data
+            .get(offset + 8..offset + 8 + count * 8)
+            .unwrap_or(&[])
+            .chunks_exact(8)
+            .map(|entry| PdoEntry {
+                index: u16::from_le_bytes([entry[0], entry[1]]),
+                subindex: entry[2],
+                bits: entry[5],
+            })
+            .collect();
+        pdos.push((
+            header[3],
+            Pdo {
+                index: u16::from_le_bytes([header[0], header[1]]),
+                entries,
+            },
+        ));
+        offset += 8 + count * 8;
+    }
+    pdos
+}
+
+async fn assigned_pdos<S: Deref<Target = SubDevice>>(
+    subdevice: &SubDeviceRef<'_, S>,
+    assign: u16,
+) -> Result<Vec<Pdo>, Box<dyn Error + Send + Sync>> {
+    let count: u8 = subdevice.sdo_read(assign, 0).await?;
+    let mut pdos = Vec::with_capacity(count as usize);
+    for subindex in 1..=count {
+        let index: u16 = subdevice.sdo_read(assign, subindex).await?;
+        let entry_count: u8 = subdevice.sdo_read(index, 0).await?;
+        let mut entries = Vec::with_capacity(entry_count as usize);
+        for subindex in 1..=entry_count {
+            let mapping: u32 = subdevice.sdo_read(index, subindex).await?;
+            entries.push(PdoEntry {
+                index: (mapping >> 16) as u16,
+                subindex: (mapping >> 8) as u8,
+                bits: mapping as u8,
+            });
+        }
+        pdos.push(Pdo { index, entries });
+    }
+    Ok(pdos)
+}
+
+/// CoE requests written to the mailbox sync managers and answered in the read mailbox. The
+/// counter is kept apart from ethercrab's, a request repeating its last counter is ignored by the
+/// subdevice, so the first exchange is retried once with the next counter
+struct Mailbox<'a, 'b, 'c, S: Deref<Target = SubDevice>> {
+    main_device: &'a MainDevice<'b>,
+    subdevice: &'a SubDeviceRef<'c, S>,
+    /// Start and length of the write and the read mailbox
+    request: (u16, u16),
+    response: (u16, u16),
+    counter: u8,
+    exchanged: bool,
+}
+
+impl<'a, 'b, 'c, S: Deref<Target = SubDevice>> Mailbox<'a, 'b, 'c, S> {
+    async fn new(
+        main_device: &'a MainDevice<'b>,
+        subdevice: &'a SubDeviceRef<'c, S>,
+    ) -> Result<Self, Box<dyn Error + Send + Sync>> {
+        let request: u32 = subdevice.register_read(REG_SM).await?;
+        let response: u32 = subdevice.register_read(REG_SM + SM_LEN).await?;
+        let mailbox = Self {
+            main_device,
+            subdevice,
+            request: (request as u16, (request >> 16) as u16),
+            response: (response as u16, (response >> 16) as u16),
+            counter: 0,
+            exchanged: false,
+        };
+        if (mailbox.request.1 as usize) < SDO_LEN + MAILBOX_HEADER_LEN
+            || (mailbox.response.1 as usize) < SDO_LEN + MAILBOX_HEADER_LEN
+        {
+            return Err("Mailbox sync managers are not configured".into());
+        }
+        // a response left in the read mailbox would answer the first request
+        let status: u8 = subdevice.register_read(REG_SM1_STATUS).await?;
+        if status & SM_STATUS_MAILBOX_FULL != 0 {
+            mailbox.read().await?;
+        }
+        Ok(mailbox)
+    }
+
+    async fn exchange(&mut self, coe: &[u8]) -> Result<Vec<u8>, Box<dyn Error + Send + Sync>> {
+        self.write(coe).await?;
+        let response = match self.response().await {
+            Err(_) if !self.exchanged => {
+                self.write(coe).await?;
+                self.response().await
+            }
+            response => response,
+        }?;
+        self.exchanged = true;
+        Ok(response)
+    }
+
+    async fn write(&mut self, coe: &[u8]) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let (start, len) = self.request;
+        if MAILBOX_HEADER_LEN + coe.len() > len as usize {
+            return Err("Request does not fit the mailbox".into());
+        }
+        self.counter = self.counter % 7 + 1;
+        let mut request = vec![0; len as usize];
+        request[0..2].copy_from_slice(&(coe.len() as u16).to_le_bytes());
+        request[5] = MAILBOX_TYPE_COE | self.counter << 4;
+        request[MAILBOX_HEADER_LEN..MAILBOX_HEADER_LEN + coe.len()].copy_from_slice(coe);
+        // the whole mailbox is written, the subdevice takes the request when its last byte is
+        Command::fpwr(self.subdevice.configured_address(), start)
+            .send(self.main_device, request.as_slice())
+            .await?;
+        Ok(())
+    }
+
+    /// Whole read mailbox, which empties it
+    async fn read(&self) -> Result<Vec<u8>, Box<dyn Error + Send + Sync>> {
+        let (start, len) = self.response;
+        let data = Command::fprd(self.subdevice.configured_address(), start)
+            .receive_slice(self.main_device, len)
+            .await?;
+        Ok(data.to_vec())
+    }
+
+    /// CoE part of the next CoE response, emergencies and other mailbox types are skipped
+    async fn response(&self) -> Result<Vec<u8>, Box<dyn Error + Send + Sync>> {
+        for _ in 0..MAILBOX_POLL_LIMIT {
+            let status: u8 = self.subdevice.register_read(REG_SM1_STATUS).await?;
+            if status & SM_STATUS_MAILBOX_FULL == 0 {
+                tokio::time::sleep(Duration::from_millis(1)).await;
+                continue;
+            }
+            let data = self.read().await?;
+            let len = u16::from_le_bytes([data[0], data[1]]) as usize;
+            let Some(coe) = data.get(MAILBOX_HEADER_LEN..MAILBOX_HEADER_LEN + len) else {
+                return Err(format!("Mailbox response length {} exceeds the mailbox", len).into());
+            };
+            if data[5] & 0x0F != MAILBOX_TYPE_COE
+                || coe.len() < 2
+                || u16::from_le_bytes([coe[0], coe[1]]) >> 12 == COE_SERVICE_EMERGENCY
+            {
+                continue;
+            }
+            return Ok(coe.to_vec());
+        }
+        Err("Mailbox response timed out".into())
+    }
+
+    /// Data of an SDO information response, its fragments joined
+    async fn info(
+        &mut self,
+        opcode: u8,
+        data: &[u8],
+    ) -> Result<Vec<u8>, Box<dyn Error + Send + Sync>> {
+        let mut request = (COE_SERVICE_SDO_INFORMATION << 12).to_le_bytes().to_vec();
+        request.extend_from_slice(&[opcode, 0, 0, 0]);
+        request.extend_from_slice(data);
+        let mut response = self.exchange(&request).await?;
+        let mut data = Vec::new();
+        loop {
+            if response.len() < INFO_HEADER_LEN
+                || u16::from_le_bytes([response[0], response[1]]) >> 12
+                    != COE_SERVICE_SDO_INFORMATION
+            {
+                return Err("Unexpected response to an SDO information request".into());
+            }
+            let fragment = &response[INFO_HEADER_LEN..];
+            match response[2] & !INFO_INCOMPLETE {
+                INFO_ERROR => {
+                    let code = fragment.get(0..4).map_or(0, |code| {
+                        u32::from_le_bytes([code[0], code[1], code[2], code[3]])
+                    });
+                    return Err(format!("SDO information request aborted: {:#010x}", code).into());
+                }
+                reply if reply == opcode + 1 => data.extend_from_slice(fragment),
+                reply => {
+                    return Err(
+                        format!("Unexpected SDO information response {:#04x}", reply).into(),
+                    )
+                }
+            }
+            if response[2] & INFO_INCOMPLETE == 0 {
+                return Ok(data);
+            }
+            response = self.response().await?;
+        }
+    }
+
+    async fn od_list(&mut self) -> Result<Vec<u16>, Box<dyn Error + Send + Sync>> {
+        let data = self
+            .info(INFO_GET_OD_LIST, &OD_LIST_ALL.to_le_bytes())
+            .await?;
+        // the list type comes first
+        Ok(data
+            .get(2..)
+            .unwrap_or(&[])
+            .chunks_exact(2)
+            .map(|index| u16::from_le_bytes([index[0], index[1]]))
+            .collect())
+    }
+
+    /// Described entries of the object, each read at its described size
+    async fn object(&mut self, index: u16) -> Result<Object, Box<dyn Error + Send + Sync>> {
+        let data = self
+            .info(INFO_GET_OBJECT_DESCRIPTION, &index.to_le_bytes())
+            .await
+            .map_err(|e| format!("Object {:#06x}: {}", index, e))?;
+        if data.len() < 6 {
+            return Err(format!("Object {:#06x}: description too short", index).into());
+        }
+        let max_subindex = data[4];
+        let object_code = data[5];
+        let mut object = Object {
+            index,
+            name: String::from_utf8_lossy(&data[6..]).to_string(),
+            object_code,
+            entries: Vec::new(),
+        };
+        let subindices = if object_code == OBJECT_CODE_VAR {
+            0..=0
+        } else {
+            0..=max_subindex
+        };
+        for subindex in subindices {
+            let mut request = index.to_le_bytes().to_vec();
+            // no value information, only the description
+            request.extend_from_slice(&[subindex, 0]);
+            // subindices of a record may be left out
+            let Ok(data) = self.info(INFO_GET_ENTRY_DESCRIPTION, &request).await else {
+                continue;
+            };
+            let Some(description) = data.get(4..10) else {
+                continue;
+            };
+            let word =
+                |offset: usize| u16::from_le_bytes([description[offset], description[offset + 1]]);
+            let mut entry = Entry {
+                subindex,
+                name: String::from_utf8_lossy(&data[10..]).to_string(),
+                data_type: word(0),
+                bits: word(2),
+                access: word(4),
+                value: None,
+                error: None,
+            };
+            if entry.access & ACCESS_READ_PRE_OP != 0 {
+                match self.upload(index, subindex).await {
+                    Ok(value) => entry.value = Some(Value::decode(entry.data_type, &value)),
+                    Err(e) => entry.error = Some(e.to_string()),
+                }
+            }
+            object.entries.push(entry);
+        }
+        Ok(object)
+    }
+
+    /// Expedited or normal upload, segmented transfers are not supported
+    async fn upload(
+        &mut self,
+        index: u16,
+        subindex: u8,
+    ) -> Result<Vec<u8>, Box<dyn Error + Send + Sync>> {
+        let mut request = (COE_SERVICE_SDO_REQUEST << 12).to_le_bytes().to_vec();
+        request.push(SDO_UPLOAD_REQUEST);
+        request.extend_from_slice(&index.to_le_bytes());
+        request.extend_from_slice(&[subindex, 0, 0, 0, 0]);
+        let response = self.exchange(&request).await?;
+        if response.len() < SDO_LEN {
+            return Err("SDO response too short".into());
+        }
+        let command = response[2];
+        let data = u32::from_le_bytes([response[6], response[7], response[8], response[9]]);
+        if command == SDO_ABORT {
+            return Err(format!("SDO upload aborted: {:#010x}", data).into());
+        }
+        if command & 0xE0 != SDO_UPLOAD_RESPONSE {
+            return Err(format!("Unexpected SDO response {:#04x}", command).into());
+        }
+        if command & SDO_EXPEDITED != 0 {
+            let size = if command & SDO_SIZE_INDICATED != 0 {
+                4 - ((command >> 2) & 0x03) as usize
+            } else {
+                4
+            };
+            return Ok(response[6..6 + size].to_vec());
+        }
+        response
+            .get(SDO_LEN..SDO_LEN + data as usize)
+            .map(|value| value.to_vec())
+            .ok_or_else(|| "Segmented SDO uploads are not supported".into())
+    }
+}
+
+/// SII EEPROM read through the ESC registers, two words at a time. The subdevice is addressed
+/// by its configured address, so it can be read without holding its group
+pub(crate) struct Sii<'a, 'b> {
+    main_device: &'a MainDevice<'b>,
+    address: u16,
+    /// Start word and the two words read from it
+    cache: Option<(u16, [u16; 2])>,
+}
+
+impl<'a, 'b> Sii<'a, 'b> {
+    pub(crate) fn new(main_device: &'a MainDevice<'b>, address: u16) -> Self {
+        Self {
+            main_device,
+            address,
+            cache: None,
+        }
+    }
+
+    pub(crate) async fn word(&mut self, address: u16) -> Result<u16, Box<dyn Error + Send + Sync>> {
+        let start = address & !1;
+        let words = match self.cache {
+            Some((cached, words)) if cached == start => words,
+            _ => {
+                let words = self.read(start).await?;
+                self.cache = Some((start, words));
+                words
+            }
+        };
+        Ok(words[(address - start) as usize])
+    }
+
+    async fn bytes(
+        &mut self,
+        address: u16,
+        words: u16,
+    ) -> Result<Vec<u8>, Box<dyn Error + Send + Sync>> {
+        let mut bytes = Vec::with_capacity(words as usize * 2);
+        for word in address..address + words {
+            bytes.extend_from_slice(&self.word(word).await?.to_le_bytes());
+        }
+        Ok(bytes)
+    }
+
+    async fn read(&self, address: u16) -> Result<[u16; 2], Box<dyn Error + Send + Sync>> {
+        Command::fpwr(self.address, REG_SII_ADDRESS)
+            .send(self.main_device, address as u32)
+            .await?;
+        Command::fpwr(self.address, REG_SII_CONTROL)
+            .send(self.main_device, SII_COMMAND_READ)
+            .await?;
+        for _ in 0..SII_POLL_LIMIT {
+            let control = Command::fprd(self.address, REG_SII_CONTROL)
+                .receive::<u16>(self.main_device)
+                .await?;
+            if control & SII_ERRORS != 0 {
+                return Err(
+                    format!("SII read of word {:#06x} failed: {:#06x}", address, control).into(),
+                );
+            }
+            if control & SII_BUSY == 0 {
+                let data = Command::fprd(self.address, REG_SII_DATA)
+                    .receive::<u32>(self.main_device)
+                    .await?;
+                return Ok([data as u16, (data >> 16) as u16]);
+            }
+            tokio::time::sleep(Duration::from_millis(1)).await;
+        }
+        Err(format!("SII read of word {:#06x} timed out", address).into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sim::{self, Segment, SimulatedSubDevice};
+    use std::sync::{Arc, Mutex};
+
+    #[test]
+    fn test_parse_range() {
+        assert_eq!(parse_range("0x1000-0x1fff").unwrap(), 0x1000..=0x1FFF);
+        assert_eq!(parse_range(" 0x8000 - 0x8FFF").unwrap(), 0x8000..=0x8FFF);
+        assert_eq!(parse_range("1c12").unwrap(), 0x1C12..=0x1C12);
+        assert!(parse_range("0x1000-x").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_scan() {
+        let segment = Arc::new(Mutex::new(Segment::new(&[
+            SimulatedSubDevice::Ek1100,
+            SimulatedSubDevice::El2008,
+            SimulatedSubDevice::I550,
+        ])));
+        let main_device = sim::main_device(&segment);
+        let scanned = scan(&main_device, Some(&[0x1018..=0x1018]))
+            .await
+            .expect("scan");
+        assert_eq!(
+            scanned.iter().map(|s| s.name.as_str()).collect::<Vec<_>>(),
+            vec!["Ek1100", "el2008", "i550"]
+        );
+        assert_eq!(scanned[1].strings, vec!["el2008".to_string()]);
+        assert_eq!(scanned[1].rx_pdos.len(), 8);
+        assert!(!scanned[1].coe);
+
+        let i550 = &scanned[2];
+        assert!(i550.coe);
+        assert_eq!(i550.sync_managers.len(), 4);
+        assert_eq!(i550.rx_pdos[0].index, 0x1605);
+        assert_eq!(
+            i550.rx_pdos[0].entries[0],
+            PdoEntry {
+                index: 0x6040,
+                subindex: 0,
+                bits: 16
+            }
+        );
+        assert_eq!(i550.tx_pdos[0].entries.len(), 7);
+        // identity object with its 4 subindices
+        assert_eq!(i550.objects.len(), 1);
+        let identity = &i550.objects[0];
+        assert_eq!(identity.index, 0x1018);
+        assert_eq!(identity.entries.len(), 5);
+        assert_eq!(identity.entries[0].value, Some(Value::Unsigned(4)));
+        assert_eq!(identity.entries[4].bits, 32);
+        assert_eq!(identity.entries[4].value, Some(Value::Unsigned(3)));
+    }
+
+    #[tokio::test]
+    async fn test_scan_object_dictionary() {
+        let segment = Arc::new(Mutex::new(Segment::new(&[SimulatedSubDevice::I550])));
+        let main_device = sim::main_device(&segment);
+        let scanned = scan(&main_device, Some(&[])).await.expect("scan");
+        let objects = &scanned[0].objects;
+        assert_eq!(
+            objects.iter().map(|o| o.index).collect::<Vec<_>>(),
+            vec![0x1000, 0x1008, 0x1018, 0x1C00, 0x1C10, 0x1C11, 0x1C12, 0x1C13, 0x1605, 0x1A05]
+        );
+        assert!(objects
+            .iter()
+            .all(|o| o.entries.iter().all(|e| e.error.is_none())));
+        // longer than an expedited transfer
+        assert_eq!(
+            objects[1].entries[0].value,
+            Some(Value::Text("i550".to_string()))
+        );
+        // only subindex 0 of a variable is described
+        assert_eq!(objects[0].object_code, OBJECT_CODE_VAR);
+        assert_eq!(objects[0].entries.len(), 1);
+        // a record with every subindex
+        assert_eq!(objects[9].entries.len(), 8);
+        assert_eq!(
+            objects[9].entries[7].value,
+            Some(Value::Unsigned(0x2DA4_0110))
+        );
+    }
+
+    #[test]
+    fn test_decode() {
+        assert_eq!(
+            Value::decode(DATA_TYPE_INTEGER16, &[0xFE, 0xFF]),
+            Value::Signed(-2)
+        );
+        assert_eq!(
+            Value::decode(DATA_TYPE_UNSIGNED8, &[200]),
+            Value::Unsigned(200)
+        );
+        assert_eq!(
+            Value::decode(DATA_TYPE_VISIBLE_STRING, b"EL1008\0"),
+            Value::Text("EL1008".to_string())
+        );
+    }
+}
@@ -0,0 +1,539 @@
+//! SDO access to running subdevices for commissioning. Requests are queued per segment position
+//! and executed by the group runner between cycles, every write is logged.
+
+#[cfg(feature = "opcua-expose")]
+use crate::opcua::OpcuaServerHandle;
+use ethercrab::{SubDevice, SubDeviceRef};
+use log::{debug, info, warn};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::ops::Deref;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+use tfc::ipc::{Base, Signal, Slot};
+use tokio::sync::{mpsc, oneshot, Mutex};
+use tokio::time::Instant;
+use zbus::interface;
+
+/// The interface of a subdevice is at `<DBUS_PATH>/<position>`
+pub static DBUS_PATH: &str = "/is/centroid/ethercat/sdo";
+
+/// Requests waiting for a subdevice, more are refused
+const QUEUE_LEN: usize = 16;
+/// A request is answered with an error when its subdevice is not in a running group within this time
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+/// Time a taken request has to complete, longer than the mailbox timeouts so the caller does not give
+/// up on a write in flight
+const EXECUTE_TIMEOUT: Duration = Duration::from_secs(5);
+
+macro_rules! sdo_types {
+    ($($variant:ident($type:ty) = $name:literal),* $(,)?) => {
+        /// Data type of an object, given by the caller as it is not read from the object dictionary
+        #[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+        pub enum SdoType {
+            $(#[serde(rename = $name)] $variant,)*
+        }
+
+        impl FromStr for SdoType {
+            type Err = String;
+            fn from_str(name: &str) -> Result<Self, Self::Err> {
+                match name {
+                    $($name => Ok(Self::$variant),)*
+                    _ => Err(format!(
+                        "Unknown type {}, expected one of: {}",
+                        name,
+                        [$($name),*].join(", ")
+                    )),
+                }
+            }
+        }
+
+        #[derive(Debug, Clone, Copy, PartialEq)]
+        pub enum SdoValue {
+            $($variant($type),)*
+        }
+
+        impl SdoValue {
+            /// Fails when the value does not fit the type
+            pub fn parse(kind: SdoType, value: &str) -> Result<Self, String> {
+                match kind {
+                    $(SdoType::$variant => value
+                        .trim()
+                        .parse::<$type>()
+                        .map(Self::$variant)
+                        .map_err(|e| format!("Invalid {} value {}: {}", $name, value, e)),)*
+                }
+            }
+            async fn read<S: Deref<Target = SubDevice>>(
+                kind: SdoType,
+                subdevice: &SubDeviceRef<'_, S>,
+                index: u16,
+                subindex: u8,
+            ) -> Result<Self, ethercrab::error::Error> {
+                match kind {
+                    $(SdoType::$variant => subdevice
+                        .sdo_read::<$type>(index, subindex)
+                        .await
+                        .map(Self::$variant),)*
+                }
+            }
+            async fn write<S: Deref<Target = SubDevice>>(
+                self,
+                subdevice: &SubDeviceRef<'_, S>,
+                index: u16,
+                subindex: u8,
+            ) -> Result<(), ethercrab::error::Error> {
+                match self {
+                    $(Self::$variant(value) => subdevice.sdo_write(index, subindex, value).await,)*
+                }
+            }
+        }
+
+        impl fmt::Display for SdoValue {
+            fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                match self {
+                    $(Self::$variant(value) => write!(f, "{}", value),)*
+                }
+            }
+        }
+    };
+}
+
+sdo_types!(
+    Bool(bool) = "bool",
+    U8(u8) = "u8",
+    U16(u16) = "u16",
+    U32(u32) = "u32",
+    U64(u64) = "u64",
+    I8(i8) = "i8",
+    I16(i16) = "i16",
+    I32(i32) = "i32",
+    I64(i64) = "i64",
+    F32(f32) = "f32",
+    F64(f64) = "f64",
+);
+
+/// Read or write of one object, the reply is the value read or written
+pub struct SdoRequest {
+    index: u16,
+    subindex: u8,
+    kind: SdoType,
+    write: Option<SdoValue>,
+    /// Dropped instead of executed when not taken from the queue by then, the caller has given up
+    deadline: Instant,
+    reply: oneshot::Sender<Result<String, String>>,
+}
+
+impl SdoRequest {
+    pub async fn execute<S: Deref<Target = SubDevice>>(
+        self,
+        subdevice: &SubDeviceRef<'_, S>,
+        position: usize,
+        log_key: &str,
+    ) {
+        let result = match self.write {
+            Some(value) => {
+                let result = value.write(subdevice, self.index, self.subindex).await;
+                match &result {
+                    Ok(()) => {
+                        info!(target: log_key, "SDO write subdevice {} {:#06x}:{:#04x} {:?} = {}", position, self.index, self.subindex, self.kind, value)
+                    }
+                    Err(e) => {
+                        warn!(target: log_key, "SDO write subdevice {} {:#06x}:{:#04x} {:?} = {} failed: {}", position, self.index, self.subindex, self.kind, value, e)
+                    }
+                }
+                result.map(|()| value.to_string())
+            }
+            None => {
+                debug!(target: log_key, "SDO read subdevice {} {:#06x}:{:#04x} {:?}", position, self.index, self.subindex, self.kind);
+                SdoValue::read(self.kind, subdevice, self.index, self.subindex)
+                    .await
+                    .map(|value| value.to_string())
+            }
+        };
+        let _ = self.reply.send(result.map_err(|e| e.to_string()));
+    }
+}
+
+/// Queues requests for one subdevice and waits for the reply
+#[derive(Clone)]
+struct SdoClient {
+    position: usize,
+    requests: mpsc::Sender<SdoRequest>,
+}
+
+impl SdoClient {
+    async fn request(
+        &self,
+        index: u16,
+        subindex: u8,
+        kind: &str,
+        value: Option<&str>,
+    ) -> Result<String, String> {
+        let kind = SdoType::from_str(kind)?;
+        let write = value
+            .map(|value| SdoValue::parse(kind, value))
+            .transpose()?;
+        let (reply, response) = oneshot::channel();
+        let deadline = Instant::now() + REQUEST_TIMEOUT;
+        self.requests
+            .try_send(SdoRequest {
+                index,
+                subindex,
+                kind,
+                write,
+                deadline,
+                reply,
+            })
+            .map_err(|e| {
+                format!(
+                    "Subdevice {} can not take the request: {}",
+                    self.position, e
+                )
+            })?;
+        match tokio::time::timeout_at(deadline + EXECUTE_TIMEOUT, response).await {
+            Ok(Ok(result)) => result,
+            Ok(Err(_)) => Err(format!("Subdevice {} dropped the request", self.position)),
+            Err(_) => Err(format!(
+                "Subdevice {} is not running, request timed out after {:?}",
+                self.position, REQUEST_TIMEOUT
+            )),
+        }
+    }
+}
+
+pub struct SdoDbusInterface {
+    client: SdoClient,
+}
+#[interface(name = "is.centroid.ethercat.Sdo")]
+impl SdoDbusInterface {
+    /// Type is one of bool, u8, u16, u32, u64, i8, i16, i32, i64, f32 and f64
+    async fn read(
+        &self,
+        index: u16,
+        subindex: u8,
+        data_type: &str,
+    ) -> Result<String, zbus::fdo::Error> {
+        self.client
+            .request(index, subindex, data_type, None)
+            .await
+            .map_err(zbus::fdo::Error::Failed)
+    }
+    async fn write(
+        &self,
+        index: u16,
+        subindex: u8,
+        data_type: &str,
+        value: &str,
+    ) -> Result<(), zbus::fdo::Error> {
+        self.client
+            .request(index, subindex, data_type, Some(value))
+            .await
+            .map(|_| ())
+            .map_err(zbus::fdo::Error::Failed)
+    }
+}
+
+/// Request written to the `sdo/<position>/request` slot, a read when value is missing
+#[derive(Serialize, Deserialize)]
+struct JsonRequest {
+    /// Chosen by the caller and returned with the reply
+    #[serde(default, skip_serializing)]
+    id: Option<serde_json::Value>,
+    index: u16,
+    subindex: u8,
+    #[serde(rename = "type")]
+    kind: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    value: Option<String>,
+}
+
+/// Reply on the `sdo/<position>/response` signal, which only holds the last one, so concurrent
+/// callers tell theirs apart by the id
+#[derive(Serialize)]
+struct JsonResponse {
+    id: Option<serde_json::Value>,
+    /// None when the request could not be parsed
+    request: Option<JsonRequest>,
+    value: Option<String>,
+    error: Option<String>,
+}
+
+impl JsonResponse {
+    /// Invalid requests are answered with the error, and with their id when it can be found
+    fn invalid(request: &str, error: String) -> Self {
+        let id = serde_json::from_str::<serde_json::Value>(request)
+            .ok()
+            .and_then(|request| request.get("id").cloned());
+        Self {
+            id,
+            request: None,
+            value: None,
+            error: Some(error),
+        }
+    }
+}
+
+/// Request queue of the subdevice at a position. It is kept by the position across driver changes
+/// and lent to the group runner with the driver.
+pub struct SdoAccess {
+    requests: mpsc::Receiver<SdoRequest>,
+    // held so the request slot keeps receiving, otherwise only read to register with OPC UA
+    #[cfg_attr(not(feature = "opcua-expose"), allow(dead_code))]
+    request_slot: Slot<String>,
+    #[cfg_attr(not(feature = "opcua-expose"), allow(dead_code))]
+    response: Arc<Mutex<Signal<String>>>,
+}
+
+impl SdoAccess {
+    pub fn new(dbus: zbus::Connection, position: usize) -> Self {
+        let (tx, requests) = mpsc::channel(QUEUE_LEN);
+        let client = SdoClient {
+            position,
+            requests: tx,
+        };
+        #[cfg(feature = "dbus-expose")]
+        {
+            let path = format!("{}/{}", DBUS_PATH, position);
+            let interface = SdoDbusInterface {
+                client: client.clone(),
+            };
+            let dbus = dbus.clone();
+            tokio::spawn(async move {
+                match dbus.object_server().at(path.as_str(), interface).await {
+                    Ok(true) => {}
+                    Ok(false) => {
+                        warn!(target: "ethercat", "SDO interface already registered at {}", path)
+                    }
+                    Err(e) => {
+                        log::error!(target: "ethercat", "Error registering object {}: {}", path, e)
+                    }
+                }
+            });
+        }
+
+        // the same requests as JSON, for OPC UA clients
+        let mut request_slot = Slot::new(
+            dbus.clone(),
+            Base::new(
+                format!("sdo/{}/request", position).as_str(),
+                Some("JSON SDO request, index, subindex, type and the value to write, without value the object is read. An optional id is returned with the reply"),
+            ),
+        );
+        let response = Arc::new(Mutex::new(Signal::new(
+            dbus.clone(),
+            Base::new(
+                format!("sdo/{}/response", position).as_str(),
+                Some("JSON reply to the last SDO request, its id and the request with the value or error"),
+            ),
+        )));
+        let response_cp = response.clone();
+        request_slot.recv(Box::new(move |request: &String| {
+            let client = client.clone();
+            let response = response_cp.clone();
+            let request = request.clone();
+            tokio::spawn(async move {
+                let reply = match serde_json::from_str::<JsonRequest>(&request) {
+                    Ok(mut request) => {
+                        let result = client
+                            .request(
+                                request.index,
+                                request.subindex,
+                                &request.kind,
+                                request.value.as_deref(),
+                            )
+                            .await;
+                        let (value, error) = match result {
+                            Ok(value) => (Some(value), None),
+                            Err(e) => (None, Some(e)),
+                        };
+                        JsonResponse {
+                            id: request.id.take(),
+                            request: Some(request),
+                            value,
+                            error,
+                        }
+                    }
+                    Err(e) => {
+                        warn!(target: "ethercat", "Invalid SDO request for subdevice {}: {}", client.position, e);
+                        JsonResponse::invalid(&request, format!("Invalid request: {}", e))
+                    }
+                };
+                if let Ok(reply) = serde_json::to_string(&reply) {
+                    let _ = response.lock().await.async_send(reply).await;
+                }
+            });
+        }));
+        Self {
+            requests,
+            request_slot,
+            response,
+        }
+    }
+    #[cfg(feature = "opcua-expose")]
+    pub async fn opcua_register(&self, handle: &OpcuaServerHandle) {
+        tfc::ipc::opcua::SlotInterface::new(
+            self.request_slot.base(),
+            self.request_slot.channel("opcua"),
+            handle.manager.clone(),
+            handle.subscriptions.clone(),
+            handle.namespace,
+        )
+        .register();
+        let response = self.response.lock().await;
+        tfc::ipc::opcua::SignalInterface::new(
+            response.base(),
+            response.subscribe(),
+            handle.manager.clone(),
+            handle.subscriptions.clone(),
+            handle.namespace,
+        )
+        .register();
+    }
+    /// Next queued request, without waiting
+    pub fn try_next(&mut self) -> Option<SdoRequest> {
+        next_request(&mut self.requests, Instant::now())
+    }
+}
+
+/// Requests nobody waits for anymore are dropped unexecuted, so a write reported as failed never
+/// lands later
+fn next_request(requests: &mut mpsc::Receiver<SdoRequest>, now: Instant) -> Option<SdoRequest> {
+    while let Ok(request) = requests.try_recv() {
+        if request.reply.is_closed() || request.deadline <= now {
+            warn!(target: "ethercat", "Dropped SDO request {:#06x}:{:#04x}, its caller gave up", request.index, request.subindex);
+            continue;
+        }
+        return Some(request);
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sim::{self, Segment, SimulatedSubDevice};
+    use ethercrab::std::ethercat_now;
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(SdoType::from_str("u16"), Ok(SdoType::U16));
+        assert!(SdoType::from_str("u24").is_err());
+        assert_eq!(SdoValue::parse(SdoType::I16, " -3 "), Ok(SdoValue::I16(-3)));
+        assert!(SdoValue::parse(SdoType::U8, "256").is_err());
+        assert!(SdoValue::parse(SdoType::Bool, "1").is_err());
+        assert_eq!(SdoValue::F32(1.5).to_string(), "1.5");
+    }
+
+    #[test]
+    fn test_json_reply() {
+        let request: JsonRequest =
+            serde_json::from_str(r#"{"id": 7, "index": 8192, "subindex": 1, "type": "u16"}"#)
+                .unwrap();
+        assert_eq!(request.id, Some(serde_json::json!(7)));
+        assert_eq!(
+            serde_json::to_value(&request).unwrap(),
+            serde_json::json!({"index": 8192, "subindex": 1, "type": "u16"})
+        );
+
+        let invalid = r#"{"id": "commissioning-1", "index": 8192, "type": "u16"}"#;
+        let reply = serde_json::to_value(JsonResponse::invalid(
+            invalid,
+            "missing field subindex".to_string(),
+        ))
+        .unwrap();
+        assert_eq!(reply["id"], "commissioning-1");
+        assert_eq!(reply["error"], "missing field subindex");
+        assert!(reply["request"].is_null());
+    }
+
+    #[tokio::test]
+    async fn test_execute() {
+        let segment = Arc::new(std::sync::Mutex::new(Segment::new(&[
+            SimulatedSubDevice::El3356,
+        ])));
+        let main_device = sim::main_device(&segment);
+        let group = main_device
+            .init_single_group::<16, 64>(ethercat_now)
+            .await
+            .expect("init");
+        let subdevice = group.subdevice(&main_device, 0).expect("subdevice");
+
+        let (reply, response) = oneshot::channel();
+        SdoRequest {
+            index: 0xFB00,
+            subindex: 0x03,
+            kind: SdoType::U32,
+            write: Some(SdoValue::U32(0x1234)),
+            deadline: Instant::now() + REQUEST_TIMEOUT,
+            reply,
+        }
+        .execute(&subdevice, 0, "test")
+        .await;
+        assert_eq!(response.await.unwrap(), Ok("4660".to_string()));
+        assert_eq!(
+            segment.lock().unwrap().object(0, 0xFB00, 0x03),
+            Some(&0x1234u32.to_le_bytes()[..])
+        );
+
+        let (reply, response) = oneshot::channel();
+        SdoRequest {
+            index: 0x1018,
+            subindex: 0x04,
+            kind: SdoType::U32,
+            write: None,
+            deadline: Instant::now() + REQUEST_TIMEOUT,
+            reply,
+        }
+        .execute(&subdevice, 0, "test")
+        .await;
+        assert_eq!(response.await.unwrap(), Ok("1".to_string()));
+    }
+
+    #[tokio::test]
+    async fn test_timed_out_write_not_executed() {
+        let segment = Arc::new(std::sync::Mutex::new(Segment::new(&[
+            SimulatedSubDevice::El3356,
+        ])));
+        let main_device = sim::main_device(&segment);
+        let group = main_device
+            .init_single_group::<16, 64>(ethercat_now)
+            .await
+            .expect("init");
+        let subdevice = group.subdevice(&main_device, 0).expect("subdevice");
+
+        let (tx, mut requests) = mpsc::channel(QUEUE_LEN);
+        let now = Instant::now();
+        let write = |value, deadline| {
+            let (reply, response) = oneshot::channel();
+            let request = SdoRequest {
+                index: 0xFB00,
+                subindex: 0x03,
+                kind: SdoType::U32,
+                write: Some(SdoValue::U32(value)),
+                deadline,
+                reply,
+            };
+            (request, response)
+        };
+        // the caller timed out, and a caller which was dropped
+        let (expired, expired_response) = write(1, now);
+        tx.try_send(expired).unwrap();
+        let (abandoned, abandoned_response) = write(2, now + REQUEST_TIMEOUT);
+        drop(abandoned_response);
+        tx.try_send(abandoned).unwrap();
+        let (waiting, waiting_response) = write(3, now + REQUEST_TIMEOUT);
+        tx.try_send(waiting).unwrap();
+
+        while let Some(request) = next_request(&mut requests, now) {
+            request.execute(&subdevice, 0, "test").await;
+        }
+        assert!(expired_response.await.is_err());
+        assert_eq!(waiting_response.await.unwrap(), Ok("3".to_string()));
+        assert_eq!(
+            segment.lock().unwrap().object(0, 0xFB00, 0x03),
+            Some(&3u32.to_le_bytes()[..])
+        );
+    }
+}
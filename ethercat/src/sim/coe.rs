@@ -0,0 +1,316 @@
+//! CoE object dictionary answering SDO and SDO information requests, segmented transfers are
+//! not supported
+
+use std::collections::BTreeMap;
+
+const COE_SERVICE_SDO_REQUEST: u16 = 2;
+const COE_SERVICE_SDO_RESPONSE: u16 = 3;
+const COE_SERVICE_SDO_INFORMATION: u16 = 8;
+
+const SDO_DOWNLOAD: u8 = 1;
+const SDO_UPLOAD: u8 = 2;
+const SDO_ABORT: u8 = 0x80;
+const SDO_SIZE_INDICATED: u8 = 0x01;
+const SDO_EXPEDITED: u8 = 0x02;
+const SDO_COMPLETE_ACCESS: u8 = 0x10;
+
+const ABORT_INVALID_COMMAND: u32 = 0x0504_0001;
+const ABORT_OUT_OF_MEMORY: u32 = 0x0504_0005;
+const ABORT_UNSUPPORTED_ACCESS: u32 = 0x0601_0000;
+const ABORT_OBJECT_NOT_FOUND: u32 = 0x0602_0000;
+const ABORT_LENGTH_MISMATCH: u32 = 0x0607_0010;
+const ABORT_SUBINDEX_NOT_FOUND: u32 = 0x0609_0011;
+const ABORT_VALUE_RANGE: u32 = 0x0609_0030;
+
+/// CoE header plus the SDO header with index, subindex and 4 bytes of data
+const SDO_LEN: usize = 10;
+
+const INFO_GET_OD_LIST: u8 = 0x01;
+const INFO_GET_OBJECT_DESCRIPTION: u8 = 0x03;
+const INFO_GET_ENTRY_DESCRIPTION: u8 = 0x05;
+const INFO_ERROR: u8 = 0x07;
+/// Set in the opcode of every fragment but the last
+const INFO_INCOMPLETE: u8 = 0x80;
+/// CoE header, opcode, reserved byte and fragments left
+const INFO_HEADER_LEN: usize = 6;
+const OD_LIST_ALL: u16 = 0x01;
+
+const DATA_TYPE_UNSIGNED8: u16 = 0x0005;
+const DATA_TYPE_UNSIGNED16: u16 = 0x0006;
+const DATA_TYPE_UNSIGNED32: u16 = 0x0007;
+const DATA_TYPE_VISIBLE_STRING: u16 = 0x0009;
+const DATA_TYPE_OCTET_STRING: u16 = 0x000A;
+const DATA_TYPE_UNSIGNED64: u16 = 0x001B;
+const OBJECT_CODE_VAR: u8 = 0x07;
+const OBJECT_CODE_RECORD: u8 = 0x09;
+/// Readable and writable in pre-op, safe-op and op
+const ACCESS_READ_WRITE: u16 = 0x003F;
+
+struct Entry {
+    data_type: u16,
+    value: Vec<u8>,
+}
+
+/// Unsigned of the value's size, other sizes are octet strings
+fn data_type(value: &[u8]) -> u16 {
+    match value.len() {
+        1 => DATA_TYPE_UNSIGNED8,
+        2 => DATA_TYPE_UNSIGNED16,
+        4 => DATA_TYPE_UNSIGNED32,
+        8 => DATA_TYPE_UNSIGNED64,
+        _ => DATA_TYPE_OCTET_STRING,
+    }
+}
+
+/// Objects by index and subindex. Downloads are refused like a terminal would: the entry must
+/// exist with the size written, and the assign objects only take the PDOs of their direction.
+#[derive(Default)]
+pub struct ObjectDictionary {
+    objects: BTreeMap<(u16, u8), Entry>,
+    /// PDOs which can be assigned, by assign object
+    assignable: BTreeMap<u16, Vec<u16>>,
+}
+
+fn header(service: u16, command: u8, index: u16, subindex: u8) -> Vec<u8> {
+    let mut response = (service << 12).to_le_bytes().to_vec();
+    response.push(command);
+    response.extend_from_slice(&index.to_le_bytes());
+    response.push(subindex);
+    response
+}
+
+fn abort(index: u16, subindex: u8, code: u32) -> Vec<u8> {
+    let mut response = header(COE_SERVICE_SDO_REQUEST, SDO_ABORT, index, subindex);
+    response.extend_from_slice(&code.to_le_bytes());
+    response
+}
+
+fn info(opcode: u8, fragments_left: u16, data: &[u8]) -> Vec<u8> {
+    let mut response = (COE_SERVICE_SDO_INFORMATION << 12).to_le_bytes().to_vec();
+    response.extend_from_slice(&[opcode, 0]);
+    response.extend_from_slice(&fragments_left.to_le_bytes());
+    response.extend_from_slice(data);
+    response
+}
+
+impl ObjectDictionary {
+    /// Typed as an unsigned of the value's size, a write keeps the type of an existing entry
+    pub fn insert(&mut self, index: u16, subindex: u8, value: Vec<u8>) {
+        let data_type = self
+            .objects
+            .get(&(index, subindex))
+            .map_or_else(|| data_type(&value), |entry| entry.data_type);
+        self.objects
+            .insert((index, subindex), Entry { data_type, value });
+    }
+    /// Assign object with the assigned PDOs first, the spare ones can be assigned by a download
+    pub fn insert_assign(&mut self, index: u16, assigned: &[u16], spare: &[u16]) {
+        self.insert(index, 0x00, vec![assigned.len() as u8]);
+        let pdos: Vec<u16> = assigned.iter().chain(spare).copied().collect();
+        for subindex in 0..pdos.len() {
+            let pdo = assigned.get(subindex).copied().unwrap_or(0);
+            self.insert(index, subindex as u8 + 1, pdo.to_le_bytes().to_vec());
+        }
+        self.assignable.insert(index, pdos);
+    }
+    pub fn insert_string(&mut self, index: u16, subindex: u8, value: &str) {
+        self.objects.insert(
+            (index, subindex),
+            Entry {
+                data_type: DATA_TYPE_VISIBLE_STRING,
+                value: value.as_bytes().to_vec(),
+            },
+        );
+    }
+    #[cfg(test)]
+    pub fn get(&self, index: u16, subindex: u8) -> Option<&Vec<u8>> {
+        self.objects
+            .get(&(index, subindex))
+            .map(|entry| &entry.value)
+    }
+
+    fn not_found(&self, index: u16) -> u32 {
+        if self.objects.keys().any(|(other, _)| *other == index) {
+            ABORT_SUBINDEX_NOT_FOUND
+        } else {
+            ABORT_OBJECT_NOT_FOUND
+        }
+    }
+
+    /// Abort code of a download the subdevice refuses
+    fn check_download(&self, index: u16, subindex: u8, value: &[u8]) -> Result<(), u32> {
+        let entry = self
+            .objects
+            .get(&(index, subindex))
+            .ok_or_else(|| self.not_found(index))?;
+        if entry.value.len() != value.len() {
+            return Err(ABORT_LENGTH_MISMATCH);
+        }
+        if let Some(pdos) = self.assignable.get(&index) {
+            let valid = match subindex {
+                0 => value[0] as usize <= pdos.len(),
+                _ => pdos.contains(&u16::from_le_bytes([value[0], value[1]])),
+            };
+            if !valid {
+                return Err(ABORT_VALUE_RANGE);
+            }
+        }
+        Ok(())
+    }
+
+    /// Answers the CoE part of a mailbox with one or more responses, capacity is the room in the
+    /// response mailbox. SDO information responses which do not fit are sent in fragments
+    pub fn coe(&mut self, request: &[u8], capacity: usize) -> Vec<Vec<u8>> {
+        let service = request
+            .get(0..2)
+            .map_or(0, |header| u16::from_le_bytes([header[0], header[1]]) >> 12);
+        if service != COE_SERVICE_SDO_INFORMATION {
+            return vec![self.sdo(request, capacity)];
+        }
+        let (opcode, data) = match self.info(request) {
+            Ok(response) => response,
+            Err(code) => (INFO_ERROR, code.to_le_bytes().to_vec()),
+        };
+        let chunks: Vec<&[u8]> = data.chunks(capacity - INFO_HEADER_LEN).collect();
+        let count = chunks.len();
+        chunks
+            .into_iter()
+            .enumerate()
+            .map(|(fragment, chunk)| {
+                let left = (count - fragment - 1) as u16;
+                let opcode = if left > 0 {
+                    opcode | INFO_INCOMPLETE
+                } else {
+                    opcode
+                };
+                info(opcode, left, chunk)
+            })
+            .collect()
+    }
+
+    /// Response opcode and data of an SDO information request, or its abort code
+    fn info(&self, request: &[u8]) -> Result<(u8, Vec<u8>), u32> {
+        let u16_at = |offset: usize| {
+            request
+                .get(offset..offset + 2)
+                .map(|bytes| u16::from_le_bytes([bytes[0], bytes[1]]))
+                .ok_or(ABORT_INVALID_COMMAND)
+        };
+        let opcode = *request.get(2).ok_or(ABORT_INVALID_COMMAND)?;
+        match opcode {
+            INFO_GET_OD_LIST => {
+                if u16_at(INFO_HEADER_LEN)? != OD_LIST_ALL {
+                    return Err(ABORT_UNSUPPORTED_ACCESS);
+                }
+                let mut data = OD_LIST_ALL.to_le_bytes().to_vec();
+                let mut last = None;
+                for (index, _) in self.objects.keys() {
+                    if last != Some(*index) {
+                        data.extend_from_slice(&index.to_le_bytes());
+                        last = Some(*index);
+                    }
+                }
+                Ok((opcode + 1, data))
+            }
+            INFO_GET_OBJECT_DESCRIPTION => {
+                let index = u16_at(INFO_HEADER_LEN)?;
+                let mut entries = self.objects.range((index, 0)..=(index, u8::MAX));
+                let (_, first) = entries.next().ok_or(ABORT_OBJECT_NOT_FOUND)?;
+                let max_subindex = entries.last().map_or(0, |((_, subindex), _)| *subindex);
+                let mut data = index.to_le_bytes().to_vec();
+                data.extend_from_slice(&first.data_type.to_le_bytes());
+                data.push(max_subindex);
+                data.push(if max_subindex == 0 {
+                    OBJECT_CODE_VAR
+                } else {
+                    OBJECT_CODE_RECORD
+                });
+                data.extend_from_slice(format!("Object {:04X}", index).as_bytes());
+                Ok((opcode + 1, data))
+            }
+            INFO_GET_ENTRY_DESCRIPTION => {
+                let index = u16_at(INFO_HEADER_LEN)?;
+                let subindex = *request
+                    .get(INFO_HEADER_LEN + 2)
+                    .ok_or(ABORT_INVALID_COMMAND)?;
+                let entry = self
+                    .objects
+                    .get(&(index, subindex))
+                    .ok_or(ABORT_SUBINDEX_NOT_FOUND)?;
+                let mut data = index.to_le_bytes().to_vec();
+                // no value information, only the description
+                data.extend_from_slice(&[subindex, 0]);
+                data.extend_from_slice(&entry.data_type.to_le_bytes());
+                data.extend_from_slice(&(entry.value.len() as u16 * 8).to_le_bytes());
+                data.extend_from_slice(&ACCESS_READ_WRITE.to_le_bytes());
+                data.extend_from_slice(format!("SubIndex {:03}", subindex).as_bytes());
+                Ok((opcode + 1, data))
+            }
+            _ => Err(ABORT_INVALID_COMMAND),
+        }
+    }
+
+    fn sdo(&mut self, request: &[u8], capacity: usize) -> Vec<u8> {
+        if request.len() < SDO_LEN {
+            return abort(0, 0, ABORT_INVALID_COMMAND);
+        }
+        let command = request[2];
+        let index = u16::from_le_bytes([request[3], request[4]]);
+        let subindex = request[5];
+        if command & SDO_COMPLETE_ACCESS != 0 {
+            return abort(index, subindex, ABORT_UNSUPPORTED_ACCESS);
+        }
+        match command >> 5 {
+            SDO_DOWNLOAD => {
+                let value = if command & SDO_EXPEDITED != 0 {
+                    let size = if command & SDO_SIZE_INDICATED != 0 {
+                        4 - ((command >> 2) & 0x03) as usize
+                    } else {
+                        4
+                    };
+                    request[6..6 + size].to_vec()
+                } else {
+                    let size = u32::from_le_bytes([request[6], request[7], request[8], request[9]])
+                        as usize;
+                    match request.get(SDO_LEN..SDO_LEN + size) {
+                        Some(value) => value.to_vec(),
+                        None => return abort(index, subindex, ABORT_OUT_OF_MEMORY),
+                    }
+                };
+                if let Err(code) = self.check_download(index, subindex, &value) {
+                    return abort(index, subindex, code);
+                }
+                self.insert(index, subindex, value);
+                let mut response = header(COE_SERVICE_SDO_RESPONSE, 0x60, index, subindex);
+                response.extend_from_slice(&[0; 4]);
+                response
+            }
+            SDO_UPLOAD => {
+                let Some(Entry { value, .. }) = self.objects.get(&(index, subindex)) else {
+                    return abort(index, subindex, self.not_found(index));
+                };
+                if value.len() <= 4 {
+                    let command =
+                        0x40 | SDO_EXPEDITED | SDO_SIZE_INDICATED | ((4 - value.len() as u8) << 2);
+                    let mut response = header(COE_SERVICE_SDO_RESPONSE, command, index, subindex);
+                    response.extend_from_slice(value);
+                    response.resize(SDO_LEN, 0);
+                    response
+                } else if SDO_LEN + value.len() <= capacity {
+                    let mut response = header(
+                        COE_SERVICE_SDO_RESPONSE,
+                        0x40 | SDO_SIZE_INDICATED,
+                        index,
+                        subindex,
+                    );
+                    response.extend_from_slice(&(value.len() as u32).to_le_bytes());
+                    response.extend_from_slice(value);
+                    response
+                } else {
+                    abort(index, subindex, ABORT_OUT_OF_MEMORY)
+                }
+            }
+            _ => abort(index, subindex, ABORT_INVALID_COMMAND),
+        }
+    }
+}
@@ -0,0 +1,896 @@
+//! In-process EtherCAT segment answering the frames of the main device, so the bus and the
+//! drivers run without hardware. Registers, SII EEPROM, mailboxes and FMMUs are emulated as far
+//! as ethercrab uses them, process data is mapped with byte granularity.
+
+mod coe;
+mod profiles;
+mod sii;
+
+use ethercrab::{PduRx, PduTx};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::future::poll_fn;
+use std::sync::{Arc, Mutex};
+use std::task::Poll;
+use std::time::Instant;
+
+use coe::ObjectDictionary;
+use sii::{
+    Identity, Mailbox, Pdo, SyncManager, SM_INPUTS, SM_MAILBOX_IN, SM_MAILBOX_OUT, SM_OUTPUTS,
+};
+
+/// Subdevices the simulation can emulate
+#[derive(Deserialize, Serialize, JsonSchema, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SimulatedSubDevice {
+    Ek1100,
+    El1002,
+    El1008,
+    El1809,
+    El2004,
+    El2008,
+    El2794,
+    El2809,
+    El3356,
+    El5101,
+    El5151,
+    I550,
+}
+
+const ETHERNET_HEADER_LEN: usize = 14;
+const ETHERCAT_HEADER_LEN: usize = 2;
+/// Command, index, address, length and interrupt
+const PDU_HEADER_LEN: usize = 10;
+const PDU_MORE_FOLLOWS: u16 = 0x8000;
+const PDU_LEN_MASK: u16 = 0x07FF;
+
+const NOP: u8 = 0;
+const APRD: u8 = 1;
+const APWR: u8 = 2;
+const APRW: u8 = 3;
+const FPRD: u8 = 4;
+const FPWR: u8 = 5;
+const FPRW: u8 = 6;
+const BRD: u8 = 7;
+const BWR: u8 = 8;
+const BRW: u8 = 9;
+const LRD: u8 = 10;
+const LWR: u8 = 11;
+const LRW: u8 = 12;
+const ARMW: u8 = 13;
+const FRMW: u8 = 14;
+
+/// Registers below 0x1000, process RAM above
+const MEMORY_LEN: usize = 0x2000;
+
+const REG_TYPE: usize = 0x0000;
+const REG_FMMU_COUNT: usize = 0x0004;
+const REG_SM_COUNT: usize = 0x0005;
+const REG_RAM_SIZE: usize = 0x0006;
+const REG_PORT_DESCRIPTOR: usize = 0x0007;
+const REG_SUPPORT_FLAGS: usize = 0x0008;
+const REG_STATION_ADDRESS: usize = 0x0010;
+const REG_DL_STATUS: usize = 0x0110;
+const REG_AL_CONTROL: usize = 0x0120;
+const REG_AL_STATUS: usize = 0x0130;
+const REG_AL_STATUS_CODE: usize = 0x0134;
+const REG_SII_CONTROL: usize = 0x0502;
+const REG_SII_ADDRESS: usize = 0x0504;
+const REG_SII_DATA: usize = 0x0508;
+const REG_FMMU: usize = 0x0600;
+const REG_SM: usize = 0x0800;
+const REG_DC_RECEIVE_TIME: usize = 0x0900;
+const REG_DC_SYSTEM_TIME: usize = 0x0910;
+const REG_DC_RECEIVE_TIME_PU: usize = 0x0918;
+const REG_DC_SYSTEM_TIME_OFFSET: usize = 0x0920;
+
+const FMMU_COUNT: usize = 8;
+const FMMU_LEN: usize = 16;
+const SM_COUNT: usize = 8;
+const SM_LEN: usize = 8;
+const SM_STATUS_MAILBOX_FULL: u8 = 0x08;
+
+/// Both EBUS ports are implemented
+const PORT_DESCRIPTOR: u8 = 0x0A;
+/// Distributed clocks with 64 bit system time
+const SUPPORT_FLAGS: u16 = 0x000C;
+/// SII reads return 8 bytes
+const SII_CONTROL_IDLE: u16 = 0x0040;
+const SII_COMMAND_READ: u16 = 0x0100;
+const AL_STATE_INIT: u8 = 0x01;
+
+/// Forwarding delay of a subdevice, used for the receive times latched for distributed clocks
+const FORWARD_DELAY_NS: u64 = 100;
+
+const MAILBOX_HEADER_LEN: usize = 6;
+const MAILBOX_TYPE_COE: u8 = 0x03;
+const MAILBOX_OUT_ADDRESS: u16 = 0x1000;
+const MAILBOX_IN_ADDRESS: u16 = 0x1080;
+const MAILBOX_LEN: u16 = 128;
+const OUTPUTS_ADDRESS: u16 = 0x1100;
+const INPUTS_ADDRESS: u16 = 0x1180;
+
+fn bytes(pdos: &[Pdo]) -> u16 {
+    pdos.iter().map(|pdo| pdo.bits()).sum::<usize>().div_ceil(8) as u16
+}
+
+fn u16_at(memory: &[u8], address: usize) -> u16 {
+    u16::from_le_bytes([memory[address], memory[address + 1]])
+}
+
+fn u32_at(memory: &[u8], address: usize) -> u32 {
+    u32::from_le_bytes([
+        memory[address],
+        memory[address + 1],
+        memory[address + 2],
+        memory[address + 3],
+    ])
+}
+
+fn u64_at(memory: &[u8], address: usize) -> u64 {
+    let mut value = [0; 8];
+    value.copy_from_slice(&memory[address..address + 8]);
+    u64::from_le_bytes(value)
+}
+
+fn overlaps(address: usize, len: usize, start: usize, end: usize) -> bool {
+    address < end && start < address + len
+}
+
+/// Registers written by the subdevice only
+fn read_only(address: usize) -> bool {
+    address < REG_STATION_ADDRESS
+        || (REG_DL_STATUS..REG_DL_STATUS + 2).contains(&address)
+        || (REG_AL_STATUS..REG_AL_STATUS_CODE + 2).contains(&address)
+        || (REG_DC_RECEIVE_TIME..REG_DC_SYSTEM_TIME_OFFSET).contains(&address)
+        || ((REG_SM..REG_SM + SM_COUNT * SM_LEN).contains(&address)
+            && (address - REG_SM) % SM_LEN == 5)
+}
+
+/// EtherCAT slave controller of one subdevice
+struct Esc {
+    /// Kept to reset the subdevice on a power cycle
+    #[cfg(test)]
+    kind: SimulatedSubDevice,
+    #[cfg(test)]
+    serial: u32,
+    memory: Vec<u8>,
+    eeprom: Vec<u16>,
+    od: ObjectDictionary,
+    /// Responses waiting for the read mailbox to be emptied, fragments of an SDO information
+    /// response
+    responses: VecDeque<Vec<u8>>,
+    /// Sync manager index of the process data by direction
+    outputs_sm: Option<usize>,
+    inputs_sm: Option<usize>,
+    connected: bool,
+    /// Connected subdevices after this one in the line
+    downstream: usize,
+}
+
+impl Esc {
+    fn new(kind: SimulatedSubDevice, serial: u32) -> Self {
+        let description = profiles::description(kind);
+        let mailbox = description.coe.then_some(Mailbox {
+            rx_address: MAILBOX_OUT_ADDRESS,
+            rx_size: MAILBOX_LEN,
+            tx_address: MAILBOX_IN_ADDRESS,
+            tx_size: MAILBOX_LEN,
+        });
+
+        let mut sync_managers = Vec::new();
+        if let Some(mailbox) = mailbox.as_ref() {
+            sync_managers.push(SyncManager {
+                address: mailbox.rx_address,
+                length: mailbox.rx_size,
+                control: 0x26,
+                enable: true,
+                kind: SM_MAILBOX_OUT,
+            });
+            sync_managers.push(SyncManager {
+                address: mailbox.tx_address,
+                length: mailbox.tx_size,
+                control: 0x22,
+                enable: true,
+                kind: SM_MAILBOX_IN,
+            });
+        }
+        let mut outputs_sm = None;
+        if !description.rx_pdos.is_empty() {
+            outputs_sm = Some(sync_managers.len());
+            sync_managers.push(SyncManager {
+                address: OUTPUTS_ADDRESS,
+                length: bytes(&description.rx_pdos),
+                control: 0x64,
+                enable: true,
+                kind: SM_OUTPUTS,
+            });
+        }
+        let mut inputs_sm = None;
+        // input PDOs which are only assigned by the driver still need their sync manager
+        if !description.tx_pdos.is_empty()
+            || description.spare_pdos.iter().any(|pdo| pdo.index >= 0x1A00)
+        {
+            inputs_sm = Some(sync_managers.len());
+            sync_managers.push(SyncManager {
+                address: INPUTS_ADDRESS,
+                length: bytes(&description.tx_pdos),
+                control: 0x20,
+                enable: true,
+                kind: SM_INPUTS,
+            });
+        }
+
+        let identity = Identity {
+            vendor_id: description.vendor_id,
+            product_id: description.product_id,
+            revision: description.revision,
+            serial,
+        };
+        let eeprom = sii::image(
+            &identity,
+            description.name,
+            mailbox.as_ref(),
+            &sync_managers,
+            &description.rx_pdos,
+            &description.tx_pdos,
+        );
+
+        let mut od = ObjectDictionary::default();
+        if description.coe {
+            od.insert(0x1000, 0x00, vec![0; 4]);
+            od.insert_string(0x1008, 0x00, description.name);
+            od.insert(0x1018, 0x00, vec![4]);
+            od.insert(0x1018, 0x01, identity.vendor_id.to_le_bytes().to_vec());
+            od.insert(0x1018, 0x02, identity.product_id.to_le_bytes().to_vec());
+            od.insert(0x1018, 0x03, identity.revision.to_le_bytes().to_vec());
+            od.insert(0x1018, 0x04, identity.serial.to_le_bytes().to_vec());
+            od.insert(0x1C00, 0x00, vec![sync_managers.len() as u8]);
+            for (index, sync_manager) in sync_managers.iter().enumerate() {
+                od.insert(0x1C00, index as u8 + 1, vec![sync_manager.kind]);
+                // spare PDOs go with the sync manager of their direction, told by the index
+                let (assigned, inputs): (&[Pdo], Option<bool>) = match sync_manager.kind {
+                    SM_OUTPUTS => (&description.rx_pdos, Some(false)),
+                    SM_INPUTS => (&description.tx_pdos, Some(true)),
+                    _ => (&[], None),
+                };
+                let assigned: Vec<u16> = assigned.iter().map(|pdo| pdo.index).collect();
+                let spare: Vec<u16> = description
+                    .spare_pdos
+                    .iter()
+                    .filter(|pdo| Some(pdo.index >= 0x1A00) == inputs)
+                    .map(|pdo| pdo.index)
+                    .collect();
+                od.insert_assign(0x1C10 + index as u16, &assigned, &spare);
+            }
+            for pdo in description
+                .rx_pdos
+                .iter()
+                .chain(description.tx_pdos.iter())
+                .chain(description.spare_pdos.iter())
+            {
+                od.insert(pdo.index, 0x00, vec![pdo.entries.len() as u8]);
+                for (subindex, entry) in pdo.entries.iter().enumerate() {
+                    od.insert(
+                        pdo.index,
+                        subindex as u8 + 1,
+                        Pdo::mapping(entry).to_le_bytes().to_vec(),
+                    );
+                }
+            }
+            for (index, subindex, value) in description.objects {
+                od.insert(index, subindex, value);
+            }
+        }
+
+        let mut memory = vec![0; MEMORY_LEN];
+        memory[REG_TYPE] = 0x11;
+        memory[REG_FMMU_COUNT] = FMMU_COUNT as u8;
+        memory[REG_SM_COUNT] = SM_COUNT as u8;
+        memory[REG_RAM_SIZE] = ((MEMORY_LEN - 0x1000) / 1024) as u8;
+        memory[REG_PORT_DESCRIPTOR] = PORT_DESCRIPTOR;
+        memory[REG_SUPPORT_FLAGS..REG_SUPPORT_FLAGS + 2]
+            .copy_from_slice(&SUPPORT_FLAGS.to_le_bytes());
+        memory[REG_AL_STATUS] = AL_STATE_INIT;
+        memory[REG_SII_CONTROL..REG_SII_CONTROL + 2]
+            .copy_from_slice(&SII_CONTROL_IDLE.to_le_bytes());
+
+        Self {
+            #[cfg(test)]
+            kind,
+            #[cfg(test)]
+            serial,
+            memory,
+            eeprom,
+            od,
+            responses: VecDeque::new(),
+            outputs_sm,
+            inputs_sm,
+            connected: true,
+            downstream: 0,
+        }
+    }
+
+    fn station_address(&self) -> u16 {
+        u16_at(&self.memory, REG_STATION_ADDRESS)
+    }
+
+    /// Start and length of a sync manager as configured by the main device, None when disabled
+    fn sync_manager(&self, index: usize) -> Option<(usize, usize)> {
+        let base = REG_SM + index * SM_LEN;
+        if self.memory[base + 6] & 0x01 == 0 {
+            return None;
+        }
+        Some((
+            u16_at(&self.memory, base) as usize,
+            u16_at(&self.memory, base + 2) as usize,
+        ))
+    }
+
+    fn read(&mut self, address: usize, len: usize, start: Instant) -> Vec<u8> {
+        if overlaps(address, len, REG_DC_SYSTEM_TIME, REG_DC_SYSTEM_TIME + 8) {
+            let system_time = (start.elapsed().as_nanos() as u64)
+                .wrapping_add(u64_at(&self.memory, REG_DC_SYSTEM_TIME_OFFSET));
+            self.memory[REG_DC_SYSTEM_TIME..REG_DC_SYSTEM_TIME + 8]
+                .copy_from_slice(&system_time.to_le_bytes());
+        }
+        let data = (address..address + len)
+            .map(|address| self.memory.get(address).copied().unwrap_or(0))
+            .collect();
+        // reading the last byte of the response mailbox empties it
+        if let Some((mailbox, length)) = self.sync_manager(1) {
+            if length > 0 && overlaps(address, len, mailbox + length - 1, mailbox + length) {
+                self.memory[REG_SM + SM_LEN + 5] &= !SM_STATUS_MAILBOX_FULL;
+                if let Some(response) = self.responses.pop_front() {
+                    self.respond(response);
+                }
+            }
+        }
+        data
+    }
+
+    fn write(&mut self, address: usize, data: &[u8], start: Instant) {
+        for (offset, value) in data.iter().enumerate() {
+            let address = address + offset;
+            if address < MEMORY_LEN && !read_only(address) {
+                self.memory[address] = *value;
+            }
+        }
+        let len = data.len();
+        if overlaps(address, len, REG_AL_CONTROL, REG_AL_CONTROL + 1) {
+            // every requested state is accepted, an error acknowledge clears the status code
+            self.memory[REG_AL_STATUS] = self.memory[REG_AL_CONTROL] & 0x0F;
+            self.memory[REG_AL_STATUS_CODE..REG_AL_STATUS_CODE + 2].fill(0);
+        }
+        if overlaps(address, len, REG_SII_CONTROL, REG_SII_CONTROL + 2) {
+            if u16_at(&self.memory, REG_SII_CONTROL) & SII_COMMAND_READ != 0 {
+                let word = u32_at(&self.memory, REG_SII_ADDRESS) as usize;
+                for i in 0..4 {
+                    let value = self.eeprom.get(word + i).copied().unwrap_or(0xFFFF);
+                    self.memory[REG_SII_DATA + i * 2..REG_SII_DATA + i * 2 + 2]
+                        .copy_from_slice(&value.to_le_bytes());
+                }
+            }
+            // commands complete immediately, writing the EEPROM is not supported
+            self.memory[REG_SII_CONTROL..REG_SII_CONTROL + 2]
+                .copy_from_slice(&SII_CONTROL_IDLE.to_le_bytes());
+        }
+        if overlaps(address, len, REG_DC_RECEIVE_TIME, REG_DC_RECEIVE_TIME + 1) {
+            self.latch(start);
+        }
+        if let Some((mailbox, length)) = self.sync_manager(0) {
+            if length > 0 && overlaps(address, len, mailbox, mailbox + length) {
+                self.mailbox(mailbox, length);
+            }
+        }
+    }
+
+    /// Receive times of the frame which wrote the port 0 receive time
+    fn latch(&mut self, start: Instant) {
+        let now = start.elapsed().as_nanos() as u64;
+        let returned = match self.downstream {
+            0 => 0,
+            downstream => now + 2 * downstream as u64 * FORWARD_DELAY_NS,
+        };
+        self.memory[REG_DC_RECEIVE_TIME..REG_DC_RECEIVE_TIME + 4]
+            .copy_from_slice(&(now as u32).to_le_bytes());
+        self.memory[REG_DC_RECEIVE_TIME + 4..REG_DC_RECEIVE_TIME + 8]
+            .copy_from_slice(&(returned as u32).to_le_bytes());
+        self.memory[REG_DC_RECEIVE_TIME_PU..REG_DC_RECEIVE_TIME_PU + 8]
+            .copy_from_slice(&now.to_le_bytes());
+    }
+
+    /// Answers the request in the write mailbox in the read mailbox, only CoE is supported
+    fn mailbox(&mut self, request_address: usize, request_len: usize) {
+        let Some((_, response_len)) = self.sync_manager(1) else {
+            return;
+        };
+        let request = self.memory[request_address..request_address + request_len].to_vec();
+        let length = u16_at(&request, 0) as usize;
+        let kind = request[5] & 0x0F;
+        let counter = request[5] & 0x70;
+        if kind != MAILBOX_TYPE_COE || MAILBOX_HEADER_LEN + length > request.len() {
+            return;
+        }
+        let coe = self.od.coe(
+            &request[MAILBOX_HEADER_LEN..MAILBOX_HEADER_LEN + length],
+            response_len - MAILBOX_HEADER_LEN,
+        );
+        self.responses = coe
+            .into_iter()
+            .map(|coe| {
+                let mut response = (coe.len() as u16).to_le_bytes().to_vec();
+                response.extend_from_slice(&[0, 0, 0, MAILBOX_TYPE_COE | counter]);
+                response.extend_from_slice(&coe);
+                response.resize(response_len, 0);
+                response
+            })
+            .collect();
+        if let Some(response) = self.responses.pop_front() {
+            self.respond(response);
+        }
+    }
+
+    fn respond(&mut self, response: Vec<u8>) {
+        let Some((address, length)) = self.sync_manager(1) else {
+            return;
+        };
+        self.memory[address..address + length].copy_from_slice(&response[..length]);
+        self.memory[REG_SM + SM_LEN + 5] |= SM_STATUS_MAILBOX_FULL;
+    }
+
+    /// Logical access through the FMMUs, returns the working counter increment
+    fn logical(&mut self, command: u8, address: u32, data: &mut [u8]) -> u16 {
+        let mut read = false;
+        let mut written = false;
+        for fmmu in 0..FMMU_COUNT {
+            let base = REG_FMMU + fmmu * FMMU_LEN;
+            if self.memory[base + 12] & 0x01 == 0 {
+                continue;
+            }
+            let logical = u32_at(&self.memory, base) as u64;
+            let length = u16_at(&self.memory, base + 4) as u64;
+            let physical = u16_at(&self.memory, base + 8) as u64;
+            let kind = self.memory[base + 11];
+            let start = logical.max(address as u64);
+            let end = (logical + length).min(address as u64 + data.len() as u64);
+            if start >= end {
+                continue;
+            }
+            let frame = (start - address as u64) as usize..(end - address as u64) as usize;
+            let memory = (physical + start - logical) as usize..(physical + end - logical) as usize;
+            if memory.end > MEMORY_LEN {
+                continue;
+            }
+            match kind {
+                1 if command != LWR => {
+                    data[frame].copy_from_slice(&self.memory[memory]);
+                    read = true;
+                }
+                2 if command != LRD => {
+                    self.memory[memory].copy_from_slice(&data[frame]);
+                    written = true;
+                }
+                _ => {}
+            }
+        }
+        match command {
+            LRW => read as u16 + 2 * written as u16,
+            _ => (read || written) as u16,
+        }
+    }
+}
+
+/// Line of emulated subdevices, the first one is connected to the main device
+pub struct Segment {
+    subdevices: Vec<Esc>,
+    start: Instant,
+    /// Logical reads and writes, one per process data exchange of a group
+    #[cfg(test)]
+    exchanges: usize,
+}
+
+impl Segment {
+    pub fn new(subdevices: &[SimulatedSubDevice]) -> Self {
+        Self {
+            subdevices: subdevices
+                .iter()
+                .enumerate()
+                .map(|(position, kind)| Esc::new(*kind, position as u32 + 1))
+                .collect(),
+            start: Instant::now(),
+            #[cfg(test)]
+            exchanges: 0,
+        }
+    }
+
+    /// Sets the inputs of the subdevice at position as if the terminal sampled them
+    #[cfg(test)]
+    pub fn set_inputs(&mut self, position: usize, inputs: &[u8]) {
+        let subdevice = &mut self.subdevices[position];
+        let Some((address, length)) = subdevice
+            .inputs_sm
+            .and_then(|index| subdevice.sync_manager(index))
+        else {
+            return;
+        };
+        let len = length.min(inputs.len());
+        subdevice.memory[address..address + len].copy_from_slice(&inputs[..len]);
+    }
+
+    /// Outputs last written to the subdevice at position, empty until its process data is configured
+    #[cfg(test)]
+    pub fn outputs(&self, position: usize) -> &[u8] {
+        let subdevice = &self.subdevices[position];
+        match subdevice
+            .outputs_sm
+            .and_then(|index| subdevice.sync_manager(index))
+        {
+            Some((address, length)) => &subdevice.memory[address..address + length],
+            None => &[],
+        }
+    }
+
+    /// Object dictionary entry of the subdevice at position
+    #[cfg(test)]
+    pub fn object(&self, position: usize, index: u16, subindex: u8) -> Option<&[u8]> {
+        self.subdevices[position]
+            .od
+            .get(index, subindex)
+            .map(|value| value.as_slice())
+    }
+
+    /// AL state of the subdevice at position, e.g. 0x08 in operational
+    #[cfg(test)]
+    pub fn al_state(&self, position: usize) -> u8 {
+        self.subdevices[position].memory[REG_AL_STATUS] & 0x0F
+    }
+
+    /// Process data exchanges so far
+    #[cfg(test)]
+    pub fn exchanges(&self) -> usize {
+        self.exchanges
+    }
+
+    /// A disconnected subdevice and everything after it no longer answers frames
+    #[cfg(test)]
+    pub fn set_connected(&mut self, position: usize, connected: bool) {
+        self.subdevices[position].connected = connected;
+    }
+
+    /// Resets the subdevice at position to its power on state
+    #[cfg(test)]
+    pub fn power_cycle(&mut self, position: usize) {
+        let subdevice = &mut self.subdevices[position];
+        let connected = subdevice.connected;
+        *subdevice = Esc::new(subdevice.kind, subdevice.serial);
+        subdevice.connected = connected;
+    }
+
+    /// Passes an ethernet frame through the segment, None when no subdevice is reachable
+    pub fn process(&mut self, frame: &[u8]) -> Option<Vec<u8>> {
+        let reachable = self
+            .subdevices
+            .iter()
+            .take_while(|subdevice| subdevice.connected)
+            .count();
+        if reachable == 0 || frame.len() < ETHERNET_HEADER_LEN + ETHERCAT_HEADER_LEN {
+            return None;
+        }
+        let start = self.start;
+        let subdevices = &mut self.subdevices[..reachable];
+        for (position, subdevice) in subdevices.iter_mut().enumerate() {
+            subdevice.downstream = reachable - position - 1;
+            // port 0 towards the main device, port 1 open when there is a next subdevice, 2 and 3 closed
+            let dl_status: u16 = match subdevice.downstream {
+                0 => 0x5610,
+                _ => 0x5A30,
+            };
+            subdevice.memory[REG_DL_STATUS..REG_DL_STATUS + 2]
+                .copy_from_slice(&dl_status.to_le_bytes());
+        }
+
+        let mut response = frame.to_vec();
+        // the first subdevice marks the frame as processed
+        response[6] |= 0x02;
+        let mut offset = ETHERNET_HEADER_LEN + ETHERCAT_HEADER_LEN;
+        loop {
+            let Some(header) = response.get(offset..offset + PDU_HEADER_LEN) else {
+                break;
+            };
+            let command = header[0];
+            let adp = u16::from_le_bytes([header[2], header[3]]);
+            let ado = u16::from_le_bytes([header[4], header[5]]) as usize;
+            let logical = u32::from_le_bytes([header[2], header[3], header[4], header[5]]);
+            let flags = u16::from_le_bytes([header[6], header[7]]);
+            let len = (flags & PDU_LEN_MASK) as usize;
+            let data_start = offset + PDU_HEADER_LEN;
+            if response.len() < data_start + len + 2 {
+                break;
+            }
+            let mut data = response[data_start..data_start + len].to_vec();
+            let mut wkc = u16_at(&response, data_start + len);
+            let mut next_adp = adp;
+
+            match command {
+                NOP => {}
+                APRD | APWR | APRW | ARMW => {
+                    // every subdevice increments the address, the one seeing 0 is addressed
+                    let addressed = Some(0u16.wrapping_sub(adp) as usize)
+                        .filter(|position| *position < reachable);
+                    for (position, subdevice) in subdevices.iter_mut().enumerate() {
+                        if Some(position) == addressed {
+                            wkc += access(subdevice, command, ado, &mut data, start);
+                        } else if command == ARMW && addressed.is_some() {
+                            subdevice.write(ado, &data, start);
+                        }
+                    }
+                    next_adp = adp.wrapping_add(reachable as u16);
+                }
+                FPRD | FPWR | FPRW | FRMW => {
+                    let addressed = subdevices
+                        .iter()
+                        .position(|subdevice| subdevice.station_address() == adp);
+                    if let Some(addressed) = addressed {
+                        wkc += access(&mut subdevices[addressed], command, ado, &mut data, start);
+                        if command == FRMW {
+                            for (position, subdevice) in subdevices.iter_mut().enumerate() {
+                                if position != addressed {
+                                    subdevice.write(ado, &data, start);
+                                }
+                            }
+                        }
+                    }
+                }
+                BRD | BWR | BRW => {
+                    let original = data.clone();
+                    for subdevice in subdevices.iter_mut() {
+                        if command != BWR {
+                            let read = subdevice.read(ado, len, start);
+                            data.iter_mut()
+                                .zip(read)
+                                .for_each(|(data, read)| *data |= read);
+                        }
+                        if command != BRD {
+                            subdevice.write(ado, &original, start);
+                        }
+                        wkc += 1;
+                    }
+                    next_adp = adp.wrapping_add(reachable as u16);
+                }
+                LRD | LWR | LRW => {
+                    for subdevice in subdevices.iter_mut() {
+                        wkc += subdevice.logical(command, logical, &mut data);
+                    }
+                    #[cfg(test)]
+                    {
+                        self.exchanges += 1;
+                    }
+                }
+                _ => {}
+            }
+
+            if command != LRD && command != LWR && command != LRW {
+                response[offset + 2..offset + 4].copy_from_slice(&next_adp.to_le_bytes());
+            }
+            response[data_start..data_start + len].copy_from_slice(&data);
+            response[data_start + len..data_start + len + 2].copy_from_slice(&wkc.to_le_bytes());
+            offset = data_start + len + 2;
+            if flags & PDU_MORE_FOLLOWS == 0 {
+                break;
+            }
+        }
+        Some(response)
+    }
+}
+
+/// Physical access of the addressed subdevice, returns the working counter increment
+fn access(
+    subdevice: &mut Esc,
+    command: u8,
+    address: usize,
+    data: &mut [u8],
+    start: Instant,
+) -> u16 {
+    match command {
+        APRD | FPRD | ARMW | FRMW => {
+            data.copy_from_slice(&subdevice.read(address, data.len(), start));
+            1
+        }
+        APWR | FPWR => {
+            subdevice.write(address, data, start);
+            1
+        }
+        APRW | FPRW => {
+            let read = subdevice.read(address, data.len(), start);
+            subdevice.write(address, data, start);
+            data.copy_from_slice(&read);
+            3
+        }
+        _ => 0,
+    }
+}
+
+/// Exchanges the frames of the main device with the segment, in place of the network interface
+pub async fn tx_rx_task(
+    segment: Arc<Mutex<Segment>>,
+    mut pdu_tx: PduTx<'static>,
+    mut pdu_rx: PduRx<'static>,
+) -> Result<(), ethercrab::error::Error> {
+    poll_fn(|ctx| {
+        pdu_tx.replace_waker(ctx.waker());
+        while let Some(frame) = pdu_tx.next_sendable_frame() {
+            let mut response = None;
+            frame.send_blocking(|data| {
+                response = segment.lock().unwrap().process(data);
+                Ok(data.len())
+            })?;
+            // a lost frame times out in the main device like on a real segment
+            if let Some(response) = response {
+                pdu_rx.receive_frame(&response)?;
+            }
+        }
+        if pdu_tx.should_exit() {
+            return Poll::Ready(Ok(()));
+        }
+        Poll::Pending
+    })
+    .await
+}
+
+/// Main device on the segment with its own PDU storage, so every test can have one
+#[cfg(test)]
+pub(crate) fn main_device(segment: &Arc<Mutex<Segment>>) -> ethercrab::MainDevice<'static> {
+    use ethercrab::{MainDevice, MainDeviceConfig, PduStorage, Timeouts};
+    let storage: &'static PduStorage<16, 1100> = Box::leak(Box::new(PduStorage::new()));
+    let (tx, rx, pdu_loop) = storage.try_split().expect("can only split once");
+    tokio::spawn(tx_rx_task(segment.clone(), tx, rx));
+    MainDevice::new(pdu_loop, Timeouts::default(), MainDeviceConfig::default())
+}
+
+/// D-Bus connection to a peer in the process, so drivers and the bus can be built without a
+/// bus daemon. The peer is leaked like the PDU storage of `main_device`
+#[cfg(test)]
+pub(crate) async fn dbus() -> zbus::Connection {
+    use zbus::connection::Builder;
+    let (server, client) = std::os::unix::net::UnixStream::pair().expect("socket pair");
+    let server = Builder::unix_stream(server)
+        .server(zbus::Guid::generate())
+        .expect("guid")
+        .p2p()
+        .build();
+    let client = Builder::unix_stream(client).p2p().build();
+    let (server, client) = tokio::try_join!(server, client).expect("peer connection");
+    Box::leak(Box::new(server));
+    client
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::devices::beckhoff::{ek1xxx::Ek1100, el1xxx::El1008Info, el2xxx::El2008Info};
+    use crate::devices::device_trait::DeviceInfo;
+    use ethercrab::std::ethercat_now;
+
+    #[tokio::test]
+    async fn test_digital_io() {
+        let segment = Arc::new(Mutex::new(Segment::new(&[
+            SimulatedSubDevice::Ek1100,
+            SimulatedSubDevice::El1008,
+            SimulatedSubDevice::El2008,
+        ])));
+        let main_device = main_device(&segment);
+        let group = main_device
+            .init_single_group::<16, 64>(ethercat_now)
+            .await
+            .expect("init");
+        assert_eq!(
+            group
+                .iter(&main_device)
+                .map(|subdevice| subdevice.identity().product_id)
+                .collect::<Vec<_>>(),
+            vec![
+                Ek1100::PRODUCT_ID,
+                El1008Info::PRODUCT_ID,
+                El2008Info::PRODUCT_ID
+            ]
+        );
+
+        let group = group.into_op(&main_device).await.expect("op");
+        segment.lock().unwrap().set_inputs(1, &[0b1010_0101]);
+        {
+            let mut el2008 = group.subdevice(&main_device, 2).expect("el2008");
+            el2008.io_raw_mut().1[0] = 0x3C;
+        }
+        group.tx_rx(&main_device).await.expect("tx/rx");
+
+        let el1008 = group.subdevice(&main_device, 1).expect("el1008");
+        assert_eq!(el1008.io_raw().0[0], 0b1010_0101);
+        assert_eq!(segment.lock().unwrap().outputs(2), &[0x3C]);
+    }
+
+    #[test]
+    fn test_sdo_information_fragments() {
+        let mut od = ObjectDictionary::default();
+        for index in 0..100u16 {
+            od.insert(0x2000 + index, 0x00, vec![0; 4]);
+        }
+        // get OD list of all objects
+        let request = [0x00, 0x80, 0x01, 0x00, 0x00, 0x00, 0x01, 0x00];
+        let fragments = od.coe(&request, MAILBOX_LEN - MAILBOX_HEADER_LEN);
+        assert_eq!(fragments.len(), 2);
+        assert_eq!(fragments[0][2..6], [0x82, 0x00, 0x01, 0x00]);
+        assert_eq!(fragments[1][2..6], [0x02, 0x00, 0x00, 0x00]);
+        let indices: Vec<u8> = fragments.iter().flat_map(|f| f[6..].to_vec()).collect();
+        // list type and the 100 indices
+        assert_eq!(indices.len(), 2 + 200);
+    }
+
+    #[tokio::test]
+    async fn test_coe() {
+        let segment = Arc::new(Mutex::new(Segment::new(&[SimulatedSubDevice::El3356])));
+        let main_device = main_device(&segment);
+        let group = main_device
+            .init_single_group::<16, 64>(ethercat_now)
+            .await
+            .expect("init");
+        {
+            let el3356 = group.iter(&main_device).next().expect("el3356");
+            el3356.sdo_write(0x8000, 0x27, 2.5f32).await.expect("write");
+            assert_eq!(
+                el3356.sdo_read::<f32>(0x8000, 0x27).await.expect("read"),
+                2.5
+            );
+            assert!(el3356.sdo_read::<u32>(0x8001, 0x01).await.is_err());
+            // objects which are not modelled, a wrong size and a PDO of the other direction
+            assert!(el3356.sdo_write(0x8001, 0x01, 0u32).await.is_err());
+            assert!(el3356.sdo_write(0x8000, 0x29, 0u32).await.is_err());
+            assert!(el3356.sdo_write(0x8000, 0x27, 0u8).await.is_err());
+            assert!(el3356.sdo_write(0x1C13, 0x01, 0x1600u16).await.is_err());
+
+            // the assignment the driver makes, status word and REAL value
+            el3356.sdo_write(0x1C13, 0x00, 0u8).await.expect("write");
+            el3356
+                .sdo_write(0x1C13, 0x01, 0x1A00u16)
+                .await
+                .expect("write");
+            el3356
+                .sdo_write(0x1C13, 0x02, 0x1A02u16)
+                .await
+                .expect("write");
+            el3356.sdo_write(0x1C13, 0x00, 2u8).await.expect("write");
+        }
+        assert_eq!(
+            segment.lock().unwrap().object(0, 0x1C13, 0x02),
+            Some(&0x1A02u16.to_le_bytes()[..])
+        );
+
+        let group = group.into_op(&main_device).await.expect("op");
+        segment
+            .lock()
+            .unwrap()
+            .set_inputs(0, &[0, 0, 0x00, 0x00, 0x20, 0x41]);
+        group.tx_rx(&main_device).await.expect("tx/rx");
+        let el3356 = group.subdevice(&main_device, 0).expect("el3356");
+        assert_eq!(
+            el3356.io_raw().0.to_vec(),
+            vec![0, 0, 0x00, 0x00, 0x20, 0x41]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_disconnected() {
+        let segment = Arc::new(Mutex::new(Segment::new(&[
+            SimulatedSubDevice::Ek1100,
+            SimulatedSubDevice::El1002,
+            SimulatedSubDevice::El2004,
+        ])));
+        segment.lock().unwrap().set_connected(1, false);
+        let main_device = main_device(&segment);
+        let group = main_device
+            .init_single_group::<16, 64>(ethercat_now)
+            .await
+            .expect("init");
+        assert_eq!(group.len(), 1);
+    }
+}
@@ -0,0 +1,232 @@
+use super::sii::Pdo;
+use super::SimulatedSubDevice;
+use crate::devices::beckhoff::{
+    ek1xxx::Ek1100,
+    el1xxx::{El1002Info, El1008Info, El1809Info},
+    el2xxx::{El2004Info, El2008Info, El2794Info, El2809Info},
+    el3356::El3356,
+    el5xxx::{El5101Info, El5151Info},
+};
+use crate::devices::device_trait::DeviceInfo;
+use crate::devices::lenze::i550::I550;
+
+/// What an emulated subdevice looks like to the master
+pub struct Description {
+    pub vendor_id: u32,
+    pub product_id: u32,
+    pub revision: u32,
+    pub name: &'static str,
+    /// Has a mailbox and an object dictionary, the PDO assignment is then read over CoE
+    pub coe: bool,
+    /// Assigned by default
+    pub rx_pdos: Vec<Pdo>,
+    /// Assigned by default
+    pub tx_pdos: Vec<Pdo>,
+    /// Mapped but not assigned by default, CoE only
+    pub spare_pdos: Vec<Pdo>,
+    /// Objects besides identity, sync manager and PDO objects
+    pub objects: Vec<(u16, u8, Vec<u8>)>,
+}
+
+impl Description {
+    fn digital<D: DeviceInfo>(rx_channels: u16, tx_channels: u16) -> Self {
+        // one PDO per channel with a single bit, like the real terminals
+        let channel = |pdo: u16, index: u16, channel: u16| Pdo {
+            index: pdo + channel,
+            entries: vec![(index + channel * 0x10, 0x01, 1)],
+        };
+        Self {
+            vendor_id: D::VENDOR_ID,
+            product_id: D::PRODUCT_ID,
+            revision: 0x0010_0000,
+            name: D::NAME,
+            coe: false,
+            rx_pdos: (0..rx_channels)
+                .map(|c| channel(0x1600, 0x7000, c))
+                .collect(),
+            tx_pdos: (0..tx_channels)
+                .map(|c| channel(0x1A00, 0x6000, c))
+                .collect(),
+            spare_pdos: Vec::new(),
+            objects: Vec::new(),
+        }
+    }
+
+    /// Encoder with the 32 bit control and status PDOs assigned, the period PDO is mapped but only
+    /// assigned by default when given
+    fn encoder<D: DeviceInfo>(period_assigned: bool) -> Self {
+        let control = Pdo {
+            index: 0x1601,
+            entries: vec![
+                (0x7000, 0x01, 1),
+                (0x7000, 0x02, 1),
+                (0x7000, 0x03, 1),
+                (0x0000, 0x00, 13),
+                (0x7000, 0x11, 32),
+            ],
+        };
+        let status = Pdo {
+            index: 0x1A01,
+            entries: vec![
+                (0x6000, 0x01, 1),
+                (0x6000, 0x02, 1),
+                (0x6000, 0x03, 1),
+                (0x0000, 0x00, 13),
+                (0x6000, 0x11, 32),
+                (0x6000, 0x12, 32),
+            ],
+        };
+        let period = Pdo {
+            index: 0x1A02,
+            entries: vec![(0x6000, 0x14, 32)],
+        };
+        let (tx_pdos, spare_pdos) = match period_assigned {
+            true => (vec![status, period], Vec::new()),
+            false => (vec![status], vec![period]),
+        };
+        Self {
+            vendor_id: D::VENDOR_ID,
+            product_id: D::PRODUCT_ID,
+            revision: 0x0014_0000,
+            name: D::NAME,
+            coe: true,
+            rx_pdos: vec![control],
+            tx_pdos,
+            spare_pdos,
+            objects: Vec::new(),
+        }
+    }
+}
+
+pub fn description(subdevice: SimulatedSubDevice) -> Description {
+    match subdevice {
+        SimulatedSubDevice::Ek1100 => Description::digital::<Ek1100>(0, 0),
+        SimulatedSubDevice::El1002 => Description::digital::<El1002Info>(0, 2),
+        SimulatedSubDevice::El1008 => Description::digital::<El1008Info>(0, 8),
+        SimulatedSubDevice::El1809 => Description::digital::<El1809Info>(0, 16),
+        SimulatedSubDevice::El2004 => Description::digital::<El2004Info>(4, 0),
+        SimulatedSubDevice::El2008 => Description::digital::<El2008Info>(8, 0),
+        SimulatedSubDevice::El2794 => Description {
+            coe: true,
+            // short circuit bits, assigned by the driver
+            spare_pdos: vec![Pdo {
+                index: 0x1A00,
+                entries: vec![
+                    (0x6000, 0x01, 1),
+                    (0x6010, 0x01, 1),
+                    (0x6020, 0x01, 1),
+                    (0x6030, 0x01, 1),
+                    (0x0000, 0x00, 4),
+                ],
+            }],
+            ..Description::digital::<El2794Info>(4, 0)
+        },
+        SimulatedSubDevice::El2809 => Description::digital::<El2809Info>(16, 0),
+        SimulatedSubDevice::El3356 => Description {
+            vendor_id: El3356::VENDOR_ID,
+            product_id: El3356::PRODUCT_ID,
+            revision: 0x0012_0000,
+            name: El3356::NAME,
+            coe: true,
+            rx_pdos: vec![Pdo {
+                index: 0x1600,
+                entries: vec![(0x7000, 0x01, 16)],
+            }],
+            // status word and the INT32 value, the driver switches to the REAL value of 0x1A02
+            tx_pdos: vec![
+                Pdo {
+                    index: 0x1A00,
+                    entries: vec![(0x6000, 0x01, 16)],
+                },
+                Pdo {
+                    index: 0x1A01,
+                    entries: vec![(0x6000, 0x11, 32)],
+                },
+            ],
+            spare_pdos: vec![Pdo {
+                index: 0x1A02,
+                entries: vec![(0x6000, 0x12, 32)],
+            }],
+            objects: [
+                // restore defaults
+                (0x1011, 0x01, vec![0; 4]),
+                // sync mode of the outputs and inputs
+                (0x1C32, 0x01, vec![0; 2]),
+                (0x1C33, 0x01, vec![0; 2]),
+                // filter
+                (0x8000, 0x11, vec![0; 2]),
+            ]
+            .into_iter()
+            // gain, tare, nominal value, nominal load, zero balance, gravity, scale factor and
+            // reference load, all REAL
+            .chain((0x21..=0x28).map(|subindex| (0x8000, subindex, vec![0; 4])))
+            // calibration command, its status and response, polled by the driver
+            .chain([
+                (0xFB00, 0x01, vec![0; 2]),
+                (0xFB00, 0x02, vec![0]),
+                (0xFB00, 0x03, vec![0; 4]),
+            ])
+            .collect(),
+        },
+        SimulatedSubDevice::El5101 => Description::encoder::<El5101Info>(false),
+        SimulatedSubDevice::El5151 => Description::encoder::<El5151Info>(true),
+        SimulatedSubDevice::I550 => Description {
+            vendor_id: I550::VENDOR_ID,
+            product_id: I550::PRODUCT_ID,
+            revision: 0x0001_0000,
+            name: I550::NAME,
+            coe: true,
+            rx_pdos: vec![Pdo {
+                index: 0x1605,
+                entries: vec![(0x6040, 0x00, 16), (0x6042, 0x00, 16)],
+            }],
+            tx_pdos: vec![Pdo {
+                index: 0x1A05,
+                entries: vec![
+                    (0x6041, 0x00, 16),
+                    (0x6044, 0x00, 16),
+                    (0x603F, 0x00, 16),
+                    (0x2D88, 0x00, 16),
+                    (0x2DDD, 0x00, 16),
+                    (0x60FD, 0x00, 32),
+                    (0x2DA4, 0x01, 16),
+                ],
+            }],
+            spare_pdos: Vec::new(),
+            // the parameters the driver writes in setup
+            objects: vec![
+                (0x1C32, 0x01, vec![0; 2]),
+                (0x1C33, 0x01, vec![0; 2]),
+                // rated mains voltage
+                (0x2540, 0x01, vec![0]),
+                // enable inverter and reset fault
+                (0x2631, 0x01, vec![0]),
+                (0x2631, 0x04, vec![0]),
+                // analog input 1 range and error response
+                (0x2636, 0x01, vec![0]),
+                (0x2636, 0x0A, vec![0]),
+                // base voltage and frequency
+                (0x2B01, 0x01, vec![0; 2]),
+                (0x2B01, 0x02, vec![0; 2]),
+                // motor data
+                (0x2C01, 0x02, vec![0; 4]),
+                (0x2C01, 0x03, vec![0; 4]),
+                (0x2C01, 0x04, vec![0; 2]),
+                (0x2C01, 0x05, vec![0; 2]),
+                (0x2C01, 0x06, vec![0; 2]),
+                (0x2C01, 0x07, vec![0; 2]),
+                (0x2C01, 0x08, vec![0; 2]),
+                // velocity mode, min and max speed, acceleration
+                (0x6046, 0x01, vec![0; 4]),
+                (0x6046, 0x02, vec![0; 4]),
+                (0x6048, 0x01, vec![0; 4]),
+                (0x6048, 0x02, vec![0; 2]),
+                (0x6060, 0x00, vec![0]),
+                // max current, rated current and max speed
+                (0x6073, 0x00, vec![0; 2]),
+                (0x6075, 0x00, vec![0; 4]),
+                (0x6080, 0x00, vec![0; 4]),
+            ],
+        },
+    }
+}
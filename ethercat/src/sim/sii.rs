@@ -0,0 +1,185 @@
+//! SII EEPROM image, only the parts ethercrab reads during init
+
+pub const SM_MAILBOX_OUT: u8 = 1;
+pub const SM_MAILBOX_IN: u8 = 2;
+pub const SM_OUTPUTS: u8 = 3;
+pub const SM_INPUTS: u8 = 4;
+
+const MAILBOX_PROTOCOL_COE: u16 = 0x0004;
+/// SDO, PDO assign and PDO configuration, no complete access
+const COE_DETAILS: u8 = 0x0D;
+
+const CATEGORY_STRINGS: u16 = 10;
+const CATEGORY_GENERAL: u16 = 30;
+const CATEGORY_FMMU: u16 = 40;
+const CATEGORY_SYNC_MANAGER: u16 = 41;
+const CATEGORY_TX_PDO: u16 = 50;
+const CATEGORY_RX_PDO: u16 = 51;
+const CATEGORY_END: u16 = 0xFFFF;
+
+const FMMU_OUTPUTS: u8 = 1;
+const FMMU_INPUTS: u8 = 2;
+const FMMU_SM_STATUS: u8 = 3;
+
+/// Process data object, entries are index, subindex and bit length
+#[derive(Clone)]
+pub struct Pdo {
+    pub index: u16,
+    pub entries: Vec<(u16, u8, u8)>,
+}
+impl Pdo {
+    pub fn bits(&self) -> usize {
+        self.entries.iter().map(|(_, _, bits)| *bits as usize).sum()
+    }
+    /// Mapping entry as written to the PDO mapping object
+    pub fn mapping(entry: &(u16, u8, u8)) -> u32 {
+        let (index, subindex, bits) = *entry;
+        (index as u32) << 16 | (subindex as u32) << 8 | bits as u32
+    }
+}
+
+#[derive(Clone, Copy)]
+pub struct SyncManager {
+    pub address: u16,
+    pub length: u16,
+    pub control: u8,
+    pub enable: bool,
+    pub kind: u8,
+}
+
+pub struct Mailbox {
+    pub rx_address: u16,
+    pub rx_size: u16,
+    pub tx_address: u16,
+    pub tx_size: u16,
+}
+
+pub struct Identity {
+    pub vendor_id: u32,
+    pub product_id: u32,
+    pub revision: u32,
+    pub serial: u32,
+}
+
+fn push_u32(words: &mut Vec<u16>, value: u32) {
+    words.push(value as u16);
+    words.push((value >> 16) as u16);
+}
+
+fn push_category(words: &mut Vec<u16>, category: u16, mut data: Vec<u8>) {
+    if data.len() % 2 != 0 {
+        data.push(0);
+    }
+    words.push(category);
+    words.push((data.len() / 2) as u16);
+    words.extend(
+        data.chunks(2)
+            .map(|chunk| u16::from_le_bytes([chunk[0], chunk[1]])),
+    );
+}
+
+fn pdo_category(pdos: &[Pdo], sync_manager: u8) -> Vec<u8> {
+    let mut data = Vec::new();
+    for pdo in pdos {
+        data.extend_from_slice(&pdo.index.to_le_bytes());
+        data.push(pdo.entries.len() as u8);
+        data.push(sync_manager);
+        // dc sync, name index and flags
+        data.extend_from_slice(&[0, 0, 0, 0]);
+        for (index, subindex, bits) in pdo.entries.iter() {
+            data.extend_from_slice(&index.to_le_bytes());
+            data.push(*subindex);
+            // name index and data type
+            data.extend_from_slice(&[0, 0]);
+            data.push(*bits);
+            data.extend_from_slice(&[0, 0]);
+        }
+    }
+    data
+}
+
+/// EEPROM content by word address
+pub fn image(
+    identity: &Identity,
+    name: &str,
+    mailbox: Option<&Mailbox>,
+    sync_managers: &[SyncManager],
+    rx_pdos: &[Pdo],
+    tx_pdos: &[Pdo],
+) -> Vec<u16> {
+    // configuration area, the station alias in word 4 is left at 0
+    let mut words = vec![0u16; 8];
+    push_u32(&mut words, identity.vendor_id);
+    push_u32(&mut words, identity.product_id);
+    push_u32(&mut words, identity.revision);
+    push_u32(&mut words, identity.serial);
+    // bootstrap mailbox
+    words.resize(0x18, 0);
+    match mailbox {
+        Some(mailbox) => words.extend_from_slice(&[
+            mailbox.rx_address,
+            mailbox.rx_size,
+            mailbox.tx_address,
+            mailbox.tx_size,
+            MAILBOX_PROTOCOL_COE,
+        ]),
+        None => words.extend_from_slice(&[0; 5]),
+    }
+    words.resize(0x3E, 0);
+    // size in kbit minus one and version
+    words.extend_from_slice(&[0x0001, 0x0001]);
+
+    let mut strings = vec![1, name.len() as u8];
+    strings.extend_from_slice(name.as_bytes());
+    push_category(&mut words, CATEGORY_STRINGS, strings);
+
+    let mut general = vec![0u8; 32];
+    // name string index
+    general[3] = 1;
+    if mailbox.is_some() {
+        general[5] = COE_DETAILS;
+    }
+    push_category(&mut words, CATEGORY_GENERAL, general);
+
+    push_category(
+        &mut words,
+        CATEGORY_FMMU,
+        vec![FMMU_OUTPUTS, FMMU_INPUTS, FMMU_SM_STATUS, 0],
+    );
+
+    let mut sync_manager_data = Vec::new();
+    for sync_manager in sync_managers {
+        sync_manager_data.extend_from_slice(&sync_manager.address.to_le_bytes());
+        sync_manager_data.extend_from_slice(&sync_manager.length.to_le_bytes());
+        sync_manager_data.push(sync_manager.control);
+        sync_manager_data.push(0);
+        sync_manager_data.push(sync_manager.enable as u8);
+        sync_manager_data.push(sync_manager.kind);
+    }
+    if !sync_managers.is_empty() {
+        push_category(&mut words, CATEGORY_SYNC_MANAGER, sync_manager_data);
+    }
+
+    let index_of = |kind: u8| {
+        sync_managers
+            .iter()
+            .position(|sync_manager| sync_manager.kind == kind)
+            .map_or(0xFF, |index| index as u8)
+    };
+    if !tx_pdos.is_empty() {
+        push_category(
+            &mut words,
+            CATEGORY_TX_PDO,
+            pdo_category(tx_pdos, index_of(SM_INPUTS)),
+        );
+    }
+    if !rx_pdos.is_empty() {
+        push_category(
+            &mut words,
+            CATEGORY_RX_PDO,
+            pdo_category(rx_pdos, index_of(SM_OUTPUTS)),
+        );
+    }
+    words.extend_from_slice(&[CATEGORY_END, 0]);
+    words
+}
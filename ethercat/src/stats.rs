@@ -0,0 +1,521 @@
+#[cfg(feature = "opcua-expose")]
+use crate::opcua::OpcuaServerHandle;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tfc::ipc::{Base, Signal};
+use zbus::interface;
+
+pub static DBUS_PATH: &str = "/is/centroid/ethercat/stats";
+
+/// Upper bounds of the tx/rx latency histogram buckets in microseconds, the last bucket is unbounded
+const TX_RX_BUCKETS_US: [u64; 10] = [50, 100, 200, 300, 500, 750, 1000, 2000, 5000, 10000];
+/// Number of most recent cycles the jitter percentile is computed over
+const JITTER_WINDOW: usize = 1000;
+
+/// Reset of every group's counters, a reset is requested by bumping the generation
+pub struct StatsDbusInterface {
+    reset: Arc<AtomicU64>,
+}
+impl StatsDbusInterface {
+    pub fn new(reset: Arc<AtomicU64>) -> Self {
+        Self { reset }
+    }
+}
+#[interface(name = "is.centroid.ethercat.Stats")]
+impl StatsDbusInterface {
+    async fn reset(&self) -> Result<(), zbus::fdo::Error> {
+        self.reset.fetch_add(1, Ordering::Relaxed);
+        Ok(())
+    }
+}
+
+#[derive(Serialize)]
+struct Bucket {
+    /// None for the last bucket
+    le_us: Option<u64>,
+    count: u64,
+}
+
+#[derive(Serialize)]
+struct DeviceDuration {
+    mean_us: f64,
+    max_us: f64,
+}
+
+struct StatsSignals {
+    jitter_min: Signal<f64>,
+    jitter_max: Signal<f64>,
+    jitter_mean: Signal<f64>,
+    jitter_p99: Signal<f64>,
+    overruns: Signal<u64>,
+    tx_rx_histogram: Signal<String>,
+    process_data: Signal<String>,
+}
+
+impl StatsSignals {
+    fn new(dbus: zbus::Connection, group: &str) -> Self {
+        let f64_signal = |name: &str, description: &str| -> Signal<f64> {
+            let signal = Signal::new(
+                dbus.clone(),
+                Base::new(
+                    format!("stats/{}/{}", group, name).as_str(),
+                    Some(description),
+                ),
+            );
+            #[cfg(feature = "dbus-expose")]
+            tfc::ipc::dbus::SignalInterface::register(
+                signal.base(),
+                dbus.clone(),
+                signal.subscribe(),
+            );
+            signal
+        };
+        let jitter_min = f64_signal(
+            "jitter_min",
+            "Smallest deviation of the cycle period from the cycle time. Microseconds",
+        );
+        let jitter_max = f64_signal(
+            "jitter_max",
+            "Largest deviation of the cycle period from the cycle time. Microseconds",
+        );
+        let jitter_mean = f64_signal(
+            "jitter_mean",
+            "Mean deviation of the cycle period from the cycle time. Microseconds",
+        );
+        let jitter_p99 = f64_signal(
+            "jitter_p99",
+            "99th percentile of the deviation over the last 1000 cycles. Microseconds",
+        );
+        let overruns = Signal::new(
+            dbus.clone(),
+            Base::new(
+                format!("stats/{}/overruns", group).as_str(),
+                Some(
+                    "Number of cycles where tx/rx and process data took longer than the cycle time",
+                ),
+            ),
+        );
+        let tx_rx_histogram = Signal::new(
+            dbus.clone(),
+            Base::new(
+                format!("stats/{}/tx_rx_histogram", group).as_str(),
+                Some("Tx/Rx latency histogram, JSON list of buckets with upper bound in microseconds"),
+            ),
+        );
+        let process_data = Signal::new(
+            dbus.clone(),
+            Base::new(
+                format!("stats/{}/process_data", group).as_str(),
+                Some("Process data duration per subdevice position, JSON. Microseconds"),
+            ),
+        );
+        #[cfg(feature = "dbus-expose")]
+        {
+            tfc::ipc::dbus::SignalInterface::register(
+                overruns.base(),
+                dbus.clone(),
+                overruns.subscribe(),
+            );
+            tfc::ipc::dbus::SignalInterface::register(
+                tx_rx_histogram.base(),
+                dbus.clone(),
+                tx_rx_histogram.subscribe(),
+            );
+            tfc::ipc::dbus::SignalInterface::register(
+                process_data.base(),
+                dbus.clone(),
+                process_data.subscribe(),
+            );
+        }
+        Self {
+            jitter_min,
+            jitter_max,
+            jitter_mean,
+            jitter_p99,
+            overruns,
+            tx_rx_histogram,
+            process_data,
+        }
+    }
+    #[cfg(feature = "opcua-expose")]
+    fn opcua_register(&self, handle: &OpcuaServerHandle) {
+        for signal in [
+            &self.jitter_min,
+            &self.jitter_max,
+            &self.jitter_mean,
+            &self.jitter_p99,
+        ] {
+            tfc::ipc::opcua::SignalInterface::new(
+                signal.base(),
+                signal.subscribe(),
+                handle.manager.clone(),
+                handle.subscriptions.clone(),
+                handle.namespace,
+            )
+            .register();
+        }
+        tfc::ipc::opcua::SignalInterface::new(
+            self.overruns.base(),
+            self.overruns.subscribe(),
+            handle.manager.clone(),
+            handle.subscriptions.clone(),
+            handle.namespace,
+        )
+        .register();
+        tfc::ipc::opcua::SignalInterface::new(
+            self.tx_rx_histogram.base(),
+            self.tx_rx_histogram.subscribe(),
+            handle.manager.clone(),
+            handle.subscriptions.clone(),
+            handle.namespace,
+        )
+        .register();
+        tfc::ipc::opcua::SignalInterface::new(
+            self.process_data.base(),
+            self.process_data.subscribe(),
+            handle.manager.clone(),
+            handle.subscriptions.clone(),
+            handle.namespace,
+        )
+        .register();
+    }
+}
+
+/// Jitter of the cycles since the last reset, microseconds
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Jitter {
+    min: f64,
+    max: f64,
+    mean: f64,
+    p99: f64,
+}
+
+/// Timing of one group's cyclic exchange, accumulated since the last reset.
+/// Jitter is the deviation of the time between two cycle starts from the cycle time.
+struct CycleTimes {
+    last_cycle_start: Option<Instant>,
+    jitter_window: Vec<f64>,
+    jitter_window_index: usize,
+    jitter_min: f64,
+    jitter_max: f64,
+    jitter_sum: f64,
+    jitter_count: u64,
+    overruns: u64,
+    tx_rx_histogram: [u64; TX_RX_BUCKETS_US.len() + 1],
+    positions: Vec<usize>,
+    device_sum: Vec<Duration>,
+    device_max: Vec<Duration>,
+    device_count: u64,
+}
+
+impl CycleTimes {
+    fn new() -> Self {
+        Self {
+            last_cycle_start: None,
+            jitter_window: Vec::with_capacity(JITTER_WINDOW),
+            jitter_window_index: 0,
+            jitter_min: f64::MAX,
+            jitter_max: 0.0,
+            jitter_sum: 0.0,
+            jitter_count: 0,
+            overruns: 0,
+            tx_rx_histogram: [0; TX_RX_BUCKETS_US.len() + 1],
+            positions: Vec::new(),
+            device_sum: Vec::new(),
+            device_max: Vec::new(),
+            device_count: 0,
+        }
+    }
+
+    fn begin(&mut self, positions: &[usize]) {
+        self.last_cycle_start = None;
+        if self.positions != positions {
+            self.positions = positions.to_vec();
+            self.device_sum = vec![Duration::ZERO; positions.len()];
+            self.device_max = vec![Duration::ZERO; positions.len()];
+            self.device_count = 0;
+        }
+    }
+
+    fn reset(&mut self) {
+        self.last_cycle_start = None;
+        self.jitter_window.clear();
+        self.jitter_window_index = 0;
+        self.jitter_min = f64::MAX;
+        self.jitter_max = 0.0;
+        self.jitter_sum = 0.0;
+        self.jitter_count = 0;
+        self.overruns = 0;
+        self.tx_rx_histogram = [0; TX_RX_BUCKETS_US.len() + 1];
+        self.device_sum.fill(Duration::ZERO);
+        self.device_max.fill(Duration::ZERO);
+        self.device_count = 0;
+    }
+
+    fn cycle_start(&mut self, now: Instant, cycle_time: Duration) {
+        if let Some(last) = self.last_cycle_start.replace(now) {
+            let period = now - last;
+            let jitter = if period > cycle_time {
+                period - cycle_time
+            } else {
+                cycle_time - period
+            }
+            .as_secs_f64()
+                * 1e6;
+            self.jitter_min = self.jitter_min.min(jitter);
+            self.jitter_max = self.jitter_max.max(jitter);
+            self.jitter_sum += jitter;
+            self.jitter_count += 1;
+            if self.jitter_window.len() < JITTER_WINDOW {
+                self.jitter_window.push(jitter);
+            } else {
+                self.jitter_window[self.jitter_window_index] = jitter;
+            }
+            self.jitter_window_index = (self.jitter_window_index + 1) % JITTER_WINDOW;
+        }
+    }
+
+    fn tx_rx(&mut self, duration: Duration) {
+        let micros = duration.as_micros() as u64;
+        let bucket = TX_RX_BUCKETS_US
+            .iter()
+            .position(|le| micros <= *le)
+            .unwrap_or(TX_RX_BUCKETS_US.len());
+        self.tx_rx_histogram[bucket] += 1;
+    }
+
+    fn process_data(&mut self, index: usize, duration: Duration) {
+        if let (Some(sum), Some(max)) = (
+            self.device_sum.get_mut(index),
+            self.device_max.get_mut(index),
+        ) {
+            *sum += duration;
+            *max = (*max).max(duration);
+        }
+    }
+
+    fn cycle_end(&mut self, work: Duration, cycle_time: Duration) {
+        self.device_count += 1;
+        if work > cycle_time {
+            self.overruns += 1;
+        }
+    }
+
+    /// None until two cycles have started
+    fn jitter(&self) -> Option<Jitter> {
+        if self.jitter_count == 0 {
+            return None;
+        }
+        let mut window = self.jitter_window.clone();
+        window.sort_by(|a, b| a.total_cmp(b));
+        Some(Jitter {
+            min: self.jitter_min,
+            max: self.jitter_max,
+            mean: self.jitter_sum / self.jitter_count as f64,
+            p99: window[(window.len() * 99 / 100).min(window.len() - 1)],
+        })
+    }
+
+    fn histogram(&self) -> Vec<Bucket> {
+        self.tx_rx_histogram
+            .iter()
+            .enumerate()
+            .map(|(index, count)| Bucket {
+                le_us: TX_RX_BUCKETS_US.get(index).copied(),
+                count: *count,
+            })
+            .collect()
+    }
+
+    /// Process data durations by position, None before the first cycle ended
+    fn devices(&self) -> Option<BTreeMap<usize, DeviceDuration>> {
+        if self.device_count == 0 {
+            return None;
+        }
+        Some(
+            self.positions
+                .iter()
+                .zip(self.device_sum.iter().zip(self.device_max.iter()))
+                .map(|(position, (sum, max))| {
+                    (
+                        *position,
+                        DeviceDuration {
+                            mean_us: sum.as_secs_f64() * 1e6 / self.device_count as f64,
+                            max_us: max.as_secs_f64() * 1e6,
+                        },
+                    )
+                })
+                .collect(),
+        )
+    }
+}
+
+/// Cycle timing of one group published on its signals
+pub struct CycleStats {
+    signals: StatsSignals,
+    reset: Arc<AtomicU64>,
+    reset_generation: u64,
+    last_publish: Instant,
+    times: CycleTimes,
+}
+
+impl CycleStats {
+    pub fn new(dbus: zbus::Connection, group: &str, reset: Arc<AtomicU64>) -> Self {
+        let reset_generation = reset.load(Ordering::Relaxed);
+        Self {
+            signals: StatsSignals::new(dbus, group),
+            reset,
+            reset_generation,
+            last_publish: Instant::now(),
+            times: CycleTimes::new(),
+        }
+    }
+    #[cfg(feature = "opcua-expose")]
+    pub fn opcua_register(&self, handle: &OpcuaServerHandle) {
+        self.signals.opcua_register(handle);
+    }
+
+    /// Called when a group starts running, the members may have changed since the last run
+    pub fn begin(&mut self, positions: &[usize]) {
+        self.times.begin(positions);
+    }
+
+    pub fn reset(&mut self) {
+        self.times.reset();
+    }
+
+    pub fn cycle_start(&mut self, cycle_time: Duration) {
+        let generation = self.reset.load(Ordering::Relaxed);
+        if generation != self.reset_generation {
+            self.reset_generation = generation;
+            self.reset();
+        }
+        self.times.cycle_start(Instant::now(), cycle_time);
+    }
+
+    pub fn tx_rx(&mut self, duration: Duration) {
+        self.times.tx_rx(duration);
+    }
+
+    pub fn process_data(&mut self, index: usize, duration: Duration) {
+        self.times.process_data(index, duration);
+    }
+
+    /// Called after process data of all subdevices, work is the time spent since the cycle start
+    pub fn cycle_end(&mut self, work: Duration, cycle_time: Duration) {
+        self.times.cycle_end(work, cycle_time);
+    }
+
+    pub async fn publish(&mut self, interval: Duration) {
+        if self.last_publish.elapsed() < interval {
+            return;
+        }
+        self.last_publish = Instant::now();
+        if let Some(jitter) = self.times.jitter() {
+            let _ = self.signals.jitter_min.async_send(jitter.min).await;
+            let _ = self.signals.jitter_max.async_send(jitter.max).await;
+            let _ = self.signals.jitter_mean.async_send(jitter.mean).await;
+            let _ = self.signals.jitter_p99.async_send(jitter.p99).await;
+        }
+        let _ = self.signals.overruns.async_send(self.times.overruns).await;
+
+        if let Ok(histogram) = serde_json::to_string(&self.times.histogram()) {
+            let _ = self.signals.tx_rx_histogram.async_send(histogram).await;
+        }
+
+        if let Some(devices) = self.times.devices() {
+            if let Ok(process_data) = serde_json::to_string(&devices) {
+                let _ = self.signals.process_data.async_send(process_data).await;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Starts cycles at the given offsets from origin, microseconds
+    fn run(times: &mut CycleTimes, origin: Instant, starts_us: &[u64], cycle_time: Duration) {
+        for start in starts_us {
+            times.cycle_start(origin + Duration::from_micros(*start), cycle_time);
+        }
+    }
+
+    #[test]
+    fn test_jitter() {
+        let cycle_time = Duration::from_micros(1000);
+        let origin = Instant::now();
+        let mut times = CycleTimes::new();
+        run(&mut times, origin, &[0], cycle_time);
+        assert_eq!(times.jitter(), None);
+
+        // periods of 1000, 1010, 990 and 1100us
+        run(&mut times, origin, &[1000, 2010, 3000, 4100], cycle_time);
+        let jitter = times.jitter().expect("jitter");
+        assert!((jitter.min - 0.0).abs() < 1e-6);
+        assert!((jitter.max - 100.0).abs() < 1e-6);
+        assert!((jitter.mean - 30.0).abs() < 1e-6);
+        assert!((jitter.p99 - 100.0).abs() < 1e-6);
+
+        // the percentile only sees the last cycles, min/max/mean everything since the reset
+        let starts: Vec<u64> = (0..=JITTER_WINDOW as u64)
+            .map(|i| 5100 + i * 1000)
+            .collect();
+        run(&mut times, origin, &starts, cycle_time);
+        let jitter = times.jitter().expect("jitter");
+        assert!((jitter.max - 100.0).abs() < 1e-6);
+        assert!(jitter.p99.abs() < 1e-6);
+        assert_eq!(times.jitter_window.len(), JITTER_WINDOW);
+    }
+
+    #[test]
+    fn test_overruns_and_histogram() {
+        let cycle_time = Duration::from_micros(1000);
+        let mut times = CycleTimes::new();
+        times.begin(&[3, 4]);
+        for (tx_rx, work) in [(40, 500), (50, 1000), (51, 1001), (20000, 30000)] {
+            times.tx_rx(Duration::from_micros(tx_rx));
+            times.process_data(0, Duration::from_micros(100));
+            times.process_data(1, Duration::from_micros(work / 10));
+            times.cycle_end(Duration::from_micros(work), cycle_time);
+        }
+        assert_eq!(times.overruns, 2);
+
+        let histogram = times.histogram();
+        assert_eq!(histogram.len(), TX_RX_BUCKETS_US.len() + 1);
+        assert_eq!((histogram[0].le_us, histogram[0].count), (Some(50), 2));
+        assert_eq!((histogram[1].le_us, histogram[1].count), (Some(100), 1));
+        assert_eq!((histogram[10].le_us, histogram[10].count), (None, 1));
+        assert_eq!(histogram.iter().map(|b| b.count).sum::<u64>(), 4);
+
+        let devices = times.devices().expect("devices");
+        assert_eq!(devices.keys().copied().collect::<Vec<_>>(), vec![3, 4]);
+        assert!((devices[&3].mean_us - 100.0).abs() < 1e-6);
+        assert!((devices[&4].max_us - 3000.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_reset() {
+        let cycle_time = Duration::from_micros(1000);
+        let origin = Instant::now();
+        let mut times = CycleTimes::new();
+        times.begin(&[0]);
+        run(&mut times, origin, &[0, 1500], cycle_time);
+        times.tx_rx(Duration::from_micros(10));
+        times.process_data(0, Duration::from_micros(10));
+        times.cycle_end(Duration::from_micros(2000), cycle_time);
+
+        times.reset();
+        assert_eq!(times.jitter(), None);
+        assert_eq!(times.overruns, 0);
+        assert!(times.histogram().iter().all(|bucket| bucket.count == 0));
+        assert!(times.devices().is_none());
+        // the first cycle after a reset has no period to measure
+        run(&mut times, origin, &[3000], cycle_time);
+        assert_eq!(times.jitter(), None);
+    }
+}
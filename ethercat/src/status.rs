@@ -0,0 +1,196 @@
+#[cfg(feature = "opcua-expose")]
+use crate::opcua::OpcuaServerHandle;
+use serde::Serialize;
+use std::error::Error;
+use std::time::{Duration, Instant};
+use tfc::ipc::{Base, Signal};
+
+const AL_STATE_MASK: u16 = 0x0F;
+
+#[derive(Serialize)]
+struct Report<'a> {
+    /// INIT, PRE-OP, SAFE-OP, OP or BOOT, None when the subdevice did not answer
+    al_state: Option<&'static str>,
+    al_error: bool,
+    /// Setup failed, the driver holds its safe outputs
+    faulted: bool,
+    last_error: &'a str,
+    error_count: u64,
+    /// None before the first good cycle
+    since_last_good_ms: Option<u64>,
+}
+
+fn al_state_name(al_status: u16) -> &'static str {
+    match al_status & AL_STATE_MASK {
+        0x01 => "INIT",
+        0x02 => "PRE-OP",
+        0x03 => "BOOT",
+        0x04 => "SAFE-OP",
+        0x08 => "OP",
+        _ => "UNKNOWN",
+    }
+}
+
+/// State behind the report, changed by setup and by every cycle
+#[derive(Default)]
+struct Health {
+    faulted: bool,
+    last_error: String,
+    error_count: u64,
+    failing: bool,
+    last_good: Option<Instant>,
+    al_status: Option<u16>,
+}
+
+impl Health {
+    fn set_faulted(&mut self, faulted: bool, reason: Option<&str>) {
+        self.faulted = faulted;
+        if let Some(reason) = reason {
+            self.last_error = reason.to_string();
+            self.error_count += 1;
+        }
+    }
+    fn record(&mut self, result: &Result<(), Box<dyn Error + Send + Sync>>, now: Instant) -> bool {
+        match result {
+            Ok(()) => {
+                self.last_good = Some(now);
+                self.failing = false;
+                false
+            }
+            Err(e) => {
+                let first = !self.failing;
+                self.failing = true;
+                self.error_count += 1;
+                if first {
+                    self.last_error = e.to_string();
+                }
+                first
+            }
+        }
+    }
+    fn report(&self, now: Instant) -> Result<String, serde_json::Error> {
+        serde_json::to_string(&Report {
+            al_state: self.al_status.map(al_state_name),
+            al_error: self
+                .al_status
+                .map_or(false, |al_status| al_status & 0x10 != 0),
+            faulted: self.faulted,
+            last_error: &self.last_error,
+            error_count: self.error_count,
+            since_last_good_ms: self
+                .last_good
+                .map(|last_good| (now - last_good).as_millis() as u64),
+        })
+    }
+}
+
+/// Health of the driver at a segment position, published as JSON on `<name>/<index>/status`
+pub struct DeviceStatus {
+    name: String,
+    status: Signal<String>,
+    health: Health,
+    last_publish: Option<Instant>,
+}
+
+impl DeviceStatus {
+    pub fn new(dbus: zbus::Connection, name: &str, position: usize) -> Self {
+        let name = format!("{}/{}", name, position);
+        let status = Signal::new(
+            dbus.clone(),
+            Base::new(
+                format!("{}/status", name).as_str(),
+                Some("JSON health report, AL state, last error, error count and time since last good cycle"),
+            ),
+        );
+        #[cfg(feature = "dbus-expose")]
+        tfc::ipc::dbus::SignalInterface::register(status.base(), dbus.clone(), status.subscribe());
+        Self {
+            name,
+            status,
+            health: Health::default(),
+            last_publish: None,
+        }
+    }
+    #[cfg(feature = "opcua-expose")]
+    pub fn opcua_register(&self, handle: &OpcuaServerHandle) {
+        tfc::ipc::opcua::SignalInterface::new(
+            self.status.base(),
+            self.status.subscribe(),
+            handle.manager.clone(),
+            handle.subscriptions.clone(),
+            handle.namespace,
+        )
+        .register();
+    }
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+    pub async fn set_ok(&mut self) {
+        self.health.set_faulted(false, None);
+        self.publish().await;
+    }
+    pub async fn set_faulted(&mut self, reason: &str) {
+        self.health.set_faulted(true, Some(reason));
+        self.publish().await;
+    }
+    /// Outcome of process data for one cycle, returns true for the first error of a series
+    pub fn record(&mut self, result: &Result<(), Box<dyn Error + Send + Sync>>) -> bool {
+        self.health.record(result, Instant::now())
+    }
+    pub fn is_due(&self, interval: Duration) -> bool {
+        self.last_publish
+            .map_or(true, |last_publish| last_publish.elapsed() >= interval)
+    }
+    pub async fn publish_al_status(&mut self, al_status: Option<u16>) {
+        self.health.al_status = al_status;
+        self.publish().await;
+    }
+    async fn publish(&mut self) {
+        let now = Instant::now();
+        self.last_publish = Some(now);
+        if let Ok(report) = self.health.report(now) {
+            let _ = self.status.async_send(report).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_report() {
+        let start = Instant::now();
+        let mut health = Health::default();
+        assert_eq!(
+            health.report(start).unwrap(),
+            r#"{"al_state":null,"al_error":false,"faulted":false,"last_error":"","error_count":0,"since_last_good_ms":null}"#
+        );
+
+        // only the first error of a series is kept, every one is counted
+        assert!(!health.record(&Ok(()), start));
+        assert!(health.record(&Err("short read".into()), start));
+        assert!(!health.record(&Err("timeout".into()), start));
+        health.al_status = Some(0x14);
+        assert_eq!(
+            health.report(start + Duration::from_millis(250)).unwrap(),
+            r#"{"al_state":"SAFE-OP","al_error":true,"faulted":false,"last_error":"short read","error_count":2,"since_last_good_ms":250}"#
+        );
+
+        // a good cycle ends the series, the next error is reported again
+        assert!(!health.record(&Ok(()), start + Duration::from_millis(300)));
+        assert!(health.record(&Err("timeout".into()), start));
+        health.set_faulted(true, Some("setup failed"));
+        health.al_status = Some(0x08);
+        assert_eq!(
+            health.report(start + Duration::from_millis(300)).unwrap(),
+            r#"{"al_state":"OP","al_error":false,"faulted":true,"last_error":"setup failed","error_count":4,"since_last_good_ms":0}"#
+        );
+        health.set_faulted(false, None);
+        health.al_status = None;
+        assert!(health
+            .report(start + Duration::from_millis(300))
+            .unwrap()
+            .starts_with(r#"{"al_state":null,"al_error":false,"faulted":false,"#));
+    }
+}
@@ -0,0 +1,580 @@
+#[cfg(feature = "opcua-expose")]
+use crate::opcua::OpcuaServerHandle;
+use crate::scan::Sii;
+use ethercrab::{Command, MainDevice, RegisterAddress, SubDeviceIdentity};
+use log::{info, warn};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::error::Error;
+use std::sync::Arc;
+use std::time::Duration;
+use tfc::ipc::{Base, Signal};
+use tfc::time::MilliDuration;
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+
+const DL_STATUS: u16 = 0x0110;
+/// Physical link on port 0..3, bits 4-7 of DL status
+const DL_STATUS_LINK_MASK: u16 = 0x00F0;
+// SII words of the identity
+const SII_VENDOR_ID: u16 = 0x0008;
+const SII_PRODUCT_ID: u16 = 0x000A;
+const SII_SERIAL: u16 = 0x000E;
+
+#[derive(Deserialize, Serialize, JsonSchema)]
+pub struct TopologyConfig {
+    #[schemars(
+        description = "Periodically check the segment for added, removed or re-powered subdevices"
+    )]
+    pub monitor: bool,
+    #[schemars(description = "Interval between topology checks. Milliseconds")]
+    pub scan_interval: MilliDuration,
+    #[schemars(
+        description = "Re-initialize the whole segment when subdevices are added. Re-powered subdevices are brought back while the groups keep running, but added ones can only be mapped into the process data by initializing the segment again, which takes every subdevice back to init. Without this they are reported and left unused until the next re-init"
+    )]
+    pub reinit_on_added: bool,
+}
+impl Default for TopologyConfig {
+    fn default() -> Self {
+        Self {
+            monitor: true,
+            scan_interval: Duration::from_millis(1000).into(),
+            reinit_on_added: false,
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, JsonSchema, Debug, Clone, PartialEq)]
+pub struct ExpectedSubDevice {
+    #[schemars(description = "Name used in the mismatch report, e.g. EL1008")]
+    pub name: Option<String>,
+    pub vendor_id: u32,
+    pub product_id: u32,
+    #[schemars(description = "Expected alias address, None to not check it")]
+    pub alias: Option<u16>,
+}
+
+#[derive(Deserialize, Serialize, JsonSchema, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MismatchPolicy {
+    #[schemars(description = "Do not go to operational when the segment does not match")]
+    Refuse,
+    #[schemars(
+        description = "Go to operational, subdevices at mismatching positions are left without a driver"
+    )]
+    Degraded,
+}
+impl Default for MismatchPolicy {
+    fn default() -> Self {
+        Self::Refuse
+    }
+}
+
+/// Subdevice as discovered during init
+#[derive(Debug, Clone)]
+pub struct FoundSubDevice {
+    pub identity: Identity,
+    pub alias: u16,
+    pub name: String,
+}
+
+#[derive(Debug, Clone)]
+pub struct Mismatch {
+    pub position: usize,
+    pub expected: Option<ExpectedSubDevice>,
+    pub found: Option<FoundSubDevice>,
+}
+impl std::fmt::Display for Mismatch {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "position {}: expected ", self.position)?;
+        match &self.expected {
+            Some(expected) => {
+                write!(
+                    f,
+                    "{} ({:#x}:{:#x}",
+                    expected.name.as_deref().unwrap_or("?"),
+                    expected.vendor_id,
+                    expected.product_id
+                )?;
+                if let Some(alias) = expected.alias {
+                    write!(f, " alias {}", alias)?;
+                }
+                write!(f, ")")?;
+            }
+            None => write!(f, "nothing")?,
+        }
+        write!(f, ", found ")?;
+        match &self.found {
+            Some(found) => write!(
+                f,
+                "{} ({:#x}:{:#x} alias {})",
+                found.name, found.identity.vendor_id, found.identity.product_id, found.alias
+            ),
+            None => write!(f, "nothing"),
+        }
+    }
+}
+
+/// Compare the discovered segment against the configured one, position by position.
+/// An empty expected list means any topology is accepted.
+pub fn check_expected(expected: &[ExpectedSubDevice], found: &[FoundSubDevice]) -> Vec<Mismatch> {
+    if expected.is_empty() {
+        return Vec::new();
+    }
+    let mut mismatches = Vec::new();
+    for position in 0..expected.len().max(found.len()) {
+        let expected = expected.get(position);
+        let found = found.get(position);
+        let matches = match (expected, found) {
+            (Some(expected), Some(found)) => {
+                expected.vendor_id == found.identity.vendor_id
+                    && expected.product_id == found.identity.product_id
+                    && expected.alias.map_or(true, |alias| alias == found.alias)
+            }
+            _ => false,
+        };
+        if !matches {
+            mismatches.push(Mismatch {
+                position,
+                expected: expected.cloned(),
+                found: found.cloned(),
+            });
+        }
+    }
+    mismatches
+}
+
+pub fn mismatch_report(mismatches: &[Mismatch]) -> String {
+    mismatches
+        .iter()
+        .map(|m| m.to_string())
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Identity {
+    pub vendor_id: u32,
+    pub product_id: u32,
+    pub revision: u32,
+    pub serial: u32,
+}
+impl From<SubDeviceIdentity> for Identity {
+    fn from(identity: SubDeviceIdentity) -> Self {
+        Self {
+            vendor_id: identity.vendor_id,
+            product_id: identity.product_id,
+            revision: identity.revision,
+            serial: identity.serial,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct LinkChange {
+    pub position: usize,
+    pub before: u16,
+    pub after: u16,
+}
+
+/// Difference between the segment as it was initialized and what the monitor currently sees
+#[derive(Debug, Clone, PartialEq)]
+pub struct TopologyChange {
+    pub expected_count: usize,
+    pub count: usize,
+    /// Positions which did not answer, they are either gone or have lost their configured address
+    pub lost: Vec<usize>,
+    /// Positions answering with another vendor id, product id or serial number than at init
+    pub replaced: Vec<usize>,
+    pub links: Vec<LinkChange>,
+}
+impl std::fmt::Display for TopologyChange {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.is_empty() {
+            return write!(f, "Topology back to {} subdevices", self.count);
+        }
+        write!(
+            f,
+            "Topology changed, subdevice count: {} -> {}",
+            self.expected_count, self.count
+        )?;
+        if !self.lost.is_empty() {
+            write!(f, ", unreachable positions: {:?}", self.lost)?;
+        }
+        if !self.replaced.is_empty() {
+            write!(f, ", replaced positions: {:?}", self.replaced)?;
+        }
+        for link in self.links.iter() {
+            write!(
+                f,
+                ", position {} link ports: {:#06b} -> {:#06b}",
+                link.position,
+                link.before >> 4,
+                link.after >> 4
+            )?;
+        }
+        Ok(())
+    }
+}
+impl Error for TopologyChange {}
+
+impl TopologyChange {
+    /// Segment is back as it was initialized
+    pub fn is_empty(&self) -> bool {
+        self.count == self.expected_count
+            && self.lost.is_empty()
+            && self.replaced.is_empty()
+            && self.links.is_empty()
+    }
+    pub fn added(&self) -> bool {
+        self.count > self.expected_count
+    }
+}
+
+pub struct TopologySignals {
+    subdevice_count: Signal<u64>,
+    change: Signal<String>,
+    matches: Signal<bool>,
+    mismatch: Signal<String>,
+}
+
+impl TopologySignals {
+    pub fn new(dbus: zbus::Connection) -> Self {
+        let subdevice_count = Signal::new(
+            dbus.clone(),
+            Base::new(
+                "topology/subdevice_count",
+                Some("Number of subdevices in the operational group"),
+            ),
+        );
+        let change = Signal::new(
+            dbus.clone(),
+            Base::new(
+                "topology/change",
+                Some("Description of the last detected topology change"),
+            ),
+        );
+        let matches = Signal::new(
+            dbus.clone(),
+            Base::new(
+                "topology/matches",
+                Some("Discovered subdevices match the expected topology"),
+            ),
+        );
+        let mismatch = Signal::new(
+            dbus.clone(),
+            Base::new(
+                "topology/mismatch",
+                Some("Per position report of differences from the expected topology"),
+            ),
+        );
+        #[cfg(feature = "dbus-expose")]
+        {
+            tfc::ipc::dbus::SignalInterface::register(
+                subdevice_count.base(),
+                dbus.clone(),
+                subdevice_count.subscribe(),
+            );
+            tfc::ipc::dbus::SignalInterface::register(
+                change.base(),
+                dbus.clone(),
+                change.subscribe(),
+            );
+            tfc::ipc::dbus::SignalInterface::register(
+                matches.base(),
+                dbus.clone(),
+                matches.subscribe(),
+            );
+            tfc::ipc::dbus::SignalInterface::register(
+                mismatch.base(),
+                dbus.clone(),
+                mismatch.subscribe(),
+            );
+        }
+        Self {
+            subdevice_count,
+            change,
+            matches,
+            mismatch,
+        }
+    }
+    #[cfg(feature = "opcua-expose")]
+    pub fn opcua_register(&self, handle: &OpcuaServerHandle) {
+        tfc::ipc::opcua::SignalInterface::new(
+            self.subdevice_count.base(),
+            self.subdevice_count.subscribe(),
+            handle.manager.clone(),
+            handle.subscriptions.clone(),
+            handle.namespace,
+        )
+        .register();
+        tfc::ipc::opcua::SignalInterface::new(
+            self.change.base(),
+            self.change.subscribe(),
+            handle.manager.clone(),
+            handle.subscriptions.clone(),
+            handle.namespace,
+        )
+        .register();
+        tfc::ipc::opcua::SignalInterface::new(
+            self.matches.base(),
+            self.matches.subscribe(),
+            handle.manager.clone(),
+            handle.subscriptions.clone(),
+            handle.namespace,
+        )
+        .register();
+        tfc::ipc::opcua::SignalInterface::new(
+            self.mismatch.base(),
+            self.mismatch.subscribe(),
+            handle.manager.clone(),
+            handle.subscriptions.clone(),
+            handle.namespace,
+        )
+        .register();
+    }
+    pub async fn publish_count(&mut self, count: usize) {
+        let _ = self.subdevice_count.async_send(count as u64).await;
+    }
+    pub async fn publish_change(&mut self, change: &TopologyChange) {
+        let _ = self.change.async_send(change.to_string()).await;
+    }
+    pub async fn publish_mismatches(&mut self, mismatches: &[Mismatch]) {
+        let _ = self.matches.async_send(mismatches.is_empty()).await;
+        let _ = self.mismatch.async_send(mismatch_report(mismatches)).await;
+    }
+}
+
+/// Watches the segment in the background while the groups are operational.
+/// The subdevice count is read with a broadcast, each subdevice's DL status and SII identity
+/// with its configured address. Each time the result differs from the last report it is
+/// reported through `changed`, a segment back as initialized is reported as an empty change
+pub struct TopologyMonitor {
+    handle: JoinHandle<()>,
+    changes: mpsc::UnboundedReceiver<TopologyChange>,
+}
+
+impl TopologyMonitor {
+    /// Subdevices are the configured address and identity of each position found at init
+    pub fn spawn(
+        main_device: Arc<MainDevice<'static>>,
+        subdevices: Vec<(u16, Identity)>,
+        interval: Duration,
+        log_key: String,
+    ) -> Self {
+        let (tx, changes) = mpsc::unbounded_channel();
+        let handle = tokio::spawn(async move {
+            let mut links: Vec<Option<u16>> = vec![None; subdevices.len()];
+            let mut reported = (subdevices.len(), Vec::new(), Vec::new());
+            let mut ticker = tokio::time::interval(interval);
+            ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Skip);
+            loop {
+                ticker.tick().await;
+                let count = match Command::brd(RegisterAddress::Type.into())
+                    .receive_wkc::<u8>(&main_device)
+                    .await
+                {
+                    Ok(count) => count as usize,
+                    Err(e) => {
+                        warn!(target: &log_key, "Failed to count subdevices: {}", e);
+                        continue;
+                    }
+                };
+                let mut lost = Vec::new();
+                let mut replaced = Vec::new();
+                let mut link_changes = Vec::new();
+                for (position, (address, identity)) in subdevices.iter().enumerate() {
+                    match Command::fprd(*address, DL_STATUS)
+                        .receive::<u16>(&main_device)
+                        .await
+                    {
+                        Ok(status) => {
+                            let link = status & DL_STATUS_LINK_MASK;
+                            if let Some(before) = links[position] {
+                                if before != link {
+                                    link_changes.push(LinkChange {
+                                        position,
+                                        before,
+                                        after: link,
+                                    });
+                                }
+                            }
+                            links[position] = Some(link);
+                        }
+                        Err(_) => {
+                            lost.push(position);
+                            continue;
+                        }
+                    }
+                    match same_identity(&main_device, *address, identity).await {
+                        Ok(true) => {}
+                        Ok(false) => replaced.push(position),
+                        Err(e) => {
+                            warn!(target: &log_key, "Failed to read identity of position {}: {}", position, e)
+                        }
+                    }
+                }
+                if (count, &lost, &replaced) != (reported.0, &reported.1, &reported.2)
+                    || !link_changes.is_empty()
+                {
+                    reported = (count, lost.clone(), replaced.clone());
+                    let change = TopologyChange {
+                        expected_count: subdevices.len(),
+                        count,
+                        lost,
+                        replaced,
+                        links: link_changes,
+                    };
+                    info!(target: &log_key, "{}", change);
+                    if tx.send(change).is_err() {
+                        return;
+                    }
+                }
+            }
+        });
+        Self { handle, changes }
+    }
+
+    pub fn changed(&mut self) -> Option<TopologyChange> {
+        self.changes.try_recv().ok()
+    }
+}
+
+/// Vendor id, product id and serial number in the SII of the subdevice at address match identity
+async fn same_identity(
+    main_device: &MainDevice<'_>,
+    address: u16,
+    identity: &Identity,
+) -> Result<bool, Box<dyn Error + Send + Sync>> {
+    let mut sii = Sii::new(main_device, address);
+    for (word, expected) in [
+        (SII_VENDOR_ID, identity.vendor_id),
+        (SII_PRODUCT_ID, identity.product_id),
+        (SII_SERIAL, identity.serial),
+    ] {
+        let found = sii.word(word).await? as u32 | (sii.word(word + 1).await? as u32) << 16;
+        if found != expected {
+            return Ok(false);
+        }
+    }
+    Ok(true)
+}
+
+impl Drop for TopologyMonitor {
+    fn drop(&mut self) {
+        self.handle.abort();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sim::{self, Segment, SimulatedSubDevice};
+    use ethercrab::std::ethercat_now;
+    use std::sync::Mutex;
+
+    fn found(product_id: u32, alias: u16) -> FoundSubDevice {
+        FoundSubDevice {
+            identity: Identity {
+                vendor_id: 0x2,
+                product_id,
+                revision: 0,
+                serial: 0,
+            },
+            alias,
+            name: format!("{:#x}", product_id),
+        }
+    }
+    fn expected(product_id: u32, alias: Option<u16>) -> ExpectedSubDevice {
+        ExpectedSubDevice {
+            name: None,
+            vendor_id: 0x2,
+            product_id,
+            alias,
+        }
+    }
+
+    #[test]
+    fn test_check_expected() {
+        let segment = [
+            found(0x44c2c52, 0),
+            found(0x3f03052, 0),
+            found(0x7d83052, 7),
+        ];
+
+        assert!(check_expected(&[], &segment).is_empty());
+        assert!(check_expected(
+            &[
+                expected(0x44c2c52, None),
+                expected(0x3f03052, None),
+                expected(0x7d83052, Some(7))
+            ],
+            &segment
+        )
+        .is_empty());
+
+        // wrong alias and a missing subdevice at the end
+        let mismatches = check_expected(
+            &[
+                expected(0x44c2c52, None),
+                expected(0x3f03052, None),
+                expected(0x7d83052, Some(8)),
+                expected(0x0d1c3052, None),
+            ],
+            &segment,
+        );
+        assert_eq!(mismatches.len(), 2);
+        assert_eq!(mismatches[0].position, 2);
+        assert_eq!(mismatches[1].position, 3);
+        assert!(mismatches[1].found.is_none());
+
+        // unexpected subdevice
+        let mismatches = check_expected(&[expected(0x44c2c52, None)], &segment);
+        assert_eq!(
+            mismatches.iter().map(|m| m.position).collect::<Vec<_>>(),
+            vec![1, 2]
+        );
+        assert!(mismatches[0].expected.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_monitor_replaced() {
+        let segment = Arc::new(Mutex::new(Segment::new(&[
+            SimulatedSubDevice::El1008,
+            SimulatedSubDevice::El2008,
+        ])));
+        let main_device = Arc::new(sim::main_device(&segment));
+        let group = main_device
+            .init_single_group::<16, 64>(ethercat_now)
+            .await
+            .expect("init");
+        let mut subdevices: Vec<(u16, Identity)> = group
+            .iter(&main_device)
+            .map(|subdevice| {
+                (
+                    subdevice.configured_address(),
+                    Identity::from(subdevice.identity()),
+                )
+            })
+            .collect();
+        // as if the EL2008 had taken the place of another one at init
+        subdevices[1].1.serial += 1;
+        let mut monitor = TopologyMonitor::spawn(
+            main_device.clone(),
+            subdevices,
+            Duration::from_millis(10),
+            "test".to_string(),
+        );
+        let mut change = None;
+        for _ in 0..100 {
+            change = monitor.changed();
+            if change.is_some() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        let change = change.expect("change");
+        assert_eq!(change.replaced, vec![1]);
+        assert!(change.lost.is_empty());
+        assert_eq!(change.count, 2);
+    }
+}